@@ -1,2 +1,5 @@
+// `gc_derive` expands `Trace` and `Finalize` into impls nested in anonymous consts.
+#![allow(non_local_definitions)]
+
 pub mod sexp;
 pub mod semantic;
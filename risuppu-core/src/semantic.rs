@@ -1,213 +1,740 @@
 pub mod frame;
 pub mod env;
-use gc::Gc;
-pub use env::Env;
-
-use crate::sexp::{Cons, Ptr, Sexp};
+pub mod error;
+pub mod backtrace;
+pub mod observer;
+pub mod continuation;
+pub mod bytecode;
+pub mod resolve;
+mod compile;
+mod expand;
+mod quasiquote;
+mod vm;
+use gc::{Finalize, Gc, GcCell, Trace};
+pub use env::{Engine, Env};
+pub use error::{Arity, EvalError, EvalResult};
+pub use backtrace::{Backtrace, BacktraceFrame, CallKind};
+pub use observer::{EvalEvent, EvalObserver, EventCollector, StdoutTracer};
+pub use continuation::Continuation;
+pub use bytecode::Closure;
+pub use expand::expand_once;
+
+use self::backtrace::Call;
+use self::continuation::{Cont, ContinuationData, Nested};
+use self::frame::{Frame, FrameKind};
+
+use crate::sexp::{symbol::Symbol, Cons, Ptr, Sexp};
 
 mod module;
 use self::module::{process_require, process_provide};
 
-pub fn evaluate(mut sexp: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    #[cfg(debug_assertions)]
-    println!("Eval: {}", sexp);
-    #[cfg(debug_assertions)]
-    let orig_sexp = sexp.clone();
+/// Grow the stack if less than this remains before a nested evaluation.
+const STACK_RED_ZONE: usize = 256 * 1024;
+/// The size of the stack segments allocated for deep evaluations.
+const STACK_SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Evaluate the expression, panicking if the evaluation fails.
+///
+/// Use [`try_evaluate`] to handle the errors.
+pub fn evaluate(sexp: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+    match try_evaluate(sexp, env) {
+        Ok(evaluated) => evaluated,
+        Err(e) => panic!("{e}"),
+    }
+}
+
+pub fn try_evaluate(sexp: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let sexp = if expand::expands(env) {
+        expand::expand(&sexp, env)
+    } else {
+        sexp
+    };
+    match compile::compile_for(&sexp, env) {
+        Some(code) => evaluate_with(sexp, env, |env| run_vm(code, env)),
+        None if resolve::resolves(env) => {
+            let sexp = resolve::resolve(&sexp, env);
+            walk(sexp, env)
+        }
+        None => walk(sexp, env),
+    }
+}
+
+/// Evaluate the expression on the tree walker.
+pub(crate) fn walk(sexp: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let step = Step::Eval(sexp.clone());
+    evaluate_with(sexp, env, |env| run_machine(step, env))
+}
+
+/// Apply a lambda or a continuation to the evaluated arguments on the tree walker.
+///
+/// `ident` is the identifier which the callee was bound to, if any.
+pub(crate) fn walk_apply(callee: Ptr<Sexp>, args: Ptr<Sexp>, ident: Option<Ptr<Sexp>>, env: &mut Env) -> EvalResult {
+    let form = Sexp::cons(callee.clone(), args.clone());
+    evaluate_with(form, env, |env| {
+        let mut machine = Machine::new(env);
+        machine.state.callee = ident;
+        let step = apply_evaluated(callee, args, env, &mut machine.state).unwrap_or_else(|e| Step::Return(Err(e)));
+        let evaluated = machine.run(step, env);
+        env.end_run();
+        evaluated
+    })
+}
+
+/// Run the evaluation in a nested evaluation.
+fn evaluate_with(sexp: Ptr<Sexp>, env: &mut Env, f: impl FnOnce(&mut Env) -> EvalResult) -> EvalResult {
+    // Start from a clean backtrace at the top level.
+    if env.depth() == 0 {
+        env.take_backtrace();
+    }
+
+    let cur_top = enter_evaluation(&sexp, env)?;
+
+    // Rust functions evaluating their arguments recurse on the native stack,
+    // which continues on the heap instead of overflowing.
+    let evaluated = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || f(env));
+
+    exit_evaluation(&sexp, cur_top, &evaluated, env);
+    evaluated
+}
+
+fn run_machine(step: Step, env: &mut Env) -> EvalResult {
+    let mut machine = Machine::new(env);
+    let evaluated = machine.run(step, env);
+    env.end_run();
+    evaluated
+}
+
+/// Run the compiled code on the VM.
+///
+/// The VM doesn't capture continuations, so a continuation of this run called by the code
+/// is resumed on the tree walker.
+fn run_vm(code: Gc<bytecode::Proto>, env: &mut Env) -> EvalResult {
+    let run = env.begin_run();
+    let evaluated = vm::run(code, env);
+    env.end_run();
+    match evaluated {
+        Err(EvalError::Escape { continuation, value }) if continuation.data().run == run => {
+            run_machine(Step::Resume(continuation, value), env)
+        }
+        evaluated => evaluated,
+    }
+}
+
+/// Apply a macro to the unevaluated arguments, returning its expansion.
+pub(crate) fn expand_macro(r#macro: Ptr<Sexp>, args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let frame = env.top_frame();
+    let expansion = apply_list_to(args, r#macro, env).and_then(|body| walk(body, env));
+    env.set_frame_ptr(frame);
+    expansion
+}
+
+/// Start a nested evaluation, returning the frame to restore when it exits.
+fn enter_evaluation(sexp: &Ptr<Sexp>, env: &mut Env) -> Result<Option<Gc<GcCell<Frame>>>, EvalError> {
+    env.enter_evaluation()?;
+    let depth = env.depth();
+    env.notify(|observer| observer.eval_enter(sexp, depth));
 
     env.push_frame();
-    let cur_top = env.top_frame();
-
-    let evaluated = loop {
-        // The `break`ed val is the return val,
-
-        // If you want to inspect the sexp when debugging,
-        // uncomment the following line.
-        #[cfg(debug_assertions)]
-        let s = sexp.to_string();
-
-        #[cfg(debug_assertions)]
-        println!("=> {}", s);
-
-        sexp = match sexp.as_ref() {
-            Sexp::Form(list) => {
-                let car = list.car.clone();
-                let cdr = list.cdr.clone();
-                match car.as_ref() {
-                    Sexp::Read => process_read(cdr, env),
-                    Sexp::Print => process_print(cdr, env),
-                    Sexp::If => process_if(cdr, env),
-                    Sexp::Eq => process_eq(cdr, env),
-                    Sexp::Quote => break cdr.car(),
-                    Sexp::Cons => break process_cons(cdr, env),
-                    Sexp::Car => break process_car(cdr, env),
-                    Sexp::Cdr => break process_cdr(cdr, env),
-                    Sexp::Lambda => {
-                        // Capture the current environment.
-                        let current_frame_ptr = env.top_frame().expect("No stack frame!");
-                        #[cfg(debug_assertions)]
-                        {
-                            let p = current_frame_ptr.clone();
-                            let raw = Gc::into_raw(p);
-                            println!("Captured frame {:p}:", raw);
-                            current_frame_ptr.borrow().debug();
-                            unsafe { Gc::from_raw(raw) };
-                        }
-                        let new_lambda = Sexp::lambda_capture(current_frame_ptr);
-                        let new_expr = Sexp::cons(new_lambda, cdr);
-                        break new_expr;
-                    }
-                    Sexp::Macro => break sexp.clone(),
-                    Sexp::CapturedLambda(_) => break sexp.clone(),
-                    Sexp::Eval => evaluate(cdr.car(), env),
-                    Sexp::Define => process_define(cdr, env),
-                    Sexp::Require => process_require(cdr, env),
-                    Sexp::Provide => process_provide(cdr, env),
-
-                    // Do nothing if the first sexp is nil.
-                    Sexp::Nil => break car.clone(),
-
-                    // Replace the identity with its defination,
-                    // and then evaluate the whole expression again.
-                    Sexp::Identifier(ident) => match env.get(ident.as_str()) {
-                        Some(new_car) => Ptr::new(Sexp::Form(Cons::new(new_car, cdr))),
-                        None => panic!("Cannot find ident: {ident}"),
-                    },
-
-                    // Evaluate the CAR and Replace it with the result.
-                    // Then evaluate the whole expression again.
-                    Sexp::Form(list) => {
-                        match list.car.as_ref() {
-                            Sexp::Lambda => {
-                                let args = eval_args(cdr, env);
-                                apply_list_to(args, car, env)
-                            }
-                            Sexp::CapturedLambda(captured_frame) => {
-                                let args = eval_args(cdr, env);
-                                #[cfg(debug_assertions)]
-                                {
-                                    println!("Entered environment captured by {}", car);
-                                }
-                                env.set_frame_ptr(Some(captured_frame.clone()));
-                                apply_list_to(args, car, env)
-                            }
-                            // Evaluate the expanded expr.
-                            Sexp::Macro => {
-                                let frame_before_expanding = env.top_frame();
-                                let expanded = evaluate(apply_list_to(cdr, car, env), env);
-                                env.set_frame_ptr(frame_before_expanding);
+    Ok(env.top_frame())
+}
 
-                                expanded
-                            }
-                            _ => {
-                                let new_car = env.evaluate(car.clone());
-                                Sexp::cons(new_car, cdr)
-                            }
+fn exit_evaluation(sexp: &Ptr<Sexp>, cur_top: Option<Gc<GcCell<Frame>>>, evaluated: &EvalResult, env: &mut Env) {
+    // Restore the stack, even if the evaluation failed.
+    env.set_frame_ptr(cur_top);
+    env.pop_frame();
+
+    let depth = env.depth();
+    env.notify(|observer| observer.eval_exit(sexp, evaluated, depth));
+    env.leave_evaluation();
+}
+
+/// The state of an evaluation loop, which is cleaned up when the loop exits.
+#[derive(Default, Clone, Trace, Finalize)]
+pub(crate) struct LoopState {
+    // The last evaluated form read from a source file.
+    located_form: Option<Ptr<Sexp>>,
+    // The identifier which the head of the form was bound to.
+    callee: Option<Ptr<Sexp>>,
+    // Whether the loop has made a call, which is replaced by its tail calls.
+    in_call: bool,
+}
+
+impl LoopState {
+    fn enter_call(&mut self, kind: CallKind, args: Ptr<Sexp>, env: &mut Env) {
+        env.notify(|observer| observer.apply(kind, self.callee.as_ref(), &args));
+        let call = Call {
+            kind,
+            callee: self.callee.take(),
+            args,
+            form: self.located_form.clone(),
+        };
+
+        if self.in_call {
+            env.replace_call(call);
+        } else {
+            env.push_call(call);
+            self.in_call = true;
+        }
+    }
+
+    /// Clean up the state when the loop exits with the result.
+    fn finish(&mut self, res: EvalResult, env: &mut Env) -> EvalResult {
+        let escaping = matches!(res, Err(EvalError::Escape { .. }));
+        if res.is_err() && !escaping {
+            env.capture_backtrace();
+        }
+        if self.in_call {
+            env.pop_call();
+        }
+
+        let located_form = self.located_form.take();
+        res.map_err(|e| match located_form.and_then(|form| env.spans().location_of(&form)) {
+            Some(location) => e.at(location),
+            None => e,
+        })
+    }
+}
+
+/// What the evaluator does next.
+enum Step {
+    /// Evaluate the form in place of the current one, e.g. a tail call.
+    Eval(Ptr<Sexp>),
+    /// The current evaluation finished.
+    Return(EvalResult),
+    /// Evaluate the form in a nested evaluation, and pass its value to the continuation.
+    Nested(Cont, Ptr<Sexp>),
+    /// Apply the function to the current continuation.
+    CallCC(Ptr<Sexp>),
+    /// Call the continuation with the value.
+    Resume(Continuation, Ptr<Sexp>),
+}
+
+/// The evaluator, which keeps the nested evaluations on a heap stack
+/// instead of recursing on the native stack.
+///
+/// Each public evaluation, e.g. by a Rust function, starts a new run of the evaluator.
+struct Machine {
+    /// The pending nested evaluations, the innermost one last.
+    stack: Vec<Nested>,
+    /// The state of the innermost evaluation.
+    state: LoopState,
+    /// The id of the run, or `None` at the top level.
+    run: Option<u64>,
+    /// The depth of the evaluations when the run started.
+    depth: usize,
+    /// The depth of the shadow call stack when the run started.
+    calls: usize,
+}
+
+impl Machine {
+    fn new(env: &mut Env) -> Self {
+        Self {
+            stack: vec![],
+            state: LoopState::default(),
+            run: env.begin_run(),
+            depth: env.depth(),
+            calls: env.call_depth(),
+        }
+    }
+
+    fn run(&mut self, mut step: Step, env: &mut Env) -> EvalResult {
+        let spans = env.spans().clone();
+        let track_location = !spans.is_empty();
+
+        loop {
+            step = match step {
+                Step::Eval(sexp) => {
+                    let stepped = env.consume_fuel().and_then(|()| {
+                        if track_location && spans.span_of(&sexp).is_some() {
+                            self.state.located_form = Some(sexp.clone());
                         }
+                        eval_step(sexp, env, &mut self.state)
+                    });
+                    stepped.unwrap_or_else(|e| Step::Return(Err(e)))
+                }
+                Step::Nested(cont, sexp) => match enter_evaluation(&sexp, env) {
+                    Ok(frame) => {
+                        let state = std::mem::take(&mut self.state);
+                        self.stack.push(Nested {
+                            cont,
+                            state,
+                            expr: sexp.clone(),
+                            frame,
+                        });
+                        Step::Eval(sexp)
+                    }
+                    Err(e) => Step::Return(Err(e)),
+                },
+                Step::CallCC(f) => {
+                    let k = Continuation::new(ContinuationData {
+                        stack: self.stack.clone(),
+                        state: self.state.clone(),
+                        calls: env.calls_since(self.calls),
+                        frame: env.top_frame(),
+                        run: self.run,
+                    });
+                    let k = Sexp::from_vec([Sexp::quote(), Sexp::wrap(Sexp::Continuation(k))]);
+                    Step::Eval(Sexp::from_vec([f, k]))
+                }
+                Step::Resume(k, value) => {
+                    if k.data().run == self.run {
+                        self.install(&k, env);
+                        Step::Return(Ok(value))
+                    } else if env.is_running(k.data().run) {
+                        Step::Return(Err(EvalError::Escape { continuation: k, value }))
+                    } else {
+                        Step::Return(Err(EvalError::raise(
+                            "continuation-error",
+                            "Cannot resume a continuation captured in a Rust function which has returned",
+                        )))
                     }
+                }
+
+                // The continuation of this run, called in a Rust function.
+                Step::Return(Err(EvalError::Escape { continuation, value })) if continuation.data().run == self.run => {
+                    self.install(&continuation, env);
+                    Step::Return(Ok(value))
+                }
+                Step::Return(res) => {
+                    let res = self.state.finish(res, env);
+                    let Some(mut nested) = self.stack.pop() else {
+                        return res;
+                    };
+                    exit_evaluation(&nested.expr, nested.frame.clone(), &res, env);
+                    self.state = std::mem::take(&mut nested.state);
+                    resume(&mut nested.cont, res, env, &mut self.state).unwrap_or_else(|e| Step::Return(Err(e)))
+                }
+            }
+        }
+    }
 
-                    // Apply the CDR to the Rust function.
-                    Sexp::RustFn(f) => {
-                        f.call(cdr, env)
+    /// Replace the pending evaluations with the ones captured by the continuation.
+    fn install(&mut self, k: &Continuation, env: &mut Env) {
+        let data = k.data();
+        self.stack = data.stack.clone();
+        self.state = data.state.clone();
+        env.restore_calls(self.calls, &data.calls);
+        env.set_depth(self.depth + self.stack.len());
+        env.set_frame_ptr(data.frame.clone());
+    }
+}
+
+fn eval_step(sexp: Ptr<Sexp>, env: &mut Env, state: &mut LoopState) -> Result<Step, EvalError> {
+    let step = match sexp.as_ref() {
+        Sexp::Form(list) => {
+            let car = list.car.clone();
+            let cdr = list.cdr.clone();
+            match car.as_ref() {
+                Sexp::Read => Step::Eval(process_read(cdr, env)?),
+                Sexp::Print => process_print(cdr)?,
+                Sexp::If => process_if(cdr)?,
+                Sexp::Eq => process_eq(cdr)?,
+                Sexp::Quote => {
+                    check_arity("quote", &cdr, Arity::exactly(1))?;
+                    Step::Return(Ok(cdr.car()))
+                }
+                Sexp::Quasiquote => Step::Eval(quasiquote::quasiquote(&cdr)?),
+                Sexp::Unquote | Sexp::UnquoteSplicing => return Err(quasiquote::unquote_outside(&car)),
+                Sexp::Cons => {
+                    check_arity("cons", &cdr, Arity::exactly(2))?;
+                    Step::Nested(Cont::ConsCar { cdr: cdr.cdr().car() }, cdr.car())
+                }
+                Sexp::Car => {
+                    check_arity("car", &cdr, Arity::exactly(1))?;
+                    Step::Nested(Cont::Car, cdr.car())
+                }
+                Sexp::Cdr => {
+                    check_arity("cdr", &cdr, Arity::exactly(1))?;
+                    Step::Nested(Cont::Cdr, cdr.car())
+                }
+                Sexp::Lambda => {
+                    // Capture the current environment.
+                    let current_frame_ptr = env.top_frame().ok_or(EvalError::NoStackFrame)?;
+                    let new_lambda = Sexp::lambda_capture(current_frame_ptr);
+                    Step::Return(Ok(Sexp::cons(new_lambda, cdr)))
+                }
+                Sexp::Macro => Step::Return(Ok(sexp.clone())),
+                Sexp::CapturedLambda(_) => Step::Return(Ok(sexp.clone())),
+                Sexp::Eval => {
+                    check_arity("eval", &cdr, Arity::exactly(1))?;
+                    Step::Nested(Cont::Eval, cdr.car())
+                }
+                Sexp::Begin => process_begin(cdr),
+                Sexp::Define => process_define(cdr, env)?,
+                Sexp::Set => process_set(cdr)?,
+                Sexp::Letrec => process_letrec("letrec", cdr, env, false)?,
+                Sexp::LetrecStar => process_letrec("letrec*", cdr, env, true)?,
+                Sexp::Require => Step::Eval(process_require(cdr, env)?),
+                Sexp::Provide => Step::Eval(process_provide(cdr, env)?),
+                Sexp::Try => process_try(cdr)?,
+                Sexp::CallCC => {
+                    check_arity("call/cc", &cdr, Arity::exactly(1))?;
+                    Step::Nested(Cont::CallCC, cdr.car())
+                }
+
+                // Do nothing if the first sexp is nil.
+                Sexp::Nil => Step::Return(Ok(car.clone())),
+
+                // Replace the identity with its defination,
+                // and then evaluate the whole expression again.
+                Sexp::Identifier(ident) => match env.get(*ident) {
+                    Some(new_car) => {
+                        state.callee = Some(car.clone());
+                        Step::Eval(Ptr::new(Sexp::Form(Cons::new(new_car, cdr))))
                     }
+                    None => return Err(EvalError::UnboundIdentifier(ident.to_string())),
+                },
+                Sexp::Local(_) | Sexp::Global(_) => {
+                    let new_car = get_resolved(&car, env)?;
+                    state.callee = Some(car.clone());
+                    Step::Eval(Ptr::new(Sexp::Form(Cons::new(new_car, cdr))))
+                }
 
-                    exp => {
-                        panic!("Error: {exp:?} is not appliable!");
+                // Evaluate the CAR and Replace it with the result.
+                // Then evaluate the whole expression again.
+                Sexp::Form(list) => match list.car.as_ref() {
+                    Sexp::Lambda | Sexp::CapturedLambda(_) => eval_args(car, cdr, env, state)?,
+                    // Evaluate the expanded expr.
+                    Sexp::Macro => {
+                        state.enter_call(CallKind::Macro, cdr.clone(), env);
+                        let frame = env.top_frame();
+                        match apply_list_to(cdr, car, env) {
+                            Ok(expansion) => Step::Nested(Cont::Expand { form: sexp.clone(), frame }, expansion),
+                            Err(e) => {
+                                env.set_frame_ptr(frame);
+                                return Err(e);
+                            }
+                        }
                     }
+                    _ => Step::Nested(Cont::Head { args: cdr }, car),
+                },
+
+                Sexp::Continuation(_) | Sexp::Closure(_) => eval_args(car, cdr, env, state)?,
+                Sexp::RustFn(f) if f.evaluates_args() => eval_args(car, cdr, env, state)?,
+
+                // Apply the CDR to the Rust function.
+                Sexp::RustFn(f) => {
+                    let args = f.preprocess(cdr, env)?;
+                    state.enter_call(CallKind::RustFn, args.clone(), env);
+                    env.consume_fuel()?;
+                    Step::Eval(f.apply(args, env)?)
                 }
+
+                exp => return Err(EvalError::NotApplicable(exp.to_string())),
             }
+        }
 
-            Sexp::Identifier(ident) => match env.get(ident.as_str()) {
-                Some(sexp) => break sexp,
-                None => panic!("Cannot find ident: {ident}"),
-            },
+        // Keywords evaluate to themselves.
+        Sexp::Identifier(ident) if keyword_name(ident).is_some() => Step::Return(Ok(sexp.clone())),
+        Sexp::Identifier(ident) => match env.get(*ident) {
+            Some(sexp) => Step::Return(Ok(sexp)),
+            None => return Err(EvalError::UnboundIdentifier(ident.to_string())),
+        },
+        Sexp::Local(_) | Sexp::Global(_) => Step::Return(get_resolved(&sexp, env)),
 
-            _ => break sexp.clone(),
-        }
+        _ => Step::Return(Ok(sexp.clone())),
     };
+    Ok(step)
+}
 
-    // Restore the stack.
-    #[cfg(debug_assertions)]
-    {
-        println!("Restored stack");
-    }
-    env.set_frame_ptr(cur_top);
-    env.pop_frame();
-
-    #[cfg(debug_assertions)]
-    println!("Evaluated: {} => {}", orig_sexp, evaluated);
-    evaluated
+/// The value of a resolved reference to a variable.
+fn get_resolved(reference: &Ptr<Sexp>, env: &Env) -> EvalResult {
+    let (value, name) = match reference.as_ref() {
+        Sexp::Local(local) => (env.get_local(local), &local.name),
+        Sexp::Global(global) => (env.get_global(global), &global.name),
+        _ => unreachable!(),
+    };
+    value.ok_or_else(|| EvalError::UnboundIdentifier(name.to_string()))
 }
 
-fn process_car(body: Gc<Sexp>, env: &mut Env) -> Gc<Sexp> {
-    let arg = body.car();
-    let arg = env.evaluate(arg);
-    arg.car()
+/// Pass the result of a nested evaluation to the continuation.
+fn resume(cont: &mut Cont, res: EvalResult, env: &mut Env, state: &mut LoopState) -> Result<Step, EvalError> {
+    match cont {
+        Cont::Try { clauses } => return handle_try(clauses.clone(), res, env),
+        Cont::Expand { form, frame } => {
+            env.set_frame_ptr(frame.clone());
+            let expansion = res?;
+            env.notify(|observer| observer.macro_expand(form, &expansion));
+            return Ok(Step::Eval(expansion));
+        }
+        _ => {}
+    }
+
+    let value = res?;
+    let step = match cont {
+        Cont::If { if_branch, else_branch } => {
+            if let Sexp::Bool(true) = value.as_ref() {
+                Step::Eval(if_branch.clone())
+            } else {
+                Step::Eval(else_branch.clone())
+            }
+        }
+        Cont::Eq { first: None, rest } => next_eq(value, rest.clone()),
+        Cont::Eq { first: Some(first), rest } => {
+            if value != *first {
+                Step::Eval(Sexp::bool(false))
+            } else {
+                next_eq(first.clone(), rest.clone())
+            }
+        }
+        Cont::ConsCar { cdr } => Step::Nested(Cont::ConsCdr { car: value }, cdr.clone()),
+        Cont::ConsCdr { car } => Step::Return(Ok(Sexp::cons(car.clone(), value))),
+        Cont::Car => Step::Return(Ok(value.car())),
+        Cont::Cdr => Step::Return(Ok(value.cdr())),
+        Cont::Print { func } => {
+            if let Sexp::SString(content) = value.as_ref() {
+                print!("{}", content);
+            } else {
+                print!("{}", value);
+            }
+            Step::Eval(Sexp::from_vec(vec![func.clone()]))
+        }
+        Cont::Eval => Step::Eval(value),
+        Cont::Begin { rest } => process_begin(rest.clone()),
+        Cont::Define { ident } => {
+            env.define(*ident, value);
+            Step::Eval(Sexp::nil())
+        }
+        Cont::Set { ident } => {
+            env.assign(*ident, value)?;
+            Step::Eval(Sexp::nil())
+        }
+        Cont::Letrec {
+            ident,
+            bindings,
+            body,
+            sequential,
+            idents,
+            values,
+        } => {
+            if *sequential {
+                env.set(*ident, value)?;
+            } else {
+                idents.push(*ident);
+                values.push(value);
+            }
+            let (idents, values) = (std::mem::take(idents), std::mem::take(values));
+            next_letrec(bindings.clone(), body.clone(), *sequential, idents, values, env)?
+        }
+        Cont::Head { args } => Step::Eval(Sexp::cons(value, args.clone())),
+        Cont::Args { callee, rest, values } => {
+            values.push(value);
+            if rest.is_nil() {
+                let args = Sexp::from_vec(std::mem::take(values));
+                apply_evaluated(callee.clone(), args, env, state)?
+            } else {
+                let (arg, rest) = (rest.car(), rest.cdr());
+                let values = std::mem::take(values);
+                Step::Nested(Cont::Args { callee: callee.clone(), rest, values }, arg)
+            }
+        }
+        Cont::CallCC => Step::CallCC(value),
+        Cont::Try { .. } | Cont::Expand { .. } => unreachable!(),
+    };
+    Ok(step)
 }
 
-fn process_cdr(body: Gc<Sexp>, env: &mut Env) -> Gc<Sexp> {
-    let arg = body.car();
-    let arg = env.evaluate(arg);
-    arg.cdr()
+/// Check that `name` is applied to an acceptable number of arguments.
+pub fn check_arity(name: &str, args: &Ptr<Sexp>, expected: Arity) -> Result<(), EvalError> {
+    let found = Sexp::iter(args.clone()).count();
+    if expected.accepts(found) {
+        Ok(())
+    } else {
+        Err(EvalError::WrongArity {
+            name: name.to_string(),
+            expected,
+            found,
+        })
+    }
 }
 
-pub fn process_if(body: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+fn process_if(body: Ptr<Sexp>) -> Result<Step, EvalError> {
+    check_arity("if", &body, Arity::between(2, 3))?;
     let condition = body.car();
     let if_branch = body.cdr().car();
     let else_branch = body.cdr().cdr().car();
 
-    if let Sexp::Bool(true) = evaluate(condition, env).as_ref() {
-        if_branch
-    } else {
-        else_branch
-    }
+    Ok(Step::Nested(Cont::If { if_branch, else_branch }, condition))
 }
 
-pub fn process_eq(body: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let pre = evaluate(body.car(), env);
-    let remaining = body.cdr();
+fn process_eq(body: Ptr<Sexp>) -> Result<Step, EvalError> {
+    check_arity("eq", &body, Arity::at_least(1))?;
+    Ok(Step::Nested(Cont::Eq { first: None, rest: body.cdr() }, body.car()))
+}
 
-    for item in Sexp::iter(remaining) {
-        let item = evaluate(item, env);
-        if item != pre {
-            return Sexp::bool(false);
-        }
+/// Compare the rest items of `eq` with the first one.
+fn next_eq(first: Ptr<Sexp>, rest: Ptr<Sexp>) -> Step {
+    if rest.is_nil() {
+        Step::Eval(Sexp::bool(true))
+    } else {
+        Step::Nested(Cont::Eq { first: Some(first), rest: rest.cdr() }, rest.car())
     }
-
-    Sexp::bool(true)
 }
 
-pub fn process_cons(body: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let first = evaluate(body.car(), env);
-    let second = evaluate(body.cdr().car(), env);
+/// The forms evaluated in sequence, wrapped in `begin` unless there is only one.
+pub fn sequence(forms: Ptr<Sexp>) -> Ptr<Sexp> {
+    if forms.cdr().is_nil() {
+        forms.car()
+    } else {
+        Sexp::cons(Sexp::begin(), forms)
+    }
+}
 
-    Ptr::new(Sexp::Form(Cons {
-        car: first,
-        cdr: second,
-    }))
+/// `(begin expr ...)`
+///
+/// Evaluate the forms in order. The last one is in tail position.
+fn process_begin(body: Ptr<Sexp>) -> Step {
+    if body.cdr().is_nil() {
+        Step::Eval(body.car())
+    } else {
+        Step::Nested(Cont::Begin { rest: body.cdr() }, body.car())
+    }
 }
 
-pub fn process_define(body: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+/// `(define ident expr)` or `(define (ident params...) body ...)`
+///
+/// Inside a lambda body, the definition is bound in the scope of the lambda,
+/// so the internal definitions are local and can see each other.
+fn process_define(body: Ptr<Sexp>, env: &mut Env) -> Result<Step, EvalError> {
+    check_arity("define", &body, Arity::at_least(2))?;
     let identity = body.car();
 
     if let Sexp::Identifier(ident) = identity.as_ref() {
-        let defination = env.evaluate(body.cdr().car());
-        env.set_global(ident, defination)
+        check_arity("define", &body, Arity::exactly(2))?;
+        return Ok(Step::Nested(Cont::Define { ident: *ident }, body.cdr().car()));
     }
 
     if let Sexp::Form(_) = identity.as_ref() {
         let ident = identity.car();
         let params = identity.cdr();
-        let defination = body.cdr().car();
+        let defination = Sexp::cons(Sexp::lambda(), Sexp::cons(params, body.cdr()));
 
         if !ident.is_nil() {
-            env.set_global(ident, Sexp::from_vec([Sexp::lambda(), params, defination]));
+            let ident = match ident.as_ref() {
+                Sexp::Identifier(ident) => *ident,
+                _ => Symbol::intern(&ident.to_string()),
+            };
+            // Internal definitions capture their scope, which is gone after the lambda returns.
+            if env.current_scope().is_some() {
+                return Ok(Step::Nested(Cont::Define { ident }, defination));
+            }
+            env.define(ident, defination);
+        }
+    }
+
+    Ok(Step::Eval(Sexp::nil()))
+}
+
+/// `(set! ident expr)`
+///
+/// Update the nearest existing binding of `ident`, which must have been bound.
+fn process_set(body: Ptr<Sexp>) -> Result<Step, EvalError> {
+    check_arity("set!", &body, Arity::exactly(2))?;
+    let identity = body.car();
+
+    let Sexp::Identifier(ident) = identity.as_ref() else {
+        return Err(EvalError::raise("type-error", format!("Expected an identifier, found {identity}")));
+    };
+    Ok(Step::Nested(Cont::Set { ident: *ident }, body.cdr().car()))
+}
+
+/// `(letrec ((ident init) ...) body ...)` or `(letrec* ((ident init) ...) body ...)`
+///
+/// Bind the identifiers in a new scope where the inits are evaluated,
+/// so the lambdas in the inits can refer to themselves and each other.
+/// `letrec*` binds each identifier before evaluating the next init,
+/// while `letrec` binds them after evaluating all the inits.
+fn process_letrec(name: &str, body: Ptr<Sexp>, env: &mut Env, sequential: bool) -> Result<Step, EvalError> {
+    check_arity(name, &body, Arity::at_least(2))?;
+    let (bindings, body) = (body.car(), sequence(body.cdr()));
+
+    // The scope is left on the stack for the body.
+    let bindings = match bindings.car().as_ref() {
+        Sexp::Layout(layout) => {
+            env.push_scope_with_layout(layout.clone());
+            bindings.cdr()
         }
+        _ => {
+            env.push_scope();
+            bindings
+        }
+    };
+    next_letrec(bindings, body, sequential, vec![], vec![], env)
+}
+
+/// Evaluate the next init of `letrec`, or bind the values and evaluate the body.
+fn next_letrec(
+    bindings: Ptr<Sexp>,
+    body: Ptr<Sexp>,
+    sequential: bool,
+    idents: Vec<Symbol>,
+    values: Vec<Ptr<Sexp>>,
+    env: &mut Env,
+) -> Result<Step, EvalError> {
+    if let Sexp::Form(Cons { car: binding, cdr: bindings }) = bindings.as_ref() {
+        let (ident, init) = (binding.car(), binding.cdr().car());
+        let Sexp::Identifier(ident) = ident.as_ref() else {
+            return Err(EvalError::raise("type-error", format!("Expected an identifier, found {ident}")));
+        };
+        let cont = Cont::Letrec {
+            ident: *ident,
+            bindings: bindings.clone(),
+            body,
+            sequential,
+            idents,
+            values,
+        };
+        return Ok(Step::Nested(cont, init));
     }
 
-    Ptr::new(Sexp::Nil)
+    for (ident, value) in idents.into_iter().zip(values) {
+        env.set(ident, value)?;
+    }
+    Ok(Step::Eval(body))
+}
+
+/// `(try expr (kind var handler ...) ... (else var handler ...))`
+///
+/// Evaluate `expr`, and if an error is raised, evaluate the handler of the first clause
+/// whose kind matches the error, with `var` bound to the error.
+/// The error is raised again if no clause matches.
+fn process_try(body: Ptr<Sexp>) -> Result<Step, EvalError> {
+    check_arity("try", &body, Arity::at_least(1))?;
+    Ok(Step::Nested(Cont::Try { clauses: body.cdr() }, body.car()))
 }
 
-pub fn process_read(body: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
+fn handle_try(clauses: Ptr<Sexp>, res: EvalResult, env: &mut Env) -> Result<Step, EvalError> {
+    let error = match res {
+        Ok(value) => return Ok(Step::Eval(Sexp::from_vec([Sexp::quote(), value]))),
+        Err(e) => e,
+    };
+    let Some(raised) = error.to_value() else {
+        return Err(error);
+    };
+    let kind = match raised.as_ref() {
+        Sexp::Error(e) => Some(e.kind.as_str()),
+        _ => None,
+    };
+
+    for clause in Sexp::iter(clauses) {
+        let (pat, var, handler) = (clause.car(), clause.cdr().car(), sequence(clause.cdr().cdr()));
+        let matched = match pat.as_ref() {
+            Sexp::Identifier(ident) => ident == "else" || Some(ident.as_str()) == kind,
+            _ => false,
+        };
+
+        if matched {
+            env.take_backtrace();
+            let handler = Sexp::from_vec([Sexp::lambda(), Sexp::from_vec([var]), handler]);
+            let raised = Sexp::from_vec([Sexp::quote(), raised.clone()]);
+            return Ok(Step::Eval(Sexp::from_vec([handler, raised])));
+        }
+    }
+
+    Err(error)
+}
+
+pub fn process_read(body: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     fn trim_newline(s: &mut String) {
         if s.ends_with('\n') {
             s.pop();
@@ -217,77 +744,325 @@ pub fn process_read(body: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
         }
     }
 
+    check_arity("read", &body, Arity::between(0, 1))?;
     use std::io::stdin;
     let f = stdin();
     let mut buf = String::new();
-    f.read_line(&mut buf).unwrap();
+    f.read_line(&mut buf)?;
     trim_newline(&mut buf);
     let unescaped = unescaper::unescape(&buf).unwrap_or("ERROR when unescaping".to_string());
 
     let arg = Ptr::new(Sexp::SString(unescaped));
     let func = body.car();
-    Sexp::from_vec(vec![func, arg])
+    Ok(Sexp::from_vec(vec![func, arg]))
 }
 
-pub fn process_print(body: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let content = evaluate(body.car(), env);
-    let func = body.cdr().car();
-    if let Sexp::SString(content) = content.as_ref() {
-        print!("{}", content);
+fn process_print(body: Ptr<Sexp>) -> Result<Step, EvalError> {
+    check_arity("print", &body, Arity::between(1, 2))?;
+    Ok(Step::Nested(Cont::Print { func: body.cdr().car() }, body.car()))
+}
+
+/// Evaluate the arguments of a lambda, a closure, a continuation or a Rust function in nested evaluations.
+fn eval_args(callee: Ptr<Sexp>, args: Ptr<Sexp>, env: &mut Env, state: &mut LoopState) -> Result<Step, EvalError> {
+    if args.is_nil() {
+        return apply_evaluated(callee, args, env, state);
+    }
+    let cont = Cont::Args {
+        callee,
+        rest: args.cdr(),
+        values: vec![],
+    };
+    Ok(Step::Nested(cont, args.car()))
+}
+
+/// Apply a lambda, a closure, a continuation or a Rust function to the evaluated arguments.
+fn apply_evaluated(callee: Ptr<Sexp>, args: Ptr<Sexp>, env: &mut Env, state: &mut LoopState) -> Result<Step, EvalError> {
+    match callee.as_ref() {
+        Sexp::Closure(closure) => {
+            state.enter_call(CallKind::Lambda, args.clone(), env);
+            return Ok(Step::Return(vm::apply(closure, args, env)));
+        }
+        Sexp::Continuation(k) => {
+            check_arity("continuation", &args, Arity::between(0, 1))?;
+            return Ok(Step::Resume(k.clone(), args.car()));
+        }
+        Sexp::RustFn(f) => {
+            state.enter_call(CallKind::RustFn, args.clone(), env);
+            env.consume_fuel()?;
+            return Ok(Step::Eval(f.apply(args, env)?));
+        }
+        _ => {}
+    }
+
+    state.enter_call(CallKind::Lambda, args.clone(), env);
+    match callee.car().as_ref() {
+        Sexp::CapturedLambda(captured_frame) => {
+            env.set_frame_ptr(Some(captured_frame.clone()));
+        }
+        Sexp::Lambda => {
+            if let Some(replaced) = tail_frame(&callee, env) {
+                let pre = replaced.borrow().pre.clone();
+                env.set_frame_ptr(pre);
+                let body = apply_list_to(args, callee, env)?;
+                // The variables which the body hasn't defined yet are still the ones of the replaced frame.
+                if let Some(frame_ptr) = env.top_frame() {
+                    let replaced = replaced.borrow();
+                    let mut frame = frame_ptr.borrow_mut();
+                    for (slot, value) in frame.slots.iter_mut().zip(replaced.slots.iter()) {
+                        if slot.is_none() {
+                            slot.clone_from(value);
+                        }
+                    }
+                }
+                return Ok(Step::Eval(body));
+            }
+        }
+        _ => {}
+    }
+    Ok(Step::Eval(apply_list_to(args, callee, env)?))
+}
+
+/// The frame of the caller, if the call is a tail call of a function defined at the top level from itself.
+///
+/// Such a function is applied on the frames of its caller, but the new frame binds the same names,
+/// so it replaces the frame of the caller instead of piling up on it in a loop.
+fn tail_frame(callee: &Ptr<Sexp>, env: &Env) -> Option<Gc<GcCell<Frame>>> {
+    let marker = callee.cdr().car().car();
+    let Sexp::Layout(layout) = marker.as_ref() else {
+        return None;
+    };
+    let frame_ptr = env.top_frame()?;
+    let frame = frame_ptr.borrow();
+    let same_scope = frame.kind == FrameKind::Scope
+        && frame.inner.is_empty()
+        && frame.layout.as_ref().is_some_and(|frame_layout| Gc::ptr_eq(frame_layout, layout));
+    drop(frame);
+    same_scope.then_some(frame_ptr)
+}
+
+/// The identifier bound to the remaining arguments,
+/// if `params` is the `rest` in `(a . rest)` or `(a &rest rest)`.
+fn rest_param(params: &Ptr<Sexp>) -> Option<Symbol> {
+    match params.as_ref() {
+        Sexp::Identifier(rest) => Some(*rest),
+        Sexp::Form(Cons { car, cdr }) if is_marker(car, "&rest") => match cdr.car().as_ref() {
+            Sexp::Identifier(rest) => Some(*rest),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_marker(param: &Ptr<Sexp>, marker: &str) -> bool {
+    matches!(param.as_ref(), Sexp::Identifier(ident) if ident == marker)
+}
+
+/// Whether `params` starts with a required param,
+/// rather than `#:optional`, `#:key` or a rest param.
+fn is_required(params: &Ptr<Sexp>) -> bool {
+    matches!(params.as_ref(), Sexp::Form(_))
+        && rest_param(params).is_none()
+        && !is_marker(&params.car(), "#:optional")
+        && !is_marker(&params.car(), "#:key")
+}
+
+/// The number of arguments accepted by the params.
+fn params_arity(mut params: Ptr<Sexp>) -> Arity {
+    let mut min = 0;
+    while is_required(&params) {
+        min += 1;
+        params = params.cdr();
+    }
+
+    let mut max = min;
+    if is_marker(&params.car(), "#:optional") {
+        params = params.cdr();
+        while matches!(params.as_ref(), Sexp::Form(_))
+            && rest_param(&params).is_none()
+            && !is_marker(&params.car(), "#:key")
+        {
+            max += 1;
+            params = params.cdr();
+        }
+    }
+
+    if params.is_nil() {
+        Arity::between(min, max)
     } else {
-        print!("{}", content);
+        Arity::at_least(min)
+    }
+}
+
+/// The name of a keyword like `:name`, which evaluates to itself.
+fn keyword_name(ident: &str) -> Option<&str> {
+    ident.strip_prefix(':').filter(|name| !name.is_empty())
+}
+
+/// Bind an optional param, which is either `name` or `(name default)`.
+///
+/// The default value is evaluated in the callee's frame if `arg` is not supplied.
+fn bind_optional(param: Ptr<Sexp>, arg: Option<Ptr<Sexp>>, env: &mut Env) -> Result<(), EvalError> {
+    let (name, default) = match param.as_ref() {
+        Sexp::Form(Cons { car, cdr }) => (car.clone(), cdr.car()),
+        _ => (param.clone(), Sexp::nil()),
+    };
+    let value = match arg {
+        Some(arg) => arg,
+        None => env.try_evaluate(default)?,
+    };
+    if let Sexp::Identifier(ident) = name.as_ref() {
+        env.set(ident, value)?;
     }
-    Sexp::from_vec(vec![func])
+    Ok(())
 }
 
-pub fn eval_args(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let evaluated_args: Vec<_> = Sexp::iter(args).map(|a| env.evaluate(a)).collect();
-    Sexp::from_vec(evaluated_args)
+/// Bind the `#:key` params to the `:name value` pairs in `args`.
+fn bind_keywords(params: Ptr<Sexp>, mut args: Ptr<Sexp>, env: &mut Env) -> Result<(), EvalError> {
+    let param_name = |param: &Ptr<Sexp>| match param.as_ref() {
+        Sexp::Form(Cons { car, .. }) => car.to_string(),
+        _ => param.to_string(),
+    };
+
+    let mut supplied = vec![];
+    while !args.is_nil() {
+        let key = args.car();
+        let name = match key.as_ref() {
+            Sexp::Identifier(ident) => keyword_name(ident),
+            _ => None,
+        };
+        let Some(name) = name.filter(|name| Sexp::iter(params.clone()).any(|p| param_name(&p) == *name)) else {
+            return Err(EvalError::UnknownKeyword(key.to_string()));
+        };
+        if args.cdr().is_nil() {
+            return Err(EvalError::MissingKeywordValue(key.to_string()));
+        }
+        supplied.push((name.to_string(), args.cdr().car()));
+        args = args.cdr().cdr();
+    }
+
+    for param in Sexp::iter(params) {
+        let name = param_name(&param);
+        // The last one wins if a keyword is passed more than once.
+        let arg = supplied.iter().rev().find(|(n, _)| *n == name).map(|(_, arg)| arg.clone());
+        bind_optional(param, arg, env)?;
+    }
+    Ok(())
 }
 
-pub fn apply_list_to(mut args: Ptr<Sexp>, expr: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+/// Apply the arguments to a lambda or a macro, returning its body to be evaluated.
+///
+/// The params can be followed by `#:optional` params and `#:key` params,
+/// which are either `name` or `(name default)`, and a rest param, e.g.
+/// `(a #:optional (b 1) . rest)` or `(a #:key (width 80) height)`.
+/// The lambda is curried if the required params are not all supplied,
+/// unless the environment checks the arity strictly and the params don't start with `#:curry`.
+///
+/// Applying a lambda opens a scope for the internal definitions,
+/// unless its params start with `#:block`, which shares the enclosing scope.
+pub fn apply_list_to(mut args: Ptr<Sexp>, expr: Ptr<Sexp>, env: &mut Env) -> EvalResult {
     let first_token = expr.car();
     let mut params = expr.cdr().car();
-    let body = expr.cdr().cdr().car();
+    let body = sequence(expr.cdr().cdr());
 
     if !first_token.is_lambda() && !first_token.is_macro() {
-        return expr;
+        return Ok(expr);
+    }
+
+    // The leading markers of the params, and the layout added by the resolver.
+    let (mut curry, mut block, mut layout) = (false, false, None);
+    loop {
+        if let Sexp::Layout(l) = params.car().as_ref() {
+            layout = Some(l.clone());
+        } else if is_marker(&params.car(), "#:curry") {
+            curry = true;
+        } else if is_marker(&params.car(), "#:block") {
+            block = true;
+        } else {
+            break;
+        }
+        params = params.cdr();
+    }
+
+    if !curry && env.strict_arity() {
+        let expected = params_arity(params.clone());
+        let found = Sexp::iter(args.clone()).count();
+        if !expected.accepts(found) {
+            let name = env.current_call().map(|call| call.name());
+            return Err(EvalError::WrongArity {
+                name: name.unwrap_or_else(|| first_token.to_string()),
+                expected,
+                found,
+            });
+        }
     }
 
     // Protect the captured environment.
-    env.push_frame();
+    if first_token.is_lambda() && !block {
+        match layout.clone() {
+            Some(layout) => env.push_scope_with_layout(layout),
+            None => env.push_scope(),
+        }
+    } else {
+        env.push_frame();
+    }
 
-    while !args.is_nil() {
+    while !args.is_nil() && is_required(&params) {
         let (first_param, remaining_params) = (params.car(), params.cdr());
         let (arg, remaining_args) = (args.car(), args.cdr());
         if let Sexp::Identifier(ident) = first_param.as_ref() {
-            match first_token.as_ref() {
-                Sexp::Macro | Sexp::Lambda | Sexp::CapturedLambda(_) => env.set(ident, arg),
-                _ => panic!("Cannot apply to a non-lambda expr!"),
-            }
+            env.set(ident, arg)?;
         }
 
         params = remaining_params;
         args = remaining_args;
     }
 
-    if params.is_nil() {
-        body
-    } else {
+    if is_required(&params) {
         let new_first_token = if first_token.is_lambda() {
-            Sexp::lambda_capture(env.top_frame().expect("No stack frame!"))
+            Sexp::lambda_capture(env.top_frame().ok_or(EvalError::NoStackFrame)?)
         } else {
             first_token
         };
-        Sexp::from_vec(vec![new_first_token, params, body])
+        if block {
+            params = Sexp::cons(Sexp::identifier("#:block"), params);
+        }
+        if curry {
+            params = Sexp::cons(Sexp::identifier("#:curry"), params);
+        }
+        if let Some(layout) = layout {
+            params = Sexp::cons(Sexp::wrap(Sexp::Layout(layout)), params);
+        }
+        return Ok(Sexp::from_vec(vec![new_first_token, params, body]));
+    }
+
+    if is_marker(&params.car(), "#:optional") {
+        params = params.cdr();
+        while matches!(params.as_ref(), Sexp::Form(_))
+            && rest_param(&params).is_none()
+            && !is_marker(&params.car(), "#:key")
+        {
+            let arg = (!args.is_nil()).then(|| args.car());
+            bind_optional(params.car(), arg, env)?;
+            params = params.cdr();
+            args = args.cdr();
+        }
     }
+
+    // Bind the remaining arguments as a list.
+    if let Some(rest) = rest_param(&params) {
+        env.set(rest, args)?;
+    } else if is_marker(&params.car(), "#:key") {
+        bind_keywords(params.cdr(), args, env)?;
+    }
+
+    Ok(body)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::sexp::{Sexp, parse::parse_sexp};
+    use crate::sexp::{Ptr, Sexp, parse::{parse_sexp, Reader}};
 
-    use super::Env;
+    use super::{Arity, CallKind, Engine, Env, EvalError};
 
     #[test]
     fn hello_world() {
@@ -401,10 +1176,312 @@ mod test {
         assert_eq!(res, Sexp::int(1));
     }
 
+    #[test]
+    fn eval_rest_params() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.evaluate(parse_sexp(s).unwrap().1);
+
+        assert_eq!(eval(&mut env, "((lambda (a . rest) rest) 1 2 3)"), parse_sexp("(2 3)").unwrap().1);
+        assert_eq!(eval(&mut env, "((lambda (a &rest rest) rest) 1 2 3)"), parse_sexp("(2 3)").unwrap().1);
+        assert_eq!(eval(&mut env, "((lambda args args) 1 2)"), parse_sexp("(1 2)").unwrap().1);
+        assert_eq!(eval(&mut env, "((lambda (a . rest) rest) 1)"), Sexp::nil());
+        assert_eq!(eval(&mut env, "((lambda (a &rest rest) a) 1)"), Sexp::int(1));
+
+        eval(&mut env, "(define (f a . rest) (cons a rest))");
+        assert_eq!(eval(&mut env, "(f 1 2 3)"), parse_sexp("(1 2 3)").unwrap().1);
+    }
+
+    #[test]
+    fn curry_with_rest_params() {
+        let mut env = Env::new();
+        let expr = parse_sexp("(((lambda (a b . rest) (cons a (cons b rest))) 1) 2 3 4)").unwrap().1;
+        assert_eq!(env.evaluate(expr), parse_sexp("(1 2 3 4)").unwrap().1);
+    }
+
+    #[test]
+    fn eval_macro_rest_params() {
+        let mut env = Env::new();
+        let expr = parse_sexp("((macro (f &rest args) (cons f args)) car '(1 2))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::int(1));
+    }
+
+    #[test]
+    fn eval_optional_params() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        eval(&mut env, "(define f (lambda (a #:optional (b a) c) (cons a (cons b c))))").unwrap();
+        assert_eq!(eval(&mut env, "(f 1)"), Ok(parse_sexp("(1 1)").unwrap().1));
+        assert_eq!(eval(&mut env, "(f 1 2)"), Ok(parse_sexp("(1 2)").unwrap().1));
+        assert_eq!(eval(&mut env, "(f 1 2 (cons 3 '()))"), Ok(parse_sexp("(1 2 3)").unwrap().1));
+
+        // Still curried if the required params are missing.
+        assert_eq!(eval(&mut env, "((f) 1)"), Ok(parse_sexp("(1 1)").unwrap().1));
+
+        eval(&mut env, "(define g (lambda (#:optional (a 1) . rest) (cons a rest)))").unwrap();
+        assert_eq!(eval(&mut env, "(g)"), Ok(parse_sexp("(1)").unwrap().1));
+        assert_eq!(eval(&mut env, "(g 2 3)"), Ok(parse_sexp("(2 3)").unwrap().1));
+    }
+
+    #[test]
+    fn eval_keyword_params() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        assert_eq!(eval(&mut env, ":width"), Ok(Sexp::identifier(":width")));
+
+        eval(&mut env, "(define (window title #:key (width 80) (height width)) (cons title (cons width height)))").unwrap();
+        assert_eq!(eval(&mut env, "(window 1)"), Ok(parse_sexp("(1 80 . 80)").unwrap().1));
+        assert_eq!(eval(&mut env, "(window 1 :height 2)"), Ok(parse_sexp("(1 80 . 2)").unwrap().1));
+        assert_eq!(eval(&mut env, "(window 1 :height 2 :width 3)"), Ok(parse_sexp("(1 3 . 2)").unwrap().1));
+
+        let e = eval(&mut env, "(window 1 :depth 2)").unwrap_err();
+        assert_eq!(e, EvalError::UnknownKeyword(":depth".to_string()));
+        let e = eval(&mut env, "(window 1 :width)").unwrap_err();
+        assert_eq!(e, EvalError::MissingKeywordValue(":width".to_string()));
+    }
+
+    #[test]
+    fn default_in_captured_frame() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        eval(&mut env, "(define make (lambda (x) (lambda (#:key (y x)) y)))").unwrap();
+        eval(&mut env, "(define x 2)").unwrap();
+        assert_eq!(eval(&mut env, "((make 1))"), Ok(Sexp::int(1)));
+        assert_eq!(eval(&mut env, "((make 1) :y 3)"), Ok(Sexp::int(3)));
+    }
+
+    #[test]
+    fn eager_expansion() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        // The macro reads `v` and counts its expansions in `n`.
+        eval(&mut env, "(define n '())").unwrap();
+        eval(&mut env, "(define v 1)").unwrap();
+        eval(&mut env, "(define m (macro () (set! n (cons 1 n)) v))").unwrap();
+        eval(&mut env, "(define f (lambda () (m)))").unwrap();
+        eval(&mut env, "(set! v 2)").unwrap();
+        assert_eq!(eval(&mut env, "(f)"), Ok(Sexp::int(1)));
+        assert_eq!(eval(&mut env, "(f)"), Ok(Sexp::int(1)));
+        assert_eq!(eval(&mut env, "n"), Ok(Sexp::from_vec([Sexp::int(1)])));
+
+        // A local binding shadows the macro.
+        assert_eq!(eval(&mut env, "((lambda (m) (m)) (lambda () 3))"), Ok(Sexp::int(3)));
+    }
+
+    #[test]
+    fn quasiquote() {
+        for engine in [Engine::TreeWalker, Engine::Vm] {
+            let mut env = Env::new();
+            env.set_engine(engine);
+            let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+            let read = |s: &str| parse_sexp(s).unwrap().1;
+
+            eval(&mut env, "(define b 2)").unwrap();
+            eval(&mut env, "(define l '(3 4))").unwrap();
+            assert_eq!(eval(&mut env, "`(a ,b ,@l 5 ,@'())"), Ok(read("(a 2 3 4 5)")));
+            assert_eq!(eval(&mut env, "`(1 . ,b)"), Ok(read("(1 . 2)")));
+            assert_eq!(eval(&mut env, "`(,@l . ,b)"), Ok(read("(3 4 . 2)")));
+            assert_eq!(eval(&mut env, "((lambda (x) `(x ,x (,x))) 1)"), Ok(read("(x 1 (1))")));
+            eval(&mut env, "(define unless (macro (c . body) `(if ,c () (begin ,@body))))").unwrap();
+            assert_eq!(eval(&mut env, "(unless (eq b 1) 'x b)"), Ok(Sexp::int(2)));
+
+            // Only the unquotes of the outermost level are evaluated.
+            assert_eq!(eval(&mut env, "`(1 `(2 ,(3 ,b)))"), Ok(read("(1 `(2 ,(3 2)))")));
+            assert_eq!(eval(&mut env, "`(1 `(2 ,@(,@l)))"), Ok(read("(1 `(2 ,@(3 4)))")));
+
+            let error = EvalError::raise("type-error", "Expected a list to splice, found 2");
+            assert_eq!(eval(&mut env, "`(,@b 1)"), Err(error));
+            let error = EvalError::raise("syntax-error", "unquote-splicing is not in a list: (unquote-splicing l)");
+            assert_eq!(eval(&mut env, "`,@l"), Err(error));
+            let error = EvalError::raise("syntax-error", "unquote is outside of quasiquote");
+            assert_eq!(eval(&mut env, ",b"), Err(error.clone()));
+            assert_eq!(eval(&mut env, "((lambda () (cons 1 ,b)))"), Err(error));
+            let error = EvalError::raise("syntax-error", "unquote-splicing is outside of quasiquote");
+            assert_eq!(eval(&mut env, ",@l"), Err(error));
+        }
+    }
+
+    #[test]
+    fn lazy_expansion() {
+        let mut env = Env::new();
+        env.set_eager_expansion(false);
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        eval(&mut env, "(define n '())").unwrap();
+        eval(&mut env, "(define v 1)").unwrap();
+        eval(&mut env, "(define m (macro () (set! n (cons 1 n)) v))").unwrap();
+        eval(&mut env, "(define f (lambda () (m)))").unwrap();
+        eval(&mut env, "(set! v 2)").unwrap();
+        assert_eq!(eval(&mut env, "(f)"), Ok(Sexp::int(2)));
+        assert_eq!(eval(&mut env, "(f)"), Ok(Sexp::int(2)));
+        assert_eq!(eval(&mut env, "n"), Ok(Sexp::from_vec([Sexp::int(1), Sexp::int(1)])));
+    }
+
+    #[test]
+    fn strict_arity() {
+        let mut env = Env::new();
+        env.set_strict_arity(true);
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        eval(&mut env, "(define f (lambda (a b #:optional c) a))").unwrap();
+        assert_eq!(eval(&mut env, "(f 1 2)"), Ok(Sexp::int(1)));
+        assert_eq!(eval(&mut env, "(f 1 2 3)"), Ok(Sexp::int(1)));
+
+        let e = eval(&mut env, "(f 1)").unwrap_err();
+        assert_eq!(e.to_string(), "Wrong number of arguments to f: expected 2 to 3, found 1");
+        let e = eval(&mut env, "(f 1 2 3 4)").unwrap_err();
+        assert_eq!(e.to_string(), "Wrong number of arguments to f: expected 2 to 3, found 4");
+        let e = eval(&mut env, "((lambda (a) a))").unwrap_err();
+        assert_eq!(e.to_string(), "Wrong number of arguments to λ: expected exactly 1, found 0");
+        let e = eval(&mut env, "((macro (a) a) 1 2)").unwrap_err();
+        assert_eq!(e.to_string(), "Wrong number of arguments to macro: expected exactly 1, found 2");
+
+        assert_eq!(eval(&mut env, "((lambda (a . rest) a) 1 2 3)"), Ok(Sexp::int(1)));
+        assert_eq!(eval(&mut env, "((lambda (a #:key b) b) 1 :b 2)"), Ok(Sexp::int(2)));
+    }
+
+    #[test]
+    fn opt_out_of_strict_arity() {
+        let mut env = Env::new();
+        env.set_strict_arity(true);
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        eval(&mut env, "(define f (lambda (#:curry a b c) (cons a (cons b c))))").unwrap();
+        assert_eq!(eval(&mut env, "(((f 1) 2) '(3))"), Ok(parse_sexp("(1 2 3)").unwrap().1));
+        assert_eq!(eval(&mut env, "((f 1) 2 '(3) 4)"), Ok(parse_sexp("(1 2 3)").unwrap().1));
+    }
+
+    #[test]
+    fn set_shared_binding() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        eval(&mut env, "(define make (lambda (xs) (cons (lambda (x) (set! xs (cons x xs))) (lambda () xs))))").unwrap();
+        eval(&mut env, "(define c (make '()))").unwrap();
+        eval(&mut env, "((car c) 1)").unwrap();
+        eval(&mut env, "((car c) 2)").unwrap();
+        assert_eq!(eval(&mut env, "((cdr c))"), Ok(parse_sexp("(2 1)").unwrap().1));
+
+        // Another closure has its own binding.
+        eval(&mut env, "(define d (make '()))").unwrap();
+        assert_eq!(eval(&mut env, "((cdr d))"), Ok(Sexp::nil()));
+    }
+
+    #[test]
+    fn set_global() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        eval(&mut env, "(define a 1)").unwrap();
+        assert_eq!(eval(&mut env, "(set! a 2)"), Ok(Sexp::nil()));
+        assert_eq!(env.get("a"), Some(Sexp::int(2)));
+
+        // The param shadows the global binding.
+        eval(&mut env, "((lambda (a) (set! a 3)) 1)").unwrap();
+        assert_eq!(env.get("a"), Some(Sexp::int(2)));
+
+        let e = eval(&mut env, "(set! b 1)").unwrap_err();
+        assert_eq!(e, EvalError::UnboundIdentifier("b".to_string()));
+        assert!(env.get("b").is_none());
+    }
+
+    #[test]
+    fn internal_define() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        eval(&mut env, "(define a 1)").unwrap();
+        eval(&mut env, "(define f (lambda (x) (define a x) a))").unwrap();
+        assert_eq!(eval(&mut env, "(f 2)"), Ok(Sexp::int(2)));
+        assert_eq!(env.get("a"), Some(Sexp::int(1)));
+
+        // The internal definitions outlive the call in the closures.
+        eval(&mut env, "(define make (lambda (x) (define (get) x) get))").unwrap();
+        assert_eq!(eval(&mut env, "((make 3))"), Ok(Sexp::int(3)));
+        assert!(env.get("get").is_none());
+    }
+
+    #[test]
+    fn mutually_recursive_internal_define() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        eval(
+            &mut env,
+            "(define (even? n)
+               (define is-even (lambda (n) (if (eq n '()) #t (is-odd (cdr n)))))
+               (define is-odd (lambda (n) (if (eq n '()) #f (is-even (cdr n)))))
+               (is-even n))",
+        )
+        .unwrap();
+        assert_eq!(eval(&mut env, "(even? '(1 1 1 1))"), Ok(Sexp::bool(true)));
+        assert_eq!(eval(&mut env, "(even? '(1 1 1))"), Ok(Sexp::bool(false)));
+        assert!(env.get("is-even").is_none());
+        assert!(env.get("is-odd").is_none());
+    }
+
+    #[test]
+    fn eval_letrec() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        let res = eval(
+            &mut env,
+            "(letrec ((is-even (lambda (n) (if (eq n '()) #t (is-odd (cdr n)))))
+                      (is-odd (lambda (n) (if (eq n '()) #f (is-even (cdr n))))))
+               (cons (is-even '(1 1)) (is-odd '(1 1))))",
+        );
+        assert_eq!(res, Ok(Sexp::cons(Sexp::bool(true), Sexp::bool(false))));
+        assert!(env.get("is-even").is_none());
+
+        let e = eval(&mut env, "(letrec ((a 1) (b a)) b)").unwrap_err();
+        assert_eq!(e, EvalError::UnboundIdentifier("a".to_string()));
+    }
+
+    #[test]
+    fn eval_letrec_star() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        assert_eq!(eval(&mut env, "(letrec* ((a 1) (b a)) b)"), Ok(Sexp::int(1)));
+        let res = eval(&mut env, "(letrec* ((xs (cons 1 (lambda () xs))) (ys ((cdr xs)))) (car ys))");
+        assert_eq!(res, Ok(Sexp::int(1)));
+        assert!(env.get("a").is_none());
+    }
+
+    #[test]
+    fn eval_begin() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        assert_eq!(eval(&mut env, "(begin (define a 1) (define b 2) (cons a b))"), Ok(Sexp::cons(Sexp::int(1), Sexp::int(2))));
+        assert_eq!(env.get("a"), Some(Sexp::int(1)));
+        assert_eq!(eval(&mut env, "(begin)"), Ok(Sexp::nil()));
+    }
+
+    #[test]
+    fn multi_form_bodies() {
+        let mut env = Env::new();
+        let eval = |env: &mut Env, s: &str| env.try_evaluate(parse_sexp(s).unwrap().1);
+
+        assert_eq!(eval(&mut env, "((lambda (x) (define y x) (cons x y)) 1)"), Ok(Sexp::cons(Sexp::int(1), Sexp::int(1))));
+        assert_eq!(eval(&mut env, "((macro (a) 'ignored a) (car '(1 2)))"), Ok(Sexp::int(1)));
+
+        eval(&mut env, "(define (f x) (set! x (cons x '())) x)").unwrap();
+        assert_eq!(eval(&mut env, "(f 1)"), Ok(parse_sexp("(1)").unwrap().1));
+        assert_eq!(eval(&mut env, "(((lambda (a b) a b) 1) 2)"), Ok(Sexp::int(2)));
+
+        let e = eval(&mut env, "(define a 1 2)").unwrap_err();
+        assert!(matches!(e, EvalError::WrongArity { found: 3, .. }));
+    }
+
     #[test]
     fn test_macro_quoted_arg() {
         let mut env = Env::new();
-        env.set_global(Sexp::identifier("a"), Sexp::int(1));
+        env.set_global("a", Sexp::int(1));
         assert_eq!(env.get("a").unwrap(), Sexp::int(1));
 
         let arg = Sexp::from_vec([Sexp::define(), Sexp::identifier("a"), Sexp::int(2)]);
@@ -425,7 +1502,7 @@ mod test {
         let mut v = 0;
         let f = move |_, _: &mut Env| {
             v += 1;
-            Sexp::int(v)
+            Ok(Sexp::int(v))
         };
         let rustfn_expr = unsafe { Sexp::rust_fn(f) };
         let expr = Sexp::from_vec([rustfn_expr]);
@@ -482,4 +1559,447 @@ mod test {
         assert_eq!(Sexp::nil(), Sexp::nil());
         assert_eq!(res, Sexp::bool(true));
     }
+
+    #[test]
+    fn unbound_identifier() {
+        let mut env = Env::new();
+        let res = env.try_evaluate(parse_sexp("(undefined-function 1)").unwrap().1);
+        assert_eq!(res, Err(EvalError::UnboundIdentifier("undefined-function".to_string())));
+    }
+
+    #[test]
+    fn not_applicable() {
+        let mut env = Env::new();
+        let res = env.try_evaluate(parse_sexp("(1 2)").unwrap().1);
+        assert_eq!(res, Err(EvalError::NotApplicable("1".to_string())));
+    }
+
+    #[test]
+    fn wrong_arity() {
+        let mut env = Env::new();
+        let res = env.try_evaluate(parse_sexp("(cons 1)").unwrap().1);
+        assert_eq!(
+            res,
+            Err(EvalError::WrongArity {
+                name: "cons".to_string(),
+                expected: Arity::exactly(2),
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn recover_after_error() {
+        let mut env = Env::new();
+        env.evaluate(parse_sexp("(define f (lambda (a) (g a)))").unwrap().1);
+        assert!(env.try_evaluate(parse_sexp("(f 1)").unwrap().1).is_err());
+        assert!(env.top_frame().is_none());
+
+        env.evaluate(parse_sexp("(define g (lambda (a) a))").unwrap().1);
+        let res = env.try_evaluate(parse_sexp("(f 1)").unwrap().1);
+        assert_eq!(res, Ok(Sexp::int(1)));
+    }
+
+    #[test]
+    fn reentrant_rust_fn() {
+        let mut env = Env::new();
+        let f = |args: crate::sexp::Ptr<Sexp>, env: &mut Env| env.try_evaluate(args.car());
+        env.set_global("f", unsafe { Sexp::rust_fn(f) });
+        let res = env.try_evaluate(parse_sexp("(f (f 1))").unwrap().1);
+        assert_eq!(res, Err(EvalError::ReentrantCall));
+    }
+
+    #[test]
+    fn catch_error() {
+        let mut env = Env::new();
+        let res = env.evaluate(parse_sexp("(try (undefined 1) (unbound-identifier e 1) (else e 2))").unwrap().1);
+        assert_eq!(res, Sexp::int(1));
+        let res = env.evaluate(parse_sexp("(try (cons 1) (unbound-identifier e 1) (else e 2))").unwrap().1);
+        assert_eq!(res, Sexp::int(2));
+        let res = env.evaluate(parse_sexp("(try '(1 2) (else e 2))").unwrap().1);
+        assert_eq!(res, parse_sexp("(1 2)").unwrap().1);
+    }
+
+    #[test]
+    fn bind_caught_error() {
+        let mut env = Env::new();
+        env.set_global("a", Sexp::int(1));
+        let res = env.evaluate(parse_sexp("(try (cons a) (arity-error e (cons a e)))").unwrap().1);
+        let error = Sexp::error("arity-error", Sexp::string("Wrong number of arguments to cons: expected exactly 2, found 1"));
+        assert_eq!(res, Sexp::cons(Sexp::int(1), error));
+    }
+
+    #[test]
+    fn rethrow_unmatched_error() {
+        let mut env = Env::new();
+        let res = env.try_evaluate(parse_sexp("(try (try (undefined) (arity-error e 1)) (unbound-identifier e 2))").unwrap().1);
+        assert_eq!(res, Ok(Sexp::int(2)));
+        let res = env.try_evaluate(parse_sexp("(try (undefined) (arity-error e 1))").unwrap().1);
+        assert_eq!(res, Err(EvalError::UnboundIdentifier("undefined".to_string())));
+        assert!(env.top_frame().is_none());
+    }
+
+    #[test]
+    fn catch_raised_value() {
+        let mut env = Env::new();
+        let f = |args: crate::sexp::Ptr<Sexp>, _: &mut Env| Err(EvalError::Raised(args.car()));
+        env.set_global("raise", unsafe { Sexp::rust_fn(f) });
+        let res = env.evaluate(parse_sexp("(try (raise 42) (type-error e 1) (else e e))").unwrap().1);
+        assert_eq!(res, Sexp::int(42));
+    }
+
+    #[test]
+    fn out_of_fuel() {
+        let mut env = Env::new();
+        env.set_fuel(1000);
+        env.evaluate(parse_sexp("(define (f) (f))").unwrap().1);
+        let res = env.try_evaluate(parse_sexp("(f)").unwrap().1);
+        assert_eq!(res, Err(EvalError::OutOfFuel));
+        assert_eq!(env.fuel(), Some(0));
+        assert!(env.top_frame().is_none());
+
+        env.set_fuel(1000);
+        let res = env.try_evaluate(parse_sexp("(try (f) (else e 1))").unwrap().1);
+        assert_eq!(res, Err(EvalError::OutOfFuel));
+
+        env.set_fuel(10);
+        let res = env.try_evaluate(parse_sexp("(car '(1 2))").unwrap().1);
+        assert_eq!(res, Ok(Sexp::int(1)));
+        assert!(env.fuel().unwrap() < 10);
+    }
+
+    #[test]
+    fn rust_fn_consumes_fuel() {
+        let mut env = Env::new();
+        let f = |_, _: &mut Env| Ok(Sexp::int(1));
+        env.set_global("f", unsafe { Sexp::rust_fn(f) });
+
+        // Two steps to resolve `f` and apply it, one to call `f` and one to evaluate the result.
+        env.set_fuel(4);
+        assert_eq!(env.try_evaluate(parse_sexp("(f)").unwrap().1), Ok(Sexp::int(1)));
+        env.set_fuel(3);
+        assert_eq!(env.try_evaluate(parse_sexp("(f)").unwrap().1), Err(EvalError::OutOfFuel));
+    }
+
+    #[test]
+    fn depth_exceeded() {
+        let mut env = Env::new();
+        env.set_max_depth(100);
+        env.set_global("l", Sexp::from_vec(vec![Sexp::int(1); 1000]));
+        env.evaluate(parse_sexp("(define (f l) (if (eq l '()) '() (cons 1 (f (cdr l)))))").unwrap().1);
+
+        let res = env.try_evaluate(parse_sexp("(f l)").unwrap().1);
+        assert_eq!(res, Err(EvalError::DepthExceeded(100)));
+        assert_eq!(env.depth(), 0);
+        assert!(env.top_frame().is_none());
+
+        let res = env.try_evaluate(parse_sexp("(try (f l) (depth-exceeded e 1))").unwrap().1);
+        assert_eq!(res, Ok(Sexp::int(1)));
+    }
+
+    #[test]
+    fn deep_recursion() {
+        fn add(n: i32) -> Ptr<Sexp> {
+            unsafe {
+                Sexp::rust_fn_with_evaluated_args(move |args, _env| match args.car().as_ref() {
+                    Sexp::I32(m) => Ok(Sexp::int(m + n)),
+                    _ => unreachable!(),
+                })
+            }
+        }
+
+        for engine in [Engine::TreeWalker, Engine::Vm] {
+            let mut env = Env::new();
+            env.set_engine(engine);
+            env.set_global("inc", add(1));
+            env.set_global("dec", add(-1));
+            env.evaluate(parse_sexp("(define (count n) (if (eq n 0) 0 (inc (count (dec n)))))").unwrap().1);
+
+            // The lookups don't walk the frames of the pending calls.
+            let res = env.try_evaluate(parse_sexp("(count 100000)").unwrap().1);
+            assert_eq!(res, Ok(Sexp::int(100_000)));
+
+            // The tail calls replace the frames of their callers.
+            env.evaluate(parse_sexp("(define (down n) (if (eq n 0) 'done (down (dec n))))").unwrap().1);
+            let res = env.try_evaluate(parse_sexp("(down 10000)").unwrap().1);
+            assert_eq!(res, Ok(Sexp::identifier("done")));
+        }
+    }
+
+    #[test]
+    fn locate_runtime_error() {
+        let mut env = Env::new();
+        let spans = env.spans().clone();
+        let source = "(define (f a)\n  (g a))\n(f 1)\n(f undefined)\n";
+        let mut res = Reader::new("test.risp", source, &spans).map(|s| env.try_evaluate(s.unwrap()));
+
+        assert!(res.next().unwrap().is_ok());
+        let e = res.next().unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "test.risp:2:3: Cannot find ident: g");
+        assert_eq!(e.root(), &EvalError::UnboundIdentifier("g".to_string()));
+        let e = res.next().unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "test.risp:4:4: Cannot find ident: undefined");
+    }
+
+    #[test]
+    fn locate_form_error() {
+        let mut env = Env::new();
+        let spans = env.spans().clone();
+        let source = "(define (f a)\n  (if a))\n(f 1)\n";
+        let mut res = Reader::new("test.risp", source, &spans).map(|s| env.try_evaluate(s.unwrap()));
+
+        assert!(res.next().unwrap().is_ok());
+        let e = res.next().unwrap().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "test.risp:2:3: Wrong number of arguments to if: expected 2 to 3, found 1"
+        );
+    }
+
+    #[test]
+    fn backtrace() {
+        let mut env = Env::new();
+        let spans = env.spans().clone();
+        let source = "(define (f n)\n  (undefined n))\n(define (g n)\n  (cons n (f n)))\n(define (h n) (g n))\n(h 1)\n";
+        let res = Reader::new("test.risp", source, &spans)
+            .map(|s| env.try_evaluate(s.unwrap()))
+            .last();
+
+        assert!(res.unwrap().is_err());
+        let backtrace = env.backtrace().unwrap();
+        assert_eq!(backtrace.frames.len(), 2);
+        assert_eq!(backtrace.frames[0].kind, CallKind::Lambda);
+        assert_eq!(backtrace.frames[0].callee, "f");
+        assert_eq!(backtrace.frames[0].args, vec![Sexp::int(1)]);
+        // `(g n)` is a tail call in `h`, which replaces the call of `h`.
+        assert_eq!(
+            backtrace.to_string(),
+            "Backtrace (most recent call first):\n  0: (f 1) at test.risp:4:11\n  1: (g 1) at test.risp:5:15\n"
+        );
+
+        assert!(env.try_evaluate(parse_sexp("(f 1)").unwrap().1).is_err());
+        assert_eq!(env.backtrace().unwrap().frames.len(), 1);
+        assert!(env.try_evaluate(parse_sexp("(try (f 1) (else e e))").unwrap().1).is_ok());
+        assert!(env.backtrace().is_none());
+    }
+
+    #[test]
+    fn backtrace_limit() {
+        let mut env = Env::new();
+        env.set_backtrace_limit(2);
+        env.evaluate(parse_sexp("(define (f l) (if (eq l '()) (undefined) (cons 1 (f (cdr l)))))").unwrap().1);
+        assert!(env.try_evaluate(parse_sexp("(f '(1 2 3))").unwrap().1).is_err());
+
+        let backtrace = env.take_backtrace().unwrap();
+        assert_eq!(backtrace.omitted, 2);
+        assert_eq!(backtrace.frames[0].args, vec![Sexp::nil()]);
+        assert_eq!(backtrace.frames[1].args, vec![Sexp::from_vec([Sexp::int(3)])]);
+    }
+
+    #[test]
+    fn tail_positions() {
+        let mut env = Env::new();
+        env.set_max_depth(100);
+        env.set_global("l", Sexp::from_vec(vec![Sexp::int(1); 1000]));
+        env.evaluate(parse_sexp("(define my-if (macro (c a b) (cons 'if (cons c (cons a (cons b '()))))))").unwrap().1);
+        env.evaluate(
+            parse_sexp("(define (walk l) (my-if (eq l '()) 'done (begin 'step (letrec () (walk (cdr l))))))")
+                .unwrap()
+                .1,
+        );
+
+        let res = env.try_evaluate(parse_sexp("(walk l)").unwrap().1);
+        assert_eq!(res, Ok(Sexp::identifier("done")));
+    }
+
+    #[test]
+    fn call_cc_escape() {
+        let mut env = Env::new();
+        let expr = parse_sexp("(cons 1 (call/cc (lambda (k) (cons 2 (k 3)))))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::cons(Sexp::int(1), Sexp::int(3)));
+
+        let expr = parse_sexp("(call-with-current-continuation (lambda (k) 4))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::int(4));
+    }
+
+    #[test]
+    fn call_cc_restores_frame() {
+        let mut env = Env::new();
+        // The inner `a` is gone after escaping to `k`.
+        let expr = parse_sexp("((lambda (a) (cons (call/cc (lambda (k) ((lambda (a) (k a)) 2))) a)) 1)").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::cons(Sexp::int(2), Sexp::int(1)));
+    }
+
+    #[test]
+    fn call_cc_reentry() {
+        let mut env = Env::new();
+        // The scope of the lambda is shared by the re-entered evaluations.
+        let expr = parse_sexp(
+            "((lambda ()
+               (define saved '())
+               (define acc '())
+               (define x (call/cc (lambda (k) (set! saved k) 'a)))
+               (set! acc (cons x acc))
+               (if (eq x 'a) (saved 'b) acc)))",
+        )
+        .unwrap()
+        .1;
+        assert_eq!(env.evaluate(expr), parse_sexp("(b a)").unwrap().1);
+
+        // Re-enter a top level evaluation which has returned.
+        env.evaluate(parse_sexp("(define saved '())").unwrap().1);
+        env.evaluate(parse_sexp("(define r (cons 1 (call/cc (lambda (k) (set! saved k) 0))))").unwrap().1);
+        assert_eq!(env.get("r").unwrap(), Sexp::cons(Sexp::int(1), Sexp::int(0)));
+        env.evaluate(parse_sexp("(saved 5)").unwrap().1);
+        assert_eq!(env.get("r").unwrap(), Sexp::cons(Sexp::int(1), Sexp::int(5)));
+        assert_eq!(env.depth(), 0);
+    }
+
+    #[test]
+    fn call_cc_through_rust_fn() {
+        let mut env = Env::new();
+        let nested = unsafe { Sexp::rust_fn(|args, env| env.try_evaluate(args.car())) };
+        env.set_global("nested", nested);
+
+        let expr = parse_sexp("(cons 1 (call/cc (lambda (k) (nested (cons 2 (k 3))))))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::cons(Sexp::int(1), Sexp::int(3)));
+        // Escaping is not an error caught by `try`.
+        let expr = parse_sexp("(call/cc (lambda (k) (try (nested (k 1)) (else e 2))))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::int(1));
+
+        // The Rust function has returned, so its continuation can't be resumed.
+        env.evaluate(parse_sexp("(define saved '())").unwrap().1);
+        env.evaluate(parse_sexp("(nested (call/cc (lambda (k) (set! saved k) 1)))").unwrap().1);
+        let res = env.try_evaluate(parse_sexp("(saved 2)").unwrap().1);
+        let kind = match res.unwrap_err().to_value().unwrap().as_ref() {
+            Sexp::Error(e) => e.kind.clone(),
+            _ => unreachable!(),
+        };
+        assert_eq!(kind, "continuation-error");
+    }
+
+    #[test]
+    fn resolved_references() {
+        let mut env = Env::new();
+        env.set_engine(Engine::TreeWalker);
+        env.evaluate(parse_sexp("(define (counter n) (define (next) (set! n (cons 1 n)) n) next)").unwrap().1);
+        env.evaluate(parse_sexp("(define c (counter '()))").unwrap().1);
+        env.evaluate(parse_sexp("(c)").unwrap().1);
+        assert_eq!(env.evaluate(parse_sexp("(c)").unwrap().1), Sexp::from_vec([Sexp::int(1), Sexp::int(1)]));
+
+        let expr = parse_sexp("(letrec* ((a 1) (b (cons a '()))) (((lambda (x y) (cons x (cons y b))) a) 2))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::from_vec([Sexp::int(1), Sexp::int(2), Sexp::int(1)]));
+    }
+
+    #[test]
+    fn resolved_references_fall_back_to_names() {
+        let mut env = Env::new();
+        env.set_engine(Engine::TreeWalker);
+        env.evaluate(parse_sexp("(define x 1)").unwrap().1);
+        // A function defined at the top level sees the variables of its caller.
+        env.evaluate(parse_sexp("(define (f) x)").unwrap().1);
+        assert_eq!(env.evaluate(parse_sexp("((lambda (x) (f)) 2)").unwrap().1), Sexp::int(2));
+        env.evaluate(parse_sexp("(define (outer q) (inner))").unwrap().1);
+        env.evaluate(parse_sexp("(define (inner) q)").unwrap().1);
+        assert_eq!(env.evaluate(parse_sexp("(outer 3)").unwrap().1), Sexp::int(3));
+        // A variable defined by `eval` shadows the global one.
+        let expr = parse_sexp("((lambda () (eval '(define x 3)) x))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::int(3));
+        assert_eq!(env.evaluate(parse_sexp("x").unwrap().1), Sexp::int(1));
+
+        // The globals defined after the reference are found.
+        env.evaluate(parse_sexp("(define (g) y)").unwrap().1);
+        env.evaluate(parse_sexp("(define y 4)").unwrap().1);
+        assert_eq!(env.evaluate(parse_sexp("(g)").unwrap().1), Sexp::int(4));
+    }
+
+    fn vm_env() -> Env {
+        let mut env = Env::new();
+        env.set_engine(Engine::Vm);
+        env
+    }
+
+    #[test]
+    fn vm_closure_upvalues() {
+        let mut env = vm_env();
+        env.evaluate(parse_sexp("(define counter (lambda (n) (lambda () (set! n (cons 1 n)) n)))").unwrap().1);
+        env.evaluate(parse_sexp("(define c (counter '()))").unwrap().1);
+        env.evaluate(parse_sexp("(c)").unwrap().1);
+        let res = env.evaluate(parse_sexp("(c)").unwrap().1);
+        assert_eq!(res, Sexp::from_vec([Sexp::int(1), Sexp::int(1)]));
+        assert!(matches!(env.get("c").as_deref(), Some(Sexp::Closure(_))));
+    }
+
+    #[test]
+    fn vm_tail_calls() {
+        let mut env = vm_env();
+        env.set_max_depth(100);
+        env.set_global("l", Sexp::from_vec(vec![Sexp::int(1); 1000]));
+        env.evaluate(parse_sexp("(define (walk l) (if (eq l '()) 'done (begin 'step (walk (cdr l)))))").unwrap().1);
+
+        let res = env.try_evaluate(parse_sexp("(walk l)").unwrap().1);
+        assert_eq!(res, Ok(Sexp::identifier("done")));
+        assert_eq!(env.depth(), 0);
+    }
+
+    #[test]
+    fn vm_letrec_and_internal_defines() {
+        let mut env = vm_env();
+        let expr = parse_sexp(
+            "((lambda (l) (define (even? l) (if (eq l '()) #t (odd? (cdr l)))) \
+               (define (odd? l) (if (eq l '()) #f (even? (cdr l)))) \
+               (letrec* ((a (even? l)) (b (cons a '()))) b)) '(1 2 3 4))",
+        )
+        .unwrap()
+        .1;
+        assert_eq!(env.evaluate(expr), Sexp::from_vec([Sexp::bool(true)]));
+    }
+
+    #[test]
+    fn vm_falls_back_to_tree_walker() {
+        let mut env = vm_env();
+        // `try` and `call/cc` are left to the tree walker, inside compiled code.
+        let expr = parse_sexp("((lambda (x) (try (undefined x) (unbound-identifier e (cons x 1)))) 2)").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::cons(Sexp::int(2), Sexp::int(1)));
+        let expr = parse_sexp("((lambda (x) (cons x (call/cc (lambda (k) (k 3) 4)))) 1)").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::cons(Sexp::int(1), Sexp::int(3)));
+    }
+
+    #[test]
+    fn vm_curry_and_strict_arity() {
+        let mut env = vm_env();
+        env.evaluate(parse_sexp("(define pair (lambda (a b) (cons a b)))").unwrap().1);
+        let res = env.evaluate(parse_sexp("((pair 1) 2)").unwrap().1);
+        assert_eq!(res, Sexp::cons(Sexp::int(1), Sexp::int(2)));
+
+        env.set_strict_arity(true);
+        let res = env.try_evaluate(parse_sexp("(pair 1)").unwrap().1);
+        assert!(matches!(res, Err(EvalError::WrongArity { found: 1, .. })));
+        assert_eq!(env.depth(), 0);
+    }
+
+    #[test]
+    fn vm_global_caches() {
+        let mut env = vm_env();
+        env.evaluate(parse_sexp("(define f (lambda () 1))").unwrap().1);
+        env.evaluate(parse_sexp("(define g (lambda () (f)))").unwrap().1);
+        assert_eq!(env.evaluate(parse_sexp("(g)").unwrap().1), Sexp::int(1));
+
+        // The cached call sites see the globals redefined in any way.
+        env.evaluate(parse_sexp("(define f (lambda () 2))").unwrap().1);
+        assert_eq!(env.evaluate(parse_sexp("(g)").unwrap().1), Sexp::int(2));
+        env.evaluate(parse_sexp("(set! f (lambda () 3))").unwrap().1);
+        assert_eq!(env.evaluate(parse_sexp("(g)").unwrap().1), Sexp::int(3));
+        env.set_global("f", Sexp::int(4));
+        let res = env.try_evaluate(parse_sexp("(g)").unwrap().1);
+        assert!(res.is_err());
+
+        // A clone keeps its own bindings.
+        let mut other = env.clone();
+        other.evaluate(parse_sexp("(define f (lambda () 5))").unwrap().1);
+        env.evaluate(parse_sexp("(define f (lambda () 6))").unwrap().1);
+        assert_eq!(other.evaluate(parse_sexp("(g)").unwrap().1), Sexp::int(5));
+        assert_eq!(env.evaluate(parse_sexp("(g)").unwrap().1), Sexp::int(6));
+        assert_eq!(other.evaluate(parse_sexp("(g)").unwrap().1), Sexp::int(5));
+    }
 }
@@ -0,0 +1,83 @@
+use std::fmt::Display;
+
+use gc::{Finalize, Trace};
+
+use crate::sexp::{span::Location, Ptr, Sexp};
+
+/// What was applied in a call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallKind {
+    Lambda,
+    Macro,
+    RustFn,
+}
+
+/// A call on the shadow stack kept by the evaluator.
+#[derive(Clone, Trace, Finalize)]
+pub(crate) struct Call {
+    #[unsafe_ignore_trace]
+    pub kind: CallKind,
+    /// The identifier which the callee was bound to, if any.
+    pub callee: Option<Ptr<Sexp>>,
+    pub args: Ptr<Sexp>,
+    /// The innermost form read from a source file when the call was made.
+    pub form: Option<Ptr<Sexp>>,
+}
+
+impl Call {
+    /// The name of the callee, or `λ`, `macro` and `rustfn` for anonymous ones.
+    pub fn name(&self) -> String {
+        match (self.callee.as_ref(), self.kind) {
+            (Some(callee), _) => callee.to_string(),
+            (None, CallKind::Lambda) => Sexp::Lambda.to_string(),
+            (None, CallKind::Macro) => Sexp::Macro.to_string(),
+            (None, CallKind::RustFn) => "rustfn".to_string(),
+        }
+    }
+}
+
+/// A call in the backtrace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BacktraceFrame {
+    pub kind: CallKind,
+    /// The name of the callee, or `λ`, `macro` and `rustfn` for anonymous ones.
+    pub callee: String,
+    pub args: Vec<Ptr<Sexp>>,
+    pub location: Option<Location>,
+}
+
+impl Display for BacktraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}", self.callee)?;
+        for arg in self.args.iter() {
+            write!(f, " {}", arg)?;
+        }
+        write!(f, ")")?;
+        if let Some(location) = self.location.as_ref() {
+            write!(f, " at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+/// The innermost calls when an evaluation failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backtrace {
+    /// The calls, the most recent one first.
+    pub frames: Vec<BacktraceFrame>,
+    /// The number of outer calls which were left out.
+    pub omitted: usize,
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Backtrace (most recent call first):")?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            writeln!(f, "  {i}: {frame}")?;
+        }
+        if self.omitted > 0 {
+            writeln!(f, "  ... {} more", self.omitted)?;
+        }
+        Ok(())
+    }
+}
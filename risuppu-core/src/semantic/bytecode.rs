@@ -0,0 +1,206 @@
+use std::rc::Rc;
+
+use gc::{Finalize, Gc, GcCell, Trace};
+
+use crate::sexp::{symbol::Symbol, Ptr, Sexp};
+
+use super::env::GlobalCache;
+use super::frame::Frame;
+
+/// An instruction of the VM, which works on a stack of values.
+///
+/// The operands are indexes into the constants, the locals, the upvalues, the nested prototypes,
+/// the call sites or the code of the prototype.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Op {
+    /// Push the constant.
+    Const(u32),
+    /// Push the local, or the global named by the constant if the local hasn't been bound yet.
+    Local(u32, u32),
+    /// Pop a value into the local.
+    SetLocal(u32),
+    /// Unbind the local when entering its scope.
+    InitLocal(u32),
+    /// Push the upvalue, or the global named by the constant if it hasn't been bound yet.
+    Upvalue(u32, u32),
+    /// Pop a value into the upvalue.
+    SetUpvalue(u32),
+    /// Push the binding of the identifier named by the constant.
+    Global(u32),
+    /// Pop a value into the nearest existing binding of the identifier named by the constant.
+    SetGlobal(u32),
+    /// Pop a value and bind the identifier named by the constant in the current scope.
+    DefineGlobal(u32),
+    Pop,
+    Jump(u32),
+    /// Pop a value, and jump unless it's `#t`.
+    JumpUnlessTrue(u32),
+    /// Pop a value and compare it with the first value of `eq` below it.
+    /// If they differ, replace the first value with `#f` and jump.
+    EqNext(u32),
+    /// Replace the first value of `eq` with `#t`.
+    EqEnd,
+    Cons,
+    Car,
+    Cdr,
+    /// Pop a value and print it.
+    Print,
+    /// Push a closure of the nested prototype, capturing its upvalues.
+    Closure(u32),
+    /// Apply a macro found on the top of the stack to the unevaluated arguments of the call site,
+    /// and jump over the arguments and the call. Other callees are left for the call.
+    Dispatch(u32, u32),
+    /// Apply the callee below the arguments to them.
+    Call(u32, u32),
+    /// Apply the callee in place of the current frame.
+    TailCall(u32, u32),
+    /// Pop a value and evaluate it in the scope of the call site.
+    Eval(u32),
+    Return,
+}
+
+/// Where a variable lives in the frame running the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Loc {
+    Local(u32),
+    Upvalue(u32),
+}
+
+/// The variables visible at a call site, which the code built at runtime is compiled against,
+/// e.g. the result of a Rust function.
+#[derive(Debug, Default)]
+pub(crate) struct Scope {
+    pub names: Vec<(Symbol, Loc)>,
+    /// The first local which is free for the code.
+    pub next_slot: u32,
+    /// Whether `define` binds in the environment rather than in a local.
+    pub top_level: bool,
+    /// Whether the code is compiled inside the frames of the environment,
+    /// e.g. by a Rust function called by the tree walker, whose bindings the names which aren't locals may refer to.
+    pub in_frames: bool,
+}
+
+/// A call site, or an `eval` form.
+#[derive(Trace, Finalize)]
+pub(crate) struct Site {
+    /// The identifier which the head of the call was bound to, if any.
+    pub callee: Option<Ptr<Sexp>>,
+    /// The unevaluated arguments, which are applied to the macros found at runtime.
+    pub args: Ptr<Sexp>,
+    /// The innermost form read from a source file.
+    pub form: Option<Ptr<Sexp>>,
+    #[unsafe_ignore_trace]
+    pub scope: Rc<Scope>,
+    #[unsafe_ignore_trace]
+    pub tail: bool,
+}
+
+/// The innermost form read from a source file, for the code starting at `start`.
+#[derive(Trace, Finalize)]
+pub(crate) struct Located {
+    #[unsafe_ignore_trace]
+    pub start: u32,
+    pub form: Option<Ptr<Sexp>>,
+}
+
+/// The compiled code of a lambda, or of an expression.
+#[derive(Trace, Finalize)]
+pub(crate) struct Proto {
+    #[unsafe_ignore_trace]
+    pub code: Vec<Op>,
+    pub constants: Vec<Ptr<Sexp>>,
+    /// The caches of the globals named by the constants.
+    #[unsafe_ignore_trace]
+    pub caches: Vec<GlobalCache>,
+    /// The prototypes of the nested lambdas.
+    pub protos: Vec<Gc<Proto>>,
+    pub sites: Vec<Site>,
+    pub located: Vec<Located>,
+    /// The number of required params.
+    #[unsafe_ignore_trace]
+    pub params: usize,
+    /// Whether the remaining arguments are bound as a list after the required params.
+    #[unsafe_ignore_trace]
+    pub rest: bool,
+    /// The number of locals, including the params.
+    #[unsafe_ignore_trace]
+    pub locals: usize,
+    /// Where the upvalues are captured from in the frame making the closure.
+    #[unsafe_ignore_trace]
+    pub captures: Vec<Loc>,
+    /// The params and the body of the lambda, for printing the closures.
+    pub source: Ptr<Sexp>,
+    /// Whether the globals are looked up in the frames of the environment first, see [`Scope::in_frames`].
+    #[unsafe_ignore_trace]
+    pub in_frames: bool,
+}
+
+impl Proto {
+    /// The innermost form read from a source file, for the instruction at `ip`.
+    pub fn located_at(&self, ip: usize) -> Option<&Ptr<Sexp>> {
+        let i = self.located.partition_point(|located| located.start as usize <= ip);
+        self.located[..i].last().and_then(|located| located.form.as_ref())
+    }
+
+    /// The identifier named by the constant.
+    pub fn name(&self, constant: u32) -> Symbol {
+        match self.constants[constant as usize].as_ref() {
+            Sexp::Identifier(ident) => *ident,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A captured variable, shared by the closures and the frame which bound it.
+///
+/// It's empty until the variable is bound, e.g. by `letrec`.
+pub(crate) type Upvalue = Gc<GcCell<Option<Ptr<Sexp>>>>;
+
+/// A lambda compiled for the VM, with the variables it captured.
+#[derive(Clone, Trace, Finalize)]
+pub struct Closure {
+    data: Gc<ClosureData>,
+}
+
+#[derive(Trace, Finalize)]
+pub(crate) struct ClosureData {
+    pub proto: Gc<Proto>,
+    pub upvalues: Vec<Upvalue>,
+    /// The frame where the globals are looked up.
+    pub frame: Option<Gc<GcCell<Frame>>>,
+    /// The arguments applied so far, if the closure is curried.
+    pub bound: Vec<Ptr<Sexp>>,
+}
+
+impl Closure {
+    pub(crate) fn new(data: ClosureData) -> Self {
+        Self { data: Gc::new(data) }
+    }
+
+    pub(crate) fn data(&self) -> &ClosureData {
+        &self.data
+    }
+}
+
+impl std::fmt::Display for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.data.proto.source.as_ref() {
+            Sexp::Form(lambda) => write!(f, "(λ {lambda})"),
+            _ => write!(f, "λ"),
+        }
+    }
+}
+
+impl std::fmt::Debug for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Closure({:p})", &*self.data)
+    }
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        Gc::ptr_eq(&self.data, &other.data)
+    }
+}
+
+impl Eq for Closure {}
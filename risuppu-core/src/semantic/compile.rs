@@ -0,0 +1,694 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use gc::Gc;
+
+use crate::sexp::{span::SpanTable, symbol::Symbol, Cons, Ptr, Sexp};
+
+use super::bytecode::{Loc, Located, Op, Proto, Scope, Site};
+use super::env::GlobalCache;
+use super::quasiquote::quasiquote;
+use super::resolve::{GlobalRef, LocalRef};
+use super::{is_marker, keyword_name, sequence, Arity, Engine, Env};
+
+/// The expression has a form which the VM leaves to the tree walker,
+/// e.g. `try`, `call/cc`, a macro call, or a lambda with `#:optional` params.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Unsupported;
+
+type Compiled<T = ()> = Result<T, Unsupported>;
+
+/// Compile a top-level expression, if the environment evaluates it on the VM.
+///
+/// Evaluations limited by fuel or watched by observers stay on the tree walker,
+/// which counts and reports each of its steps.
+pub(crate) fn compile_for(expr: &Ptr<Sexp>, env: &Env) -> Option<Gc<Proto>> {
+    if env.engine() != Engine::Vm || env.fuel().is_some() || env.has_observers() {
+        return None;
+    }
+
+    let scope = Scope {
+        top_level: true,
+        in_frames: env.top_frame().is_some(),
+        ..Default::default()
+    };
+    compile_in(expr, Rc::new(scope), env).ok()
+}
+
+/// Compile an expression built at runtime in the scope of a call site,
+/// e.g. the result of a Rust function, to run in the frame of the call.
+pub(crate) fn compile_in(expr: &Ptr<Sexp>, scope: Rc<Scope>, env: &Env) -> Compiled<Gc<Proto>> {
+    let mut compiler = Compiler::new(env, scope.in_frames);
+    compiler.functions.push(Function::new(Some(scope)));
+    compiler.expr(expr, true)?;
+
+    let function = compiler.functions.pop().unwrap();
+    Ok(function.finish(0, false, vec![], Sexp::nil(), compiler.in_frames))
+}
+
+/// A lambda, or the expression, being compiled.
+struct Function {
+    code: Vec<Op>,
+    constants: Vec<Ptr<Sexp>>,
+    names: HashMap<Symbol, u32>,
+    protos: Vec<Gc<Proto>>,
+    sites: Vec<Site>,
+    located: Vec<Located>,
+    located_form: Option<Ptr<Sexp>>,
+    /// The locals bound by the params, `letrec` and the internal defines, the innermost scope last.
+    blocks: Vec<Vec<(Symbol, u32)>>,
+    /// The upvalues, and where they are captured from in the enclosing function.
+    captures: Vec<(Symbol, Loc)>,
+    /// The scope of the call site, for an expression built at runtime.
+    outer: Option<Rc<Scope>>,
+    next_slot: u32,
+    locals: u32,
+    /// The scope of the call sites, until a variable is bound or captured.
+    scope: Option<Rc<Scope>>,
+}
+
+impl Function {
+    fn new(outer: Option<Rc<Scope>>) -> Self {
+        let next_slot = outer.as_ref().map(|scope| scope.next_slot).unwrap_or(0);
+        Self {
+            code: vec![],
+            constants: vec![],
+            names: HashMap::new(),
+            protos: vec![],
+            sites: vec![],
+            located: vec![],
+            located_form: None,
+            blocks: vec![],
+            captures: vec![],
+            outer,
+            next_slot,
+            locals: next_slot,
+            scope: None,
+        }
+    }
+
+    fn finish(self, params: usize, rest: bool, captures: Vec<Loc>, source: Ptr<Sexp>, in_frames: bool) -> Gc<Proto> {
+        Gc::new(Proto {
+            code: self.code,
+            caches: self.constants.iter().map(|_| GlobalCache::default()).collect(),
+            constants: self.constants,
+            protos: self.protos,
+            sites: self.sites,
+            located: self.located,
+            params,
+            rest,
+            locals: self.locals as usize,
+            captures,
+            source,
+            in_frames,
+        })
+    }
+
+    fn alloc(&mut self) -> u32 {
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        self.locals = self.locals.max(self.next_slot);
+        self.scope = None;
+        slot
+    }
+
+    /// Bind the identifier in the innermost scope, unless it has been bound there.
+    fn bind(&mut self, ident: Symbol) -> (u32, bool) {
+        let bound = self.blocks.last().and_then(|block| block.iter().find(|(name, _)| *name == ident));
+        if let Some((_, slot)) = bound {
+            return (*slot, false);
+        }
+        let slot = self.alloc();
+        self.blocks.last_mut().unwrap().push((ident, slot));
+        (slot, true)
+    }
+}
+
+struct Compiler<'a> {
+    env: &'a Env,
+    spans: SpanTable,
+    track_location: bool,
+    /// The enclosing functions, the innermost one last.
+    functions: Vec<Function>,
+    in_frames: bool,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(env: &'a Env, in_frames: bool) -> Self {
+        let spans = env.spans().clone();
+        Self {
+            env,
+            track_location: !spans.is_empty(),
+            spans,
+            functions: vec![],
+            in_frames,
+        }
+    }
+
+    fn function(&mut self) -> &mut Function {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let code = &mut self.function().code;
+        code.push(op);
+        code.len() - 1
+    }
+
+    /// Make the jump at `at` jump to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let code = &mut self.function().code;
+        let next = code.len() as u32;
+        match &mut code[at] {
+            Op::Jump(target) | Op::JumpUnlessTrue(target) | Op::EqNext(target) | Op::Dispatch(_, target) => {
+                *target = next
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn constant(&mut self, value: Ptr<Sexp>) {
+        let function = self.function();
+        function.constants.push(value);
+        let index = function.constants.len() as u32 - 1;
+        self.emit(Op::Const(index));
+    }
+
+    /// The constant holding the identifier.
+    fn name(&mut self, ident: Symbol) -> u32 {
+        let function = self.function();
+        if let Some(index) = function.names.get(&ident) {
+            return *index;
+        }
+        function.constants.push(Sexp::wrap(Sexp::Identifier(ident)));
+        let index = function.constants.len() as u32 - 1;
+        function.names.insert(ident, index);
+        index
+    }
+
+    /// Return from the function after an expression in tail position.
+    fn finish_tail(&mut self, tail: bool) {
+        if tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    /// Make the expression the innermost located form, returning the one it replaced.
+    fn locate(&mut self, expr: &Ptr<Sexp>) -> Option<Option<Ptr<Sexp>>> {
+        if !self.track_location || self.spans.span_of(expr).is_none() {
+            return None;
+        }
+        let function = self.function();
+        let start = function.code.len() as u32;
+        function.located.push(Located { start, form: Some(expr.clone()) });
+        Some(function.located_form.replace(expr.clone()))
+    }
+
+    fn restore_location(&mut self, outer: Option<Option<Ptr<Sexp>>>) {
+        if let Some(form) = outer {
+            let function = self.function();
+            let start = function.code.len() as u32;
+            function.located.push(Located { start, form: form.clone() });
+            function.located_form = form;
+        }
+    }
+
+    /// Find the variable in the function at `level`, capturing it from the enclosing functions,
+    /// or `None` for a global.
+    fn resolve(&mut self, level: usize, ident: Symbol) -> Option<Loc> {
+        let function = &self.functions[level];
+        for block in function.blocks.iter().rev() {
+            if let Some((_, slot)) = block.iter().rev().find(|(name, _)| *name == ident) {
+                return Some(Loc::Local(*slot));
+            }
+        }
+        if let Some(index) = function.captures.iter().position(|(name, _)| *name == ident) {
+            return Some(Loc::Upvalue(index as u32));
+        }
+        if let Some(outer) = function.outer.as_ref() {
+            return outer.names.iter().find(|(name, _)| *name == ident).map(|(_, loc)| *loc);
+        }
+        if level == 0 {
+            return None;
+        }
+
+        let loc = self.resolve(level - 1, ident)?;
+        let function = &mut self.functions[level];
+        function.captures.push((ident, loc));
+        function.scope = None;
+        Some(Loc::Upvalue(function.captures.len() as u32 - 1))
+    }
+
+    /// Capture the variables named in the arguments of a call,
+    /// which a macro found at runtime might refer to.
+    fn touch(&mut self, expr: &Ptr<Sexp>) {
+        let mut cur = expr.clone();
+        loop {
+            let next = match cur.as_ref() {
+                Sexp::Form(Cons { car, cdr }) => {
+                    self.touch(car);
+                    cdr.clone()
+                }
+                Sexp::Identifier(ident) => {
+                    self.resolve(self.functions.len() - 1, *ident);
+                    return;
+                }
+                _ => return,
+            };
+            cur = next;
+        }
+    }
+
+    /// The variables visible in the current function.
+    fn scope(&mut self) -> Rc<Scope> {
+        let in_frames = self.in_frames;
+        let function = self.function();
+        if let Some(scope) = function.scope.as_ref() {
+            return scope.clone();
+        }
+
+        let mut names: Vec<(Symbol, Loc)> = vec![];
+        let mut add = |name: Symbol, loc: Loc| match names.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = loc,
+            None => names.push((name, loc)),
+        };
+        if let Some(outer) = function.outer.as_ref() {
+            outer.names.iter().for_each(|(name, loc)| add(*name, *loc));
+        }
+        for (index, (name, _)) in function.captures.iter().enumerate() {
+            add(*name, Loc::Upvalue(index as u32));
+        }
+        for (name, slot) in function.blocks.iter().flatten() {
+            add(*name, Loc::Local(*slot));
+        }
+
+        let scope = Rc::new(Scope {
+            names,
+            next_slot: function.next_slot,
+            top_level: function.blocks.is_empty() && function.outer.as_ref().is_some_and(|outer| outer.top_level),
+            in_frames,
+        });
+        function.scope = Some(scope.clone());
+        scope
+    }
+
+    fn site(&mut self, callee: Option<Ptr<Sexp>>, args: Ptr<Sexp>, tail: bool) -> u32 {
+        let scope = self.scope();
+        let function = self.function();
+        function.sites.push(Site {
+            callee,
+            args,
+            form: function.located_form.clone(),
+            scope,
+            tail,
+        });
+        function.sites.len() as u32 - 1
+    }
+
+    fn expr(&mut self, expr: &Ptr<Sexp>, tail: bool) -> Compiled {
+        let outer = self.locate(expr);
+        let res = self.unlocated(expr, tail);
+        self.restore_location(outer);
+        res
+    }
+
+    fn unlocated(&mut self, expr: &Ptr<Sexp>, tail: bool) -> Compiled {
+        match expr.as_ref() {
+            Sexp::Form(Cons { car, cdr }) => return self.form(expr, car, cdr, tail),
+            Sexp::Identifier(ident) if keyword_name(ident).is_none() => self.variable(*ident),
+            Sexp::Local(LocalRef { name, .. }) | Sexp::Global(GlobalRef { name, .. }) => self.variable(*name),
+            _ => self.constant(expr.clone()),
+        }
+        self.finish_tail(tail);
+        Ok(())
+    }
+
+    fn variable(&mut self, ident: Symbol) {
+        let name = self.name(ident);
+        let op = match self.resolve(self.functions.len() - 1, ident) {
+            Some(Loc::Local(slot)) => Op::Local(slot, name),
+            Some(Loc::Upvalue(index)) => Op::Upvalue(index, name),
+            None => Op::Global(name),
+        };
+        self.emit(op);
+    }
+
+    fn store(&mut self, ident: Symbol) {
+        let op = match self.resolve(self.functions.len() - 1, ident) {
+            Some(Loc::Local(slot)) => Op::SetLocal(slot),
+            Some(Loc::Upvalue(index)) => Op::SetUpvalue(index),
+            None => Op::SetGlobal(self.name(ident)),
+        };
+        self.emit(op);
+    }
+
+    fn form(&mut self, form: &Ptr<Sexp>, car: &Ptr<Sexp>, cdr: &Ptr<Sexp>, tail: bool) -> Compiled {
+        match car.as_ref() {
+            Sexp::Quote => {
+                arity(cdr, Arity::exactly(1))?;
+                self.constant(cdr.car());
+            }
+            // The walker reports the errors of the template.
+            Sexp::Quasiquote => return self.expr(&quasiquote(cdr).map_err(|_| Unsupported)?, tail),
+            Sexp::If => return self.r#if(cdr, tail),
+            Sexp::Eq => self.eq(cdr)?,
+            Sexp::Cons => {
+                arity(cdr, Arity::exactly(2))?;
+                self.expr(&cdr.car(), false)?;
+                self.expr(&cdr.cdr().car(), false)?;
+                self.emit(Op::Cons);
+            }
+            Sexp::Car | Sexp::Cdr => {
+                arity(cdr, Arity::exactly(1))?;
+                self.expr(&cdr.car(), false)?;
+                self.emit(if car.as_ref() == &Sexp::Car { Op::Car } else { Op::Cdr });
+            }
+            Sexp::Print => {
+                arity(cdr, Arity::between(1, 2))?;
+                self.expr(&cdr.car(), false)?;
+                self.emit(Op::Print);
+                // Call the function after printing, or evaluate `(())` to nil without one.
+                return self.expr(&Sexp::from_vec([cdr.cdr().car()]), tail);
+            }
+            Sexp::Lambda => self.lambda(cdr)?,
+            Sexp::Macro | Sexp::CapturedLambda(_) => self.constant(form.clone()),
+            Sexp::Eval => {
+                arity(cdr, Arity::exactly(1))?;
+                self.expr(&cdr.car(), false)?;
+                let site = self.site(None, Sexp::nil(), tail);
+                self.emit(Op::Eval(site));
+                if tail {
+                    return Ok(());
+                }
+            }
+            Sexp::Begin => return self.begin(cdr, tail),
+            Sexp::Define => self.define(cdr)?,
+            Sexp::Set => self.set(cdr)?,
+            Sexp::Letrec => return self.letrec(cdr, false, tail),
+            Sexp::LetrecStar => return self.letrec(cdr, true, tail),
+            Sexp::Nil => self.constant(car.clone()),
+
+            Sexp::Identifier(ident) => return self.call_identifier(car, *ident, cdr, tail),
+            Sexp::Local(LocalRef { name, .. }) | Sexp::Global(GlobalRef { name, .. }) => {
+                return self.call_identifier(car, *name, cdr, tail)
+            }
+            Sexp::Form(head) if head.car.is_macro() => return Err(Unsupported),
+            Sexp::Form(head) => {
+                let known = head.car.is_lambda();
+                self.expr(car, false)?;
+                return self.call(None, cdr, tail, known);
+            }
+            Sexp::RustFn(f) if f.has_preprocess() => return Err(Unsupported),
+            Sexp::RustFn(f) if !f.evaluates_args() => {
+                self.constant(car.clone());
+                return self.call(None, cdr, tail, false);
+            }
+            Sexp::Read | Sexp::Require | Sexp::Provide | Sexp::Try | Sexp::CallCC => return Err(Unsupported),
+            Sexp::Unquote | Sexp::UnquoteSplicing => return Err(Unsupported),
+            _ => {
+                self.constant(car.clone());
+                return self.call(None, cdr, tail, true);
+            }
+        }
+        self.finish_tail(tail);
+        Ok(())
+    }
+
+    fn r#if(&mut self, body: &Ptr<Sexp>, tail: bool) -> Compiled {
+        arity(body, Arity::between(2, 3))?;
+        self.expr(&body.car(), false)?;
+        let to_else = self.emit(Op::JumpUnlessTrue(0));
+        self.expr(&body.cdr().car(), tail)?;
+
+        if tail {
+            self.patch(to_else);
+            return self.expr(&body.cdr().cdr().car(), true);
+        }
+        let to_end = self.emit(Op::Jump(0));
+        self.patch(to_else);
+        self.expr(&body.cdr().cdr().car(), false)?;
+        self.patch(to_end);
+        Ok(())
+    }
+
+    fn eq(&mut self, body: &Ptr<Sexp>) -> Compiled {
+        arity(body, Arity::at_least(1))?;
+        self.expr(&body.car(), false)?;
+        let mut to_end = vec![];
+        for item in Sexp::iter(body.cdr()) {
+            self.expr(&item, false)?;
+            to_end.push(self.emit(Op::EqNext(0)));
+        }
+        self.emit(Op::EqEnd);
+        to_end.into_iter().for_each(|at| self.patch(at));
+        Ok(())
+    }
+
+    fn begin(&mut self, body: &Ptr<Sexp>, tail: bool) -> Compiled {
+        let forms = Sexp::iter(body.clone()).collect::<Vec<_>>();
+        let Some((last, forms)) = forms.split_last() else {
+            self.constant(Sexp::nil());
+            self.finish_tail(tail);
+            return Ok(());
+        };
+        for form in forms {
+            self.expr(form, false)?;
+            self.emit(Op::Pop);
+        }
+        self.expr(last, tail)
+    }
+
+    /// `(params body ...)` of a lambda, with required params and a rest param.
+    fn lambda(&mut self, lambda: &Ptr<Sexp>) -> Compiled {
+        let (params, rest) = lambda_params(lambda.car())?;
+        let body = lambda.cdr();
+
+        let mut function = Function::new(None);
+        function.blocks.push(vec![]);
+        for param in params.iter().chain(rest.iter()) {
+            function.bind(*param);
+        }
+        self.functions.push(function);
+        // The internal defines are unbound when the function starts.
+        for ident in defines(&body) {
+            self.function().bind(ident);
+        }
+        let res = self.expr(&sequence(body), true);
+
+        let function = self.functions.pop().unwrap();
+        res?;
+        let captures = function.captures.iter().map(|(_, loc)| *loc).collect();
+        let proto = function.finish(params.len(), rest.is_some(), captures, lambda.clone(), self.in_frames);
+
+        let protos = &mut self.function().protos;
+        protos.push(proto);
+        let index = protos.len() as u32 - 1;
+        self.emit(Op::Closure(index));
+        Ok(())
+    }
+
+    fn define(&mut self, body: &Ptr<Sexp>) -> Compiled {
+        arity(body, Arity::at_least(2))?;
+        let target = body.car();
+        let ident = match target.as_ref() {
+            Sexp::Identifier(ident) => {
+                arity(body, Arity::exactly(2))?;
+                self.expr(&body.cdr().car(), false)?;
+                ident
+            }
+            Sexp::Form(Cons { car, cdr: params }) => match car.as_ref() {
+                Sexp::Identifier(ident) => {
+                    self.lambda(&Sexp::cons(params.clone(), body.cdr()))?;
+                    ident
+                }
+                _ => return Err(Unsupported),
+            },
+            _ => return Err(Unsupported),
+        };
+
+        let function = self.function();
+        let op = match function.blocks.last() {
+            Some(block) => match block.iter().find(|(name, _)| name == ident) {
+                Some((_, slot)) => Op::SetLocal(*slot),
+                // Not at the top of a body, so it's left to the tree walker.
+                None => return Err(Unsupported),
+            },
+            None if function.outer.as_ref().is_some_and(|outer| outer.top_level) => {
+                Op::DefineGlobal(self.name(*ident))
+            }
+            None => return Err(Unsupported),
+        };
+        self.emit(op);
+        self.constant(Sexp::nil());
+        Ok(())
+    }
+
+    fn set(&mut self, body: &Ptr<Sexp>) -> Compiled {
+        arity(body, Arity::exactly(2))?;
+        let target = body.car();
+        let Sexp::Identifier(ident) = target.as_ref() else {
+            return Err(Unsupported);
+        };
+        self.expr(&body.cdr().car(), false)?;
+        self.store(*ident);
+        self.constant(Sexp::nil());
+        Ok(())
+    }
+
+    fn letrec(&mut self, body: &Ptr<Sexp>, sequential: bool, tail: bool) -> Compiled {
+        arity(body, Arity::at_least(2))?;
+        let (bindings, body) = (body.car(), body.cdr());
+
+        let mut inits = vec![];
+        for binding in Sexp::iter(bindings) {
+            let (ident, init) = (binding.car(), binding.cdr().car());
+            let Sexp::Identifier(ident) = ident.as_ref() else {
+                return Err(Unsupported);
+            };
+            inits.push((*ident, init));
+        }
+
+        let next_slot = self.function().next_slot;
+        self.function().blocks.push(vec![]);
+        let idents = inits.iter().map(|(ident, _)| *ident).chain(defines(&body));
+        for ident in idents.collect::<Vec<_>>() {
+            if let (slot, true) = self.function().bind(ident) {
+                self.emit(Op::InitLocal(slot));
+            }
+        }
+
+        let slots = inits
+            .iter()
+            .map(|(ident, _)| self.resolve(self.functions.len() - 1, *ident))
+            .collect::<Vec<_>>();
+        for ((_, init), slot) in inits.iter().zip(slots.iter()) {
+            self.expr(init, false)?;
+            if sequential {
+                self.emit(set_local(*slot));
+            }
+        }
+        if !sequential {
+            for slot in slots.iter().rev() {
+                self.emit(set_local(*slot));
+            }
+        }
+        let res = self.expr(&sequence(body), tail);
+
+        let function = self.function();
+        function.blocks.pop();
+        function.next_slot = next_slot;
+        function.scope = None;
+        res
+    }
+
+    fn call_identifier(&mut self, head: &Ptr<Sexp>, ident: Symbol, args: &Ptr<Sexp>, tail: bool) -> Compiled {
+        let name = self.name(ident);
+        let known = match self.resolve(self.functions.len() - 1, ident) {
+            Some(Loc::Local(slot)) => {
+                self.emit(Op::Local(slot, name));
+                false
+            }
+            Some(Loc::Upvalue(index)) => {
+                self.emit(Op::Upvalue(index, name));
+                false
+            }
+            None => {
+                // Macros are expanded by the tree walker.
+                let known = match self.env.get(ident).as_deref() {
+                    Some(Sexp::Form(Cons { car, .. })) if car.is_macro() => return Err(Unsupported),
+                    Some(Sexp::Form(Cons { car, .. })) => car.is_lambda(),
+                    // The preprocess evaluates the arguments outside the scope of the locals.
+                    Some(Sexp::RustFn(f)) if f.has_preprocess() => return Err(Unsupported),
+                    Some(Sexp::RustFn(f)) => f.evaluates_args(),
+                    Some(Sexp::Closure(_)) => true,
+                    _ => false,
+                };
+                self.emit(Op::Global(name));
+                known
+            }
+        };
+        self.call(Some(head.clone()), args, tail, known)
+    }
+
+    /// Compile the arguments and the call of the callee on the top of the stack.
+    ///
+    /// Unless the callee is `known` to be a function, it might turn out to be a macro,
+    /// whose expansion can refer to the variables named in the arguments.
+    fn call(&mut self, callee: Option<Ptr<Sexp>>, args: &Ptr<Sexp>, tail: bool, known: bool) -> Compiled {
+        if !known && self.functions.len() > 1 {
+            self.touch(args);
+        }
+        let site = self.site(callee, args.clone(), tail);
+        let dispatch = self.emit(Op::Dispatch(site, 0));
+        let mut argc = 0;
+        for arg in Sexp::iter(args.clone()) {
+            self.expr(&arg, false)?;
+            argc += 1;
+        }
+        self.emit(if tail { Op::TailCall(argc, site) } else { Op::Call(argc, site) });
+        self.patch(dispatch);
+        Ok(())
+    }
+}
+
+fn set_local(loc: Option<Loc>) -> Op {
+    match loc {
+        Some(Loc::Local(slot)) => Op::SetLocal(slot),
+        _ => unreachable!(),
+    }
+}
+
+fn arity(args: &Ptr<Sexp>, expected: Arity) -> Compiled {
+    // The tree walker raises the error when the form is evaluated.
+    if expected.accepts(Sexp::iter(args.clone()).count()) {
+        Ok(())
+    } else {
+        Err(Unsupported)
+    }
+}
+
+/// The required params and the rest param, which are all the VM supports.
+fn lambda_params(mut params: Ptr<Sexp>) -> Compiled<(Vec<Symbol>, Option<Symbol>)> {
+    let mut required = vec![];
+    loop {
+        let next = match params.as_ref() {
+            Sexp::Nil => return Ok((required, None)),
+            Sexp::Identifier(rest) => return Ok((required, Some(*rest))),
+            Sexp::Form(Cons { car, cdr }) if is_marker(car, "&rest") => match (cdr.car().as_ref(), cdr.cdr().is_nil()) {
+                (Sexp::Identifier(rest), true) => return Ok((required, Some(*rest))),
+                _ => return Err(Unsupported),
+            },
+            Sexp::Form(Cons { car, cdr }) => match car.as_ref() {
+                Sexp::Identifier(param) if !param.starts_with("#:") => {
+                    required.push(*param);
+                    cdr.clone()
+                }
+                _ => return Err(Unsupported),
+            },
+            _ => return Err(Unsupported),
+        };
+        params = next;
+    }
+}
+
+/// The identifiers defined at the top of a body, including in its `begin` forms.
+pub(crate) fn defines(body: &Ptr<Sexp>) -> Vec<Symbol> {
+    let mut idents = vec![];
+    for form in Sexp::iter(body.clone()) {
+        match form.car().as_ref() {
+            Sexp::Define => {
+                let target = form.cdr().car();
+                let ident = match target.as_ref() {
+                    Sexp::Form(Cons { car, .. }) => car.clone(),
+                    _ => target.clone(),
+                };
+                if let Sexp::Identifier(ident) = ident.as_ref() {
+                    idents.push(*ident);
+                }
+            }
+            Sexp::Begin => idents.extend(defines(&form.cdr())),
+            _ => {}
+        }
+    }
+    idents
+}
@@ -0,0 +1,121 @@
+use gc::{Finalize, Gc, GcCell, Trace};
+
+use crate::sexp::{symbol::Symbol, Ptr, Sexp};
+
+use super::{backtrace::Call, frame::Frame, LoopState};
+
+/// What the evaluator does with the value of a nested evaluation.
+#[derive(Clone, Trace, Finalize)]
+pub(crate) enum Cont {
+    /// Evaluate a branch of `if`.
+    If {
+        if_branch: Ptr<Sexp>,
+        else_branch: Ptr<Sexp>,
+    },
+    /// Compare the value with the first one of `eq`, or evaluate the rest.
+    Eq {
+        first: Option<Ptr<Sexp>>,
+        rest: Ptr<Sexp>,
+    },
+    /// Evaluate the CDR of `cons`.
+    ConsCar { cdr: Ptr<Sexp> },
+    /// Pair the evaluated CAR of `cons` with the value.
+    ConsCdr { car: Ptr<Sexp> },
+    Car,
+    Cdr,
+    /// Print the value, and call `func` if any.
+    Print { func: Ptr<Sexp> },
+    /// Evaluate the value again.
+    Eval,
+    /// Evaluate the rest forms of `begin`.
+    Begin { rest: Ptr<Sexp> },
+    Define { ident: Symbol },
+    Set { ident: Symbol },
+    /// Bind the value of an init of `letrec`, and evaluate the rest inits.
+    Letrec {
+        ident: Symbol,
+        bindings: Ptr<Sexp>,
+        body: Ptr<Sexp>,
+        sequential: bool,
+        idents: Vec<Symbol>,
+        values: Vec<Ptr<Sexp>>,
+    },
+    /// Handle the error raised by the expression of `try`.
+    Try { clauses: Ptr<Sexp> },
+    /// Apply the evaluated head of a form to the arguments.
+    Head { args: Ptr<Sexp> },
+    /// Collect the evaluated arguments of a lambda or a continuation.
+    Args {
+        callee: Ptr<Sexp>,
+        rest: Ptr<Sexp>,
+        values: Vec<Ptr<Sexp>>,
+    },
+    /// Evaluate the expansion of a macro in the frame before expanding.
+    Expand {
+        form: Ptr<Sexp>,
+        frame: Option<Gc<GcCell<Frame>>>,
+    },
+    /// Call the function with the current continuation.
+    CallCC,
+}
+
+/// A nested evaluation waiting on the stack of the evaluator.
+#[derive(Clone, Trace, Finalize)]
+pub(crate) struct Nested {
+    /// The continuation which receives the value.
+    pub cont: Cont,
+    /// The state of the outer loop.
+    pub state: LoopState,
+    /// The evaluated expression, passed to the observers when it exits.
+    pub expr: Ptr<Sexp>,
+    /// The frame restored when it exits.
+    pub frame: Option<Gc<GcCell<Frame>>>,
+}
+
+/// A continuation captured by `call/cc`, which can be called with the value of the `call/cc` form.
+///
+/// Calling a continuation replaces the pending evaluations with the captured ones,
+/// so it can escape from the current evaluation or re-enter one which has returned.
+/// A continuation captured inside a Rust function, e.g. in the arguments of `+`,
+/// can only escape while the function is running, since the native stack of the function is gone.
+#[derive(Clone, Trace, Finalize)]
+pub struct Continuation {
+    data: Gc<ContinuationData>,
+}
+
+#[derive(Trace, Finalize)]
+pub(crate) struct ContinuationData {
+    /// The pending evaluations, the innermost one last.
+    pub stack: Vec<Nested>,
+    /// The state of the loop evaluating the `call/cc` form.
+    pub state: LoopState,
+    /// The calls on the shadow stack made by the run.
+    pub calls: Vec<Call>,
+    pub frame: Option<Gc<GcCell<Frame>>>,
+    /// The run of the evaluator to resume, or `None` for the top level.
+    pub run: Option<u64>,
+}
+
+impl Continuation {
+    pub(crate) fn new(data: ContinuationData) -> Self {
+        Self { data: Gc::new(data) }
+    }
+
+    pub(crate) fn data(&self) -> &ContinuationData {
+        &self.data
+    }
+}
+
+impl std::fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Continuation({:p})", &*self.data)
+    }
+}
+
+impl PartialEq for Continuation {
+    fn eq(&self, other: &Self) -> bool {
+        Gc::ptr_eq(&self.data, &other.data)
+    }
+}
+
+impl Eq for Continuation {}
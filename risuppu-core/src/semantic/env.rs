@@ -1,25 +1,110 @@
-use crate::semantic::evaluate;
+use crate::semantic::{evaluate, try_evaluate, EvalError, EvalResult};
 use std::collections::HashMap;
-use std::iter::from_fn;
-use std::ops::ControlFlow::*;
-
-use crate::sexp::{Ptr, Sexp};
+use crate::sexp::{span::SpanTable, symbol::Symbol, Ptr, Sexp};
 use gc::{Gc, GcCell};
-use super::frame::Frame;
+use super::backtrace::{Backtrace, BacktraceFrame, Call};
+use super::frame::{Frame, FrameKind, Layout};
+use super::resolve::{GlobalRef, LocalRef};
+use super::observer::EvalObserver;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The default number of calls kept in a backtrace.
+const DEFAULT_BACKTRACE_LIMIT: usize = 16;
+/// The default maximum depth of nested evaluations,
+/// which is unlimited since the evaluator keeps them on the heap.
+const DEFAULT_MAX_DEPTH: usize = usize::MAX;
+
+/// How the expressions are evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// Walk the expressions step by step.
+    TreeWalker,
+    /// Compile the expressions to bytecode and run it on a VM,
+    /// leaving the forms it can't compile to the tree walker.
+    Vm,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        if cfg!(feature = "default-vm") {
+            Engine::Vm
+        } else {
+            Engine::TreeWalker
+        }
+    }
+}
+
+/// The next version of a global variable, unique among all the environments.
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(0);
+
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A global variable, which is unbound until it's defined.
+///
+/// The version changes whenever the variable is bound,
+/// so the caches of the call sites which looked it up are invalidated.
+#[derive(Clone)]
+struct Global {
+    value: Option<Ptr<Sexp>>,
+    version: u64,
+}
+
+/// The slot and the version of the global variable looked up by a call site or a resolved reference.
+///
+/// The versions are unique among the environments, so a cache filled by another environment
+/// only hits a slot which still holds the same binding, e.g. in a clone of the environment.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct GlobalCache(Cell<Option<(usize, u64)>>);
 
 #[derive(Clone)]
 pub struct Env {
-    global_table: HashMap<String, Ptr<Sexp>>,
+    // The global variables, which the resolved references address by their slots.
+    globals: Vec<Global>,
+    global_slots: HashMap<Symbol, usize>,
     provided_table: HashMap<String, Ptr<Sexp>>,
     stack_frame_ptr: Option<Gc<GcCell<Frame>>>,
+    spans: SpanTable,
+    // The shadow stack of the applied lambdas, macros and Rust functions.
+    call_stack: Vec<Call>,
+    backtrace: Option<Backtrace>,
+    backtrace_limit: usize,
+    // The remaining steps of evaluation, or `None` if unlimited.
+    fuel: Option<u64>,
+    depth: usize,
+    max_depth: usize,
+    observers: Vec<Rc<RefCell<dyn EvalObserver>>>,
+    strict_arity: bool,
+    eager_expansion: bool,
+    // The runs of the evaluator in progress, `None` for the top level.
+    runs: Vec<Option<u64>>,
+    next_run: u64,
+    engine: Engine,
 }
 
 impl Env {
     pub fn new() -> Self {
         Self {
-            global_table: HashMap::new(),
+            globals: vec![],
+            global_slots: HashMap::new(),
             stack_frame_ptr: None,
             provided_table: HashMap::new(),
+            spans: SpanTable::new(),
+            call_stack: vec![],
+            backtrace: None,
+            backtrace_limit: DEFAULT_BACKTRACE_LIMIT,
+            fuel: None,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            observers: vec![],
+            strict_arity: false,
+            eager_expansion: true,
+            runs: vec![],
+            next_run: 0,
+            engine: Engine::default(),
         }
     }
 
@@ -28,6 +113,18 @@ impl Env {
         self.stack_frame_ptr = Some(new_ptr);
     }
 
+    /// Push the frame of a lambda application, where the internal defines bind.
+    pub fn push_scope(&mut self) {
+        let new_ptr = Frame::push_with_kind(self.stack_frame_ptr.take(), FrameKind::Scope);
+        self.stack_frame_ptr = Some(new_ptr);
+    }
+
+    /// Push the frame of a resolved lambda or `letrec`, with a slot for each variable in the layout.
+    pub fn push_scope_with_layout(&mut self, layout: Gc<Layout>) {
+        let new_ptr = Frame::push_with_layout(self.stack_frame_ptr.take(), layout);
+        self.stack_frame_ptr = Some(new_ptr);
+    }
+
     pub fn pop_frame(&mut self) {
         self.stack_frame_ptr = match self.stack_frame_ptr.take() {
             Some(ptr) => Frame::pop(ptr),
@@ -43,59 +140,436 @@ impl Env {
         &mut self,
         new_frame_ptr: Option<Gc<GcCell<Frame>>>,
     ) -> Option<Gc<GcCell<Frame>>> {
-        #[cfg(debug_assertions)]
-        {
-            if let Some(p) = new_frame_ptr.as_ref() {
-                let raw = gc::Gc::into_raw(p.clone());
-                println!("Switched frame ptr to {:p}:", raw);
-                p.borrow().debug();
-                unsafe { Gc::from_raw(raw) };
-            }
-        }
+        self.notify(|observer| observer.frame_switch(new_frame_ptr.as_ref()));
         let old_ptr = self.stack_frame_ptr.take();
         self.stack_frame_ptr = new_frame_ptr;
         old_ptr
     }
 
-    pub fn get(&self, identity: impl AsRef<str>) -> Option<Ptr<Sexp>> {
-        let identity = identity.as_ref();
+    pub fn get(&self, identity: impl Into<Symbol>) -> Option<Ptr<Sexp>> {
+        let identity = identity.into();
+        match self.get_in_frames(identity) {
+            Some(value) => Some(value),
+            None => self.global(identity),
+        }
+    }
+
+    /// Look up a global variable with the cache of a call site or a resolved reference,
+    /// which skips the global table while the variable keeps its binding.
+    ///
+    /// The frames aren't searched, which the resolved references do first if they may bind the name,
+    /// and the compiler leaves the names which the frames may bind to the lookups by name.
+    pub(crate) fn get_cached(&self, identity: Symbol, cache: &GlobalCache) -> Option<Ptr<Sexp>> {
+        if let Some((slot, version)) = cache.0.get() {
+            match self.globals.get(slot) {
+                Some(global) if global.version == version => return global.value.clone(),
+                _ => {}
+            }
+        }
+
+        let slot = *self.global_slots.get(&identity)?;
+        let global = &self.globals[slot];
+        cache.0.set(Some((slot, global.version)));
+        global.value.clone()
+    }
+
+    pub(crate) fn get_in_frames(&self, identity: Symbol) -> Option<Ptr<Sexp>> {
+        match self.stack_frame_ptr.as_ref() {
+            Some(frame_ptr) if Frame::may_bind(frame_ptr, identity) => {}
+            _ => return None,
+        }
         let mut cur = self.stack_frame_ptr.clone();
-        match from_fn(|| match cur.clone() {
-            None => None,
-            Some(frame_ptr) => {
-                cur = frame_ptr.borrow().pre.clone();
-                Some(frame_ptr)
+        while let Some(frame_ptr) = cur {
+            if let Some(value) = frame_ptr.borrow().get(identity) {
+                return Some(value);
             }
-        })
-        .try_fold(Option::<()>::None, |_, frame| {
-            match Frame::read(frame, |frame| frame.get(identity).cloned()) {
-                Some(d) => Break(Some(d.clone())),
-                None => Continue(None),
+            cur = frame_ptr.borrow().pre.clone();
+        }
+        None
+    }
+
+    /// The value of a variable resolved to a slot of an enclosing scope.
+    ///
+    /// The name is looked up instead if the scope frame at the depth isn't the expected one,
+    /// e.g. in a curried lambda, or a binding outside of the layouts shadows the variable.
+    pub(crate) fn get_local(&self, local: &LocalRef) -> Option<Ptr<Sexp>> {
+        let mut cur = self.stack_frame_ptr.clone();
+        let mut depth = 0;
+        while let Some(frame_ptr) = cur {
+            let frame = frame_ptr.borrow();
+            if let Some(layout) = frame.layout.as_ref() {
+                if depth == local.depth {
+                    match frame.slots[local.index].as_ref() {
+                        Some(value) if Gc::ptr_eq(layout, &local.layout) => return Some(value.clone()),
+                        _ => break,
+                    }
+                }
+                depth += 1;
             }
-        }) {
-            Break(p) => p,
-            Continue(_) => self.global_table.get(identity).cloned(),
+            if frame.shadows(local.name) {
+                break;
+            }
+            cur = frame.pre.clone();
         }
+        self.get(local.name)
     }
 
-    pub fn set(&mut self, identity: impl ToString, expr: Ptr<Sexp>) {
+    /// The value of a global variable referred to from inside the scope frames at the depth.
+    ///
+    /// The frames beyond the outermost scope belong to the top level, where nothing shadows the globals,
+    /// unless the scope is applied in the frame of its caller.
+    /// The frames aren't walked if none of them may bind the name, e.g. in the deep calls of a function.
+    pub(crate) fn get_global(&self, global: &GlobalRef) -> Option<Ptr<Sexp>> {
+        match self.stack_frame_ptr.as_ref() {
+            Some(frame_ptr) if Frame::may_bind(frame_ptr, global.name) => {}
+            _ => return self.get_cached(global.name, &global.cache),
+        }
+
+        let mut cur = self.stack_frame_ptr.clone();
+        let mut depth = 0;
+        while let Some(frame_ptr) = cur {
+            let frame = frame_ptr.borrow();
+            if frame.shadows(global.name) {
+                return self.get(global.name);
+            }
+            if let Some(layout) = frame.layout.as_ref() {
+                if depth == global.depth || layout.dynamic {
+                    return self.get(global.name);
+                }
+                depth += 1;
+                if depth == global.depth {
+                    break;
+                }
+            }
+            cur = frame.pre.clone();
+        }
+
+        match depth == global.depth {
+            true => self.get_cached(global.name, &global.cache),
+            false => self.get(global.name),
+        }
+    }
+
+    pub fn set(&mut self, identity: impl Into<Symbol>, expr: Ptr<Sexp>) -> Result<(), EvalError> {
+        let identity = identity.into();
         if let Some(frame) = self.stack_frame_ptr.clone() {
             Frame::modify(frame, |frame| {
-                frame.insert(identity.to_string(), expr.clone());
+                frame.insert(identity, expr.clone());
             });
+            Ok(())
         } else {
-            panic!("No stack frame!");
+            Err(EvalError::NoStackFrame)
+        }
+    }
+
+    /// The innermost scope frame, or `None` at the top level.
+    pub fn current_scope(&self) -> Option<Gc<GcCell<Frame>>> {
+        let mut cur = self.stack_frame_ptr.clone();
+        while let Some(frame_ptr) = cur {
+            if frame_ptr.borrow().kind == FrameKind::Scope {
+                return Some(frame_ptr);
+            }
+            cur = frame_ptr.borrow().pre.clone();
+        }
+        None
+    }
+
+    /// Bind in the innermost scope frame, or in the global table at the top level.
+    pub fn define(&mut self, identity: impl Into<Symbol>, expr: Ptr<Sexp>) {
+        let identity = identity.into();
+        match self.current_scope() {
+            Some(scope) => {
+                Frame::modify(scope, |frame| {
+                    frame.insert(identity, expr.clone());
+                });
+            }
+            None => self.set_global(identity, expr),
+        }
+    }
+
+    /// Update the nearest existing binding in the frame chain, or the global binding.
+    ///
+    /// The frames are shared by the closures which captured them, so they see the new value.
+    pub fn assign(&mut self, identity: impl Into<Symbol>, expr: Ptr<Sexp>) -> Result<(), EvalError> {
+        let identity = identity.into();
+        let mut cur = self.stack_frame_ptr.clone();
+        while let Some(frame_ptr) = cur {
+            if Frame::read(frame_ptr.clone(), |frame| frame.contains(identity)) {
+                Frame::modify(frame_ptr, |frame| {
+                    frame.insert(identity, expr.clone());
+                });
+                return Ok(());
+            }
+            cur = frame_ptr.borrow().pre.clone();
+        }
+
+        match self.global_slots.get(&identity) {
+            Some(&slot) if self.globals[slot].value.is_some() => {
+                self.globals[slot].value = Some(expr);
+                self.globals[slot].version = next_version();
+                Ok(())
+            }
+            _ => Err(EvalError::UnboundIdentifier(identity.to_string())),
         }
     }
 
-    pub fn set_global(&mut self, identity: impl ToString, expr: Ptr<Sexp>) {
-        self.global_table.insert(identity.to_string(), expr);
+    pub fn set_global(&mut self, identity: impl Into<Symbol>, expr: Ptr<Sexp>) {
+        let slot = self.global_slot(identity.into());
+        self.globals[slot].value = Some(expr);
+        self.globals[slot].version = next_version();
     }
 
+    fn global(&self, identity: Symbol) -> Option<Ptr<Sexp>> {
+        let slot = *self.global_slots.get(&identity)?;
+        self.globals[slot].value.clone()
+    }
+
+    /// A cache filled with the slot of the global variable, for a reference resolved to it.
+    pub(crate) fn global_cache(&mut self, identity: Symbol) -> GlobalCache {
+        let slot = self.global_slot(identity);
+        GlobalCache(Cell::new(Some((slot, self.globals[slot].version))))
+    }
+
+    /// The slot of the global variable, which is kept unbound until it's defined.
+    fn global_slot(&mut self, identity: Symbol) -> usize {
+        if let Some(&slot) = self.global_slots.get(&identity) {
+            return slot;
+        }
+        self.globals.push(Global {
+            value: None,
+            version: next_version(),
+        });
+        self.global_slots.insert(identity, self.globals.len() - 1);
+        self.globals.len() - 1
+    }
+
+    /// Evaluate the expression, panicking if the evaluation fails.
     pub fn evaluate(&mut self, expr: Ptr<Sexp>) -> Ptr<Sexp> {
         evaluate(expr, self)
     }
 
+    pub fn try_evaluate(&mut self, expr: Ptr<Sexp>) -> EvalResult {
+        try_evaluate(expr, self)
+    }
+
+    /// Spans of the expressions read from source files.
+    pub fn spans(&self) -> &SpanTable {
+        &self.spans
+    }
+
+    /// Limit the steps of evaluation.
+    ///
+    /// Each step of the evaluation loop and each call of a Rust function consume one unit of fuel.
+    /// When the fuel runs out, the evaluation fails with [`EvalError::OutOfFuel`],
+    /// which can't be caught by `try`. Set the fuel again to continue evaluating.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    /// The remaining fuel, or `None` if the evaluation is unlimited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Remove the limit of the evaluation steps.
+    pub fn clear_fuel(&mut self) {
+        self.fuel = None;
+    }
+
+    pub(crate) fn consume_fuel(&mut self) -> Result<(), EvalError> {
+        match self.fuel.as_mut() {
+            Some(0) => Err(EvalError::OutOfFuel),
+            Some(fuel) => {
+                *fuel -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Limit the depth of nested evaluations, e.g. evaluating the arguments of a non-tail call.
+    ///
+    /// Deeper evaluations fail with [`EvalError::DepthExceeded`]. The depth is unlimited by default.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// The number of the nested evaluations running.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub(crate) fn enter_evaluation(&mut self) -> Result<(), EvalError> {
+        if self.depth >= self.max_depth {
+            Err(EvalError::DepthExceeded(self.max_depth))
+        } else {
+            self.depth += 1;
+            Ok(())
+        }
+    }
+
+    pub(crate) fn leave_evaluation(&mut self) {
+        self.depth -= 1;
+    }
+
+    /// Reset the depth when a continuation replaces the nested evaluations.
+    pub(crate) fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+    }
+
+    /// Start a run of the evaluator, returning its id, or `None` at the top level.
+    pub(crate) fn begin_run(&mut self) -> Option<u64> {
+        let run = (self.depth > 1).then(|| {
+            self.next_run += 1;
+            self.next_run
+        });
+        self.runs.push(run);
+        run
+    }
+
+    pub(crate) fn end_run(&mut self) {
+        self.runs.pop();
+    }
+
+    pub(crate) fn is_running(&self, run: Option<u64>) -> bool {
+        self.runs.contains(&run)
+    }
+
+    /// Register an observer of the evaluation steps.
+    pub fn add_observer(&mut self, observer: impl EvalObserver + 'static) {
+        self.observers.push(Rc::new(RefCell::new(observer)));
+    }
+
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    pub(crate) fn has_observers(&self) -> bool {
+        !self.observers.is_empty()
+    }
+
+    pub(crate) fn notify(&self, mut f: impl FnMut(&mut dyn EvalObserver)) {
+        for observer in self.observers.iter() {
+            f(&mut *observer.borrow_mut());
+        }
+    }
+
+    /// Raise [`EvalError::WrongArity`] when lambdas and macros get a wrong number of arguments,
+    /// instead of currying them or ignoring the extra arguments.
+    ///
+    /// Lambdas whose params start with `#:curry` are still curried.
+    pub fn set_strict_arity(&mut self, strict: bool) {
+        self.strict_arity = strict;
+    }
+
+    pub fn strict_arity(&self) -> bool {
+        self.strict_arity
+    }
+
+    /// Expand the macros in a top-level expression once, including in its lambda bodies,
+    /// before the expression is evaluated. This is the default.
+    ///
+    /// Otherwise the macros are expanded each time the code calling them is evaluated,
+    /// which follows the bindings of the macros and the variables they read at runtime.
+    pub fn set_eager_expansion(&mut self, eager: bool) {
+        self.eager_expansion = eager;
+    }
+
+    pub fn eager_expansion(&self) -> bool {
+        self.eager_expansion
+    }
+
+    /// Choose how the expressions are evaluated.
+    ///
+    /// The [`Engine::Vm`] falls back to the tree walker when the fuel is limited
+    /// or observers are registered, since they count and report each step of the evaluation.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /// The innermost call on the shadow stack.
+    pub(crate) fn current_call(&self) -> Option<&Call> {
+        self.call_stack.last()
+    }
+
+    pub(crate) fn push_call(&mut self, call: Call) {
+        self.call_stack.push(call);
+    }
+
+    /// Replace the innermost call with a tail call.
+    pub(crate) fn replace_call(&mut self, call: Call) {
+        match self.call_stack.last_mut() {
+            Some(last) => *last = call,
+            None => self.call_stack.push(call),
+        }
+    }
+
+    pub(crate) fn pop_call(&mut self) {
+        self.call_stack.pop();
+    }
+
+    pub(crate) fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    /// The calls made since the shadow stack was `base` deep.
+    pub(crate) fn calls_since(&self, base: usize) -> Vec<Call> {
+        self.call_stack[base..].to_vec()
+    }
+
+    /// Replace the calls made since the shadow stack was `base` deep.
+    pub(crate) fn restore_calls(&mut self, base: usize, calls: &[Call]) {
+        self.call_stack.truncate(base);
+        self.call_stack.extend_from_slice(calls);
+    }
+
+    /// Record the innermost calls, unless the backtrace of the failed evaluation has been recorded.
+    pub(crate) fn capture_backtrace(&mut self) {
+        if self.backtrace.is_some() {
+            return;
+        }
+
+        let frames = self
+            .call_stack
+            .iter()
+            .rev()
+            .take(self.backtrace_limit)
+            .map(|call| BacktraceFrame {
+                kind: call.kind,
+                callee: call.name(),
+                args: Sexp::iter(call.args.clone()).collect(),
+                location: call
+                    .form
+                    .as_ref()
+                    .and_then(|form| self.spans.location_of(form)),
+            })
+            .collect::<Vec<_>>();
+        let omitted = self.call_stack.len() - frames.len();
+        self.backtrace = Some(Backtrace { frames, omitted });
+    }
+
+    /// The backtrace of the last failed evaluation.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace.as_ref()
+    }
+
+    pub fn take_backtrace(&mut self) -> Option<Backtrace> {
+        self.backtrace.take()
+    }
+
+    /// Set the number of the innermost calls kept in a backtrace.
+    pub fn set_backtrace_limit(&mut self, limit: usize) {
+        self.backtrace_limit = limit;
+    }
+
     pub fn add_provided(&mut self, identity: impl ToString, expr: Ptr<Sexp>) {
         self.provided_table.insert(identity.to_string(), expr);
     }
@@ -0,0 +1,186 @@
+use std::fmt::Display;
+
+use crate::sexp::{parse::ParseError, span::Location, Ptr, Sexp};
+
+use super::continuation::Continuation;
+
+pub type EvalResult = Result<Ptr<Sexp>, EvalError>;
+
+/// The number of arguments accepted by a form or a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arity {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    pub fn exactly(n: usize) -> Self {
+        Self { min: n, max: Some(n) }
+    }
+
+    pub fn at_least(n: usize) -> Self {
+        Self { min: n, max: None }
+    }
+
+    pub fn between(min: usize, max: usize) -> Self {
+        Self { min, max: Some(max) }
+    }
+
+    pub fn accepts(&self, n: usize) -> bool {
+        n >= self.min && self.max.map(|max| n <= max).unwrap_or(true)
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "exactly {}", self.min),
+            Some(max) => write!(f, "{} to {}", self.min, max),
+            None => write!(f, "at least {}", self.min),
+        }
+    }
+}
+
+/// Errors raised when evaluating an expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvalError {
+    /// The identifier is bound neither in the stack frames nor in the global table.
+    UnboundIdentifier(String),
+    /// The head of a form cannot be applied to its arguments.
+    NotApplicable(String),
+    /// A form or a function received a wrong number of arguments.
+    WrongArity {
+        name: String,
+        expected: Arity,
+        found: usize,
+    },
+    /// A keyword argument doesn't match any `#:key` param of the callee.
+    UnknownKeyword(String),
+    /// A keyword argument is not followed by its value.
+    MissingKeywordValue(String),
+    /// Reading from stdin or loading a module failed.
+    Io(String),
+    /// The required module has syntax errors.
+    Syntax(Vec<ParseError>),
+    /// A binding was made while there is no stack frame.
+    NoStackFrame,
+    /// A Rust function was called again while it was still running.
+    ReentrantCall,
+    /// A value raised by `raise`, or an error value raised by the builtins.
+    Raised(Ptr<Sexp>),
+    /// The evaluation used up the fuel of the environment.
+    OutOfFuel,
+    /// The evaluations were nested deeper than the maximum depth of the environment.
+    DepthExceeded(usize),
+    /// A continuation of an outer evaluation was called with the value,
+    /// which unwinds the Rust functions in between.
+    Escape {
+        continuation: Continuation,
+        value: Ptr<Sexp>,
+    },
+    /// The error was raised when evaluating the form at the location.
+    Located {
+        location: Location,
+        error: Box<EvalError>,
+    },
+}
+
+impl EvalError {
+    /// Raise an error value of the kind, e.g. `type-error`.
+    pub fn raise(kind: impl ToString, message: impl ToString) -> Self {
+        EvalError::Raised(Sexp::error(kind, Sexp::string(message)))
+    }
+
+    /// The value passed to the `try` handlers, or `None` if the error can't be caught.
+    ///
+    /// Errors of the evaluator are converted to error values.
+    pub fn to_value(&self) -> Option<Ptr<Sexp>> {
+        let kind = match self.root() {
+            EvalError::Raised(value) => return Some(value.clone()),
+            EvalError::OutOfFuel | EvalError::Escape { .. } => return None,
+            EvalError::UnboundIdentifier(_) => "unbound-identifier",
+            EvalError::NotApplicable(_) => "not-applicable",
+            EvalError::WrongArity { .. } => "arity-error",
+            EvalError::UnknownKeyword(_) | EvalError::MissingKeywordValue(_) => "keyword-error",
+            EvalError::Io(_) => "io-error",
+            EvalError::Syntax(_) => "syntax-error",
+            EvalError::DepthExceeded(_) => "depth-exceeded",
+            EvalError::NoStackFrame | EvalError::ReentrantCall => "internal-error",
+            EvalError::Located { .. } => unreachable!(),
+        };
+        Some(Sexp::error(kind, Sexp::string(self.root())))
+    }
+
+    /// The error without the location.
+    pub fn root(&self) -> &EvalError {
+        match self {
+            EvalError::Located { error, .. } => error.root(),
+            e => e,
+        }
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            EvalError::Located { location, .. } => Some(location),
+            _ => None,
+        }
+    }
+
+    /// Attach the location if the error hasn't been located.
+    pub fn at(self, location: Location) -> Self {
+        match self {
+            EvalError::Located { .. } | EvalError::Escape { .. } => self,
+            e => EvalError::Located {
+                location,
+                error: Box::new(e),
+            },
+        }
+    }
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::UnboundIdentifier(ident) => write!(f, "Cannot find ident: {ident}"),
+            EvalError::NotApplicable(expr) => write!(f, "{expr} is not appliable"),
+            EvalError::WrongArity {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Wrong number of arguments to {name}: expected {expected}, found {found}"
+            ),
+            EvalError::UnknownKeyword(key) => write!(f, "Unknown keyword argument: {key}"),
+            EvalError::MissingKeywordValue(key) => write!(f, "Missing value for keyword argument: {key}"),
+            EvalError::Io(e) => write!(f, "IO error: {e}"),
+            EvalError::Syntax(errors) => {
+                write!(f, "Syntax error:")?;
+                for e in errors {
+                    write!(f, "\n{e}")?;
+                }
+                Ok(())
+            }
+            EvalError::NoStackFrame => write!(f, "No stack frame!"),
+            EvalError::ReentrantCall => write!(f, "Rust function is called recursively"),
+            EvalError::Raised(value) => match value.as_ref() {
+                Sexp::Error(e) => write!(f, "{e}"),
+                _ => write!(f, "Uncaught exception: {value}"),
+            },
+            EvalError::OutOfFuel => write!(f, "Out of fuel"),
+            EvalError::DepthExceeded(max_depth) => {
+                write!(f, "Maximum evaluation depth {max_depth} exceeded")
+            }
+            EvalError::Escape { .. } => write!(f, "Continuation called outside of its evaluation"),
+            EvalError::Located { location, error } => write!(f, "{location}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+impl From<std::io::Error> for EvalError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value.to_string())
+    }
+}
@@ -0,0 +1,244 @@
+use crate::sexp::{span::SpanTable, symbol::Symbol, Cons, Ptr, Sexp};
+
+use super::compile::defines;
+use super::quasiquote::quasiquote;
+use super::{expand_macro, is_marker, Env, EvalError, EvalResult};
+
+/// The deepest nesting of expansions, beyond which the calls are left to be expanded at runtime,
+/// e.g. a macro expanding to a call of itself until a variable changes.
+const MAX_NESTED_EXPANSIONS: usize = 256;
+
+/// Whether the expressions evaluated by the environment are expanded before they are evaluated.
+///
+/// Only the top level is expanded, where the globals are the only bindings.
+/// Evaluations watched by observers are expanded as they run, so the observers see the expansions.
+pub(crate) fn expands(env: &Env) -> bool {
+    env.eager_expansion() && env.depth() == 0 && env.top_frame().is_none() && !env.has_observers()
+}
+
+/// Expand the calls of the global macros and expanders in a top-level expression,
+/// including in the bodies of its lambdas.
+///
+/// The quoted data and the arguments of the other forms which don't evaluate them are left as they are,
+/// and so are the calls of the names bound by a lambda, `letrec` or an internal `define`.
+/// A call whose expansion fails is left to fail when it's evaluated.
+pub(crate) fn expand(expr: &Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+    let spans = env.spans().clone();
+    let mut expander = Expander {
+        env,
+        spans,
+        scopes: vec![],
+        nested: 0,
+    };
+    expander.expr(expr)
+}
+
+/// Expand a call of a macro, or of a Rust function registered as an expander, once without evaluating the expansion.
+///
+/// `None` if the expression isn't such a call.
+pub fn expand_once(expr: &Ptr<Sexp>, env: &mut Env) -> Result<Option<Ptr<Sexp>>, EvalError> {
+    let Sexp::Form(Cons { car, cdr }) = expr.as_ref() else {
+        return Ok(None);
+    };
+    let callee = match car.as_ref() {
+        Sexp::Identifier(ident) => match env.get(*ident) {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+        _ => car.clone(),
+    };
+
+    match callee.as_ref() {
+        Sexp::Form(Cons { car, .. }) if car.is_macro() => expand_macro(callee.clone(), cdr.clone(), env).map(Some),
+        Sexp::RustFn(f) if f.is_expander() => f.apply(cdr.clone(), env).map(Some),
+        _ => Ok(None),
+    }
+}
+
+struct Expander<'a> {
+    env: &'a mut Env,
+    spans: SpanTable,
+    /// The names bound by the enclosing scopes, the innermost one last.
+    scopes: Vec<Vec<Symbol>>,
+    nested: usize,
+}
+
+impl Expander<'_> {
+    fn expr(&mut self, expr: &Ptr<Sexp>) -> Ptr<Sexp> {
+        match expr.as_ref() {
+            Sexp::Form(Cons { car, cdr }) => match self.form(car, cdr) {
+                Some(form) => self.located(expr, form),
+                None => expr.clone(),
+            },
+            _ => expr.clone(),
+        }
+    }
+
+    /// Keep the span of the node read from a source file on the node replacing it.
+    fn located(&self, old: &Ptr<Sexp>, new: Ptr<Sexp>) -> Ptr<Sexp> {
+        if let Some((file, span)) = self.spans.span_of(old) {
+            if self.spans.span_of(&new).is_none() {
+                self.spans.insert(new.clone(), file, span);
+            }
+        }
+        new
+    }
+
+    /// The expanded form, or `None` if it's left as it is.
+    fn form(&mut self, car: &Ptr<Sexp>, cdr: &Ptr<Sexp>) -> Option<Ptr<Sexp>> {
+        let args = match car.as_ref() {
+            Sexp::If | Sexp::Eq | Sexp::Cons | Sexp::Car | Sexp::Cdr | Sexp::Print => self.list(cdr),
+            Sexp::Eval | Sexp::Begin | Sexp::CallCC => self.list(cdr),
+            // The clauses bind the errors by name.
+            Sexp::Try if !cdr.is_nil() => Sexp::cons(self.expr(&cdr.car()), cdr.cdr()),
+            Sexp::Set if !cdr.is_nil() => Sexp::cons(cdr.car(), self.list(&cdr.cdr())),
+            Sexp::Define => self.define(cdr)?,
+            Sexp::Lambda => self.lambda(cdr)?,
+            Sexp::Letrec | Sexp::LetrecStar => self.letrec(cdr)?,
+            Sexp::Quasiquote => return quasiquote(cdr).ok().map(|expr| self.expr(&expr)),
+
+            Sexp::Identifier(ident) if self.is_bound(*ident) => self.list(cdr),
+            Sexp::Identifier(ident) => match self.env.get(*ident) {
+                Some(value) => return self.call(value, car, cdr),
+                None => self.list(cdr),
+            },
+            Sexp::Form(Cons { car: head, .. }) if head.is_macro() => return self.call(car.clone(), car, cdr),
+            Sexp::Form(_) => return Some(Sexp::cons(self.expr(car), self.list(cdr))),
+            Sexp::RustFn(_) => return self.call(car.clone(), car, cdr),
+            Sexp::Continuation(_) | Sexp::Closure(_) => self.list(cdr),
+            _ => return None,
+        };
+        Some(Sexp::cons(car.clone(), args))
+    }
+
+    /// Expand a call of the value bound to the head.
+    fn call(&mut self, callee: Ptr<Sexp>, head: &Ptr<Sexp>, args: &Ptr<Sexp>) -> Option<Ptr<Sexp>> {
+        let expansion = match callee.as_ref() {
+            Sexp::Form(Cons { car, .. }) if car.is_macro() => {
+                self.expansion(|env| expand_macro(callee.clone(), args.clone(), env))?
+            }
+            Sexp::RustFn(f) if f.is_expander() => self.expansion(|env| f.apply(args.clone(), env))?,
+            // The other Rust functions may read their arguments as data.
+            Sexp::RustFn(f) if !f.evaluates_args() => return None,
+            _ => return Some(Sexp::cons(self.expr(head), self.list(args))),
+        };
+
+        self.nested += 1;
+        let expanded = self.expr(&expansion);
+        self.nested -= 1;
+        Some(expanded)
+    }
+
+    /// The expansion of a call, or `None` if it's left to be expanded at runtime.
+    fn expansion(&mut self, f: impl FnOnce(&mut Env) -> EvalResult) -> Option<Ptr<Sexp>> {
+        if self.nested >= MAX_NESTED_EXPANSIONS {
+            return None;
+        }
+        f(self.env).ok()
+    }
+
+    fn is_bound(&self, ident: Symbol) -> bool {
+        self.scopes.iter().any(|names| names.contains(&ident))
+    }
+
+    /// Expand the items of a list, leaving a dotted tail as it is.
+    fn list(&mut self, list: &Ptr<Sexp>) -> Ptr<Sexp> {
+        match list.as_ref() {
+            Sexp::Form(Cons { car, cdr }) => {
+                let expanded = Sexp::cons(self.expr(car), self.list(cdr));
+                self.located(list, expanded)
+            }
+            _ => list.clone(),
+        }
+    }
+
+    /// Expand a body in the scope of the names.
+    fn body(&mut self, mut names: Vec<Symbol>, body: &Ptr<Sexp>) -> Ptr<Sexp> {
+        names.extend(defines(body));
+        self.scopes.push(names);
+        let body = self.list(body);
+        self.scopes.pop();
+        body
+    }
+
+    /// `(params body ...)` of a lambda.
+    fn lambda(&mut self, lambda: &Ptr<Sexp>) -> Option<Ptr<Sexp>> {
+        let Sexp::Form(Cons { car: params, cdr: body }) = lambda.as_ref() else {
+            return None;
+        };
+        let body = self.body(param_names(params), body);
+        Some(Sexp::cons(params.clone(), body))
+    }
+
+    /// `(ident expr)` or `((ident params ...) body ...)` of `define`.
+    fn define(&mut self, body: &Ptr<Sexp>) -> Option<Ptr<Sexp>> {
+        let Sexp::Form(Cons { car: target, cdr: rest }) = body.as_ref() else {
+            return None;
+        };
+        match target.as_ref() {
+            Sexp::Identifier(_) => Some(Sexp::cons(target.clone(), self.list(rest))),
+            Sexp::Form(Cons { cdr: params, .. }) => {
+                let body = self.body(param_names(params), rest);
+                Some(Sexp::cons(target.clone(), body))
+            }
+            _ => None,
+        }
+    }
+
+    /// `(((ident init) ...) body ...)` of `letrec`.
+    fn letrec(&mut self, letrec: &Ptr<Sexp>) -> Option<Ptr<Sexp>> {
+        let Sexp::Form(Cons { car: bindings, cdr: body }) = letrec.as_ref() else {
+            return None;
+        };
+        let mut names = vec![];
+        for binding in Sexp::iter(bindings.clone()) {
+            match binding.car().as_ref() {
+                Sexp::Identifier(ident) => names.push(*ident),
+                _ => return None,
+            }
+        }
+
+        self.scopes.push(names.clone());
+        let expanded = Sexp::iter(bindings.clone())
+            .map(|binding| {
+                let expanded = Sexp::cons(binding.car(), self.list(&binding.cdr()));
+                self.located(&binding, expanded)
+            })
+            .collect::<Vec<_>>();
+        self.scopes.pop();
+        let body = self.body(names, body);
+
+        let bindings = self.located(bindings, Sexp::from_vec(expanded));
+        Some(Sexp::cons(bindings, body))
+    }
+}
+
+/// The names bound by the params, including the optional, keyword and rest params.
+fn param_names(params: &Ptr<Sexp>) -> Vec<Symbol> {
+    let mut names = vec![];
+    let mut params = params.clone();
+    loop {
+        let next = match params.as_ref() {
+            Sexp::Identifier(rest) => {
+                names.push(*rest);
+                return names;
+            }
+            Sexp::Form(Cons { car, cdr }) => {
+                match car.as_ref() {
+                    Sexp::Identifier(param) if param.starts_with("#:") || is_marker(car, "&rest") => {}
+                    Sexp::Identifier(param) => names.push(*param),
+                    // An optional param with its default value.
+                    Sexp::Form(Cons { car, .. }) => {
+                        if let Sexp::Identifier(param) = car.as_ref() {
+                            names.push(*param);
+                        }
+                    }
+                    _ => {}
+                }
+                cdr.clone()
+            }
+            _ => return names,
+        };
+        params = next;
+    }
+}
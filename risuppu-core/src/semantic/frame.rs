@@ -1,14 +1,121 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use gc::{Gc, GcCell, Trace, Finalize};
 
-use crate::sexp::{Ptr, Sexp};
+use crate::sexp::{symbol::Symbol, Ptr, Sexp};
 
 type MutPtr<T> = Gc<GcCell<T>>;
-type InnerFrame = std::collections::HashMap<String, Ptr<Sexp>>;
+type InnerFrame = std::collections::HashMap<Symbol, Ptr<Sexp>>;
+
+/// The version of the sets of the names bound in the frames.
+///
+/// It changes when a name is bound in a frame which other frames have been pushed on,
+/// whose sets are then stale until they're rebuilt.
+static BOUND_VERSION: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The frames left to trace by the outermost frame being traced, or `None` if no frame is being traced.
+    static PENDING_TRACE: RefCell<Option<Vec<*const MutPtr<Frame>>>> = const { RefCell::new(None) };
+}
 
+/// What pushed the frame.
 #[derive(Debug, Clone, Trace, Finalize, PartialEq, Eq)]
+pub enum FrameKind {
+    /// A temporary frame of an evaluation.
+    Eval,
+    /// The frame of a lambda application, where the internal defines bind.
+    Scope,
+}
+
+/// The variables bound by a lambda or a `letrec`, in the order of their slots in the frame.
+#[derive(Debug, Trace, Finalize, PartialEq, Eq)]
+pub struct Layout {
+    pub names: Vec<Symbol>,
+    /// Whether the lambda is applied in the frame of its caller rather than a captured one,
+    /// e.g. a function defined at the top level.
+    pub dynamic: bool,
+}
+
+impl Layout {
+    pub fn slot_of(&self, name: Symbol) -> Option<usize> {
+        self.names.iter().position(|n| *n == name)
+    }
+}
+
+#[derive(Debug, Clone, Finalize, PartialEq, Eq)]
 pub struct Frame {
+    /// The bindings which are not in the layout, e.g. made by `eval`.
+    ///
+    /// They're made by [`Frame::insert`], which keeps track of the names bound in the frames.
     pub inner: InnerFrame,
     pub pre: Option<MutPtr<Frame>>,
+    pub kind: FrameKind,
+    pub layout: Option<Gc<Layout>>,
+    /// The values of the variables in the layout, `None` until they are bound.
+    pub slots: Vec<Option<Ptr<Sexp>>>,
+    /// The names which this frame or the frames below it may bind, as of `bound_version`,
+    /// shared with the frames pushed on it while they bind no other names.
+    bound: Rc<HashSet<Symbol>>,
+    bound_version: u64,
+    /// Whether a frame has been pushed on this one.
+    pushed_on: bool,
+}
+
+// The frames below are traced in a loop rather than recursively,
+// since the frames of the pending calls chain as deep as the recursion goes.
+unsafe impl Trace for Frame {
+    unsafe fn trace(&self) {
+        self.inner.trace();
+        self.layout.trace();
+        self.slots.trace();
+        let Some(pre) = self.pre.as_ref() else {
+            return;
+        };
+
+        let pre = pre as *const MutPtr<Frame>;
+        let nested = PENDING_TRACE.with(|pending| match pending.borrow_mut().as_mut() {
+            Some(pending) => {
+                pending.push(pre);
+                true
+            }
+            None => false,
+        });
+        if nested {
+            return;
+        }
+
+        PENDING_TRACE.with(|pending| *pending.borrow_mut() = Some(vec![pre]));
+        // The collector neither frees nor changes the frames while it marks them.
+        while let Some(pre) = PENDING_TRACE.with(|pending| pending.borrow_mut().as_mut().and_then(Vec::pop)) {
+            (*pre).trace();
+        }
+        PENDING_TRACE.with(|pending| *pending.borrow_mut() = None);
+    }
+
+    unsafe fn root(&self) {
+        self.inner.root();
+        self.pre.root();
+        self.layout.root();
+        self.slots.root();
+    }
+
+    unsafe fn unroot(&self) {
+        self.inner.unroot();
+        self.pre.unroot();
+        self.layout.unroot();
+        self.slots.unroot();
+    }
+
+    fn finalize_glue(&self) {
+        self.finalize();
+        self.inner.finalize_glue();
+        self.pre.finalize_glue();
+        self.layout.finalize_glue();
+        self.slots.finalize_glue();
+    }
 }
 
 impl Frame {
@@ -16,36 +123,135 @@ impl Frame {
         Gc::new(GcCell::new(Self {
             inner: InnerFrame::new(),
             pre: None,
+            kind: FrameKind::Eval,
+            layout: None,
+            slots: vec![],
+            bound: Rc::default(),
+            bound_version: BOUND_VERSION.load(Ordering::Relaxed),
+            pushed_on: false,
         }))
     }
 
     pub fn push(cur: Option<MutPtr<Self>>) -> MutPtr<Self> {
+        Self::push_with_kind(cur, FrameKind::Eval)
+    }
+
+    pub fn push_with_kind(cur: Option<MutPtr<Self>>, kind: FrameKind) -> MutPtr<Self> {
         let new_cur = Self::new();
+        if let Some(frame_ptr) = cur.as_ref() {
+            let bound = Self::bound_names(frame_ptr);
+            frame_ptr.borrow_mut().pushed_on = true;
+            new_cur.borrow_mut().bound = bound;
+        }
         new_cur.borrow_mut().pre = cur;
+        new_cur.borrow_mut().kind = kind;
+        new_cur
+    }
+
+    /// Push a scope frame with a slot for each variable in the layout.
+    pub fn push_with_layout(cur: Option<MutPtr<Self>>, layout: Gc<Layout>) -> MutPtr<Self> {
+        let new_cur = Self::push_with_kind(cur, FrameKind::Scope);
+        {
+            let mut frame = new_cur.borrow_mut();
+            frame.slots = vec![None; layout.names.len()];
+            if layout.names.iter().any(|name| !frame.bound.contains(name)) {
+                Rc::make_mut(&mut frame.bound).extend(layout.names.iter().copied());
+            }
+            frame.layout = Some(layout);
+        }
         new_cur
     }
 
+    /// The names which the frame or the frames below it may bind,
+    /// rebuilding the stale sets of the frames.
+    fn bound_names(frame_ptr: &MutPtr<Self>) -> Rc<HashSet<Symbol>> {
+        let version = BOUND_VERSION.load(Ordering::Relaxed);
+        let mut stale = vec![];
+        let mut cur = Some(frame_ptr.clone());
+        let mut bound = Rc::default();
+        while let Some(frame_ptr) = cur {
+            let frame = frame_ptr.borrow();
+            if frame.bound_version == version {
+                bound = frame.bound.clone();
+                break;
+            }
+            cur = frame.pre.clone();
+            drop(frame);
+            stale.push(frame_ptr);
+        }
+
+        // Rebuild the sets from the bottom, without recursing into the long chains of the deep calls.
+        for frame_ptr in stale.into_iter().rev() {
+            let mut frame = frame_ptr.borrow_mut();
+            let names = frame.layout.iter().flat_map(|layout| layout.names.clone()).chain(frame.inner.keys().copied());
+            let names = names.filter(|name| !bound.contains(name)).collect::<Vec<_>>();
+            if !names.is_empty() {
+                Rc::make_mut(&mut bound).extend(names);
+            }
+            frame.bound = bound.clone();
+            frame.bound_version = version;
+        }
+        bound
+    }
+
+    /// Whether the frame or the frames below it may bind the name.
+    ///
+    /// It's `false` for the most of the globals, which are then looked up without walking the frames.
+    pub(crate) fn may_bind(frame_ptr: &MutPtr<Self>, name: Symbol) -> bool {
+        let frame = frame_ptr.borrow();
+        frame.bound_version != BOUND_VERSION.load(Ordering::Relaxed) || frame.bound.contains(&name)
+    }
+
     pub fn pop(cur: MutPtr<Self>) -> Option<MutPtr<Self>> {
         cur.borrow().pre.clone()
     }
 
-    pub fn modify(frame_ptr: MutPtr<Self>, mut f: impl FnMut(&mut InnerFrame)) -> MutPtr<Self> {
-        f(&mut frame_ptr.borrow_mut().inner);
+    pub fn modify(frame_ptr: MutPtr<Self>, mut f: impl FnMut(&mut Self)) -> MutPtr<Self> {
+        f(&mut frame_ptr.borrow_mut());
         frame_ptr
     }
 
-    pub fn read<O>(frame_ptr: MutPtr<Self>, mut f: impl FnMut(&InnerFrame) -> O) -> O {
-        f(& frame_ptr.borrow().inner)
+    pub fn read<O>(frame_ptr: MutPtr<Self>, mut f: impl FnMut(&Self) -> O) -> O {
+        f(&frame_ptr.borrow())
     }
 
-    #[cfg(debug_assertions)]
-    pub fn debug(&self) {
-        println!("--- FRAME ---");
-        for (k, v) in self.inner.iter() {
-            println!("{k} -> {v}");
+    fn slot_of(&self, name: Symbol) -> Option<usize> {
+        self.layout.as_ref().and_then(|layout| layout.slot_of(name))
+    }
+
+    /// The value bound to the name in this frame.
+    pub fn get(&self, name: Symbol) -> Option<Ptr<Sexp>> {
+        match self.slot_of(name) {
+            Some(slot) => self.slots[slot].clone(),
+            None if self.inner.is_empty() => None,
+            None => self.inner.get(&name).cloned(),
         }
-        if let Some(pre) = self.pre.as_ref() {
-            pre.borrow().debug()
+    }
+
+    pub fn contains(&self, name: Symbol) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Bind the name in this frame, in its slot if the layout has one.
+    pub fn insert(&mut self, name: impl Into<Symbol>, value: Ptr<Sexp>) {
+        let name = name.into();
+        match self.slot_of(name) {
+            Some(slot) => self.slots[slot] = Some(value),
+            None => {
+                if !self.bound.contains(&name) {
+                    Rc::make_mut(&mut self.bound).insert(name);
+                    // The frames pushed on this one don't have the name in their sets.
+                    if self.pushed_on {
+                        BOUND_VERSION.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                self.inner.insert(name, value);
+            }
         }
     }
+
+    /// Whether a binding outside of the layout shadows the name.
+    pub(crate) fn shadows(&self, name: Symbol) -> bool {
+        !self.inner.is_empty() && self.inner.contains_key(&name)
+    }
 }
@@ -1,19 +1,21 @@
 use std::path::PathBuf;
 
-use crate::sexp::{Ptr, Sexp};
+use crate::sexp::{parse::Reader, Ptr, Sexp};
 
-use super::Env;
+use super::{check_arity, Arity, Env, EvalError, EvalResult};
 
-pub fn process_provide(body: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+pub fn process_provide(body: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    check_arity("provide", &body, Arity::exactly(2))?;
     let ident = body.car();
     let provided = body.cdr().car();
-    let provided = env.evaluate(provided);
+    let provided = env.try_evaluate(provided)?;
     env.add_provided(ident, provided);
 
-    Sexp::nil()
+    Ok(Sexp::nil())
 }
 
-pub fn process_require(body: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+pub fn process_require(body: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    check_arity("require", &body, Arity::between(1, 2))?;
     // TODO: Clone cost
     let mut required_env = env.clone();
     let module_name = body.car();
@@ -23,57 +25,60 @@ pub fn process_require(body: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
         match std::fs::read_to_string(module) {
             Ok(content) => {
                 let buf = content.as_str();
-                evaluate_buf(buf, &mut required_env);
+                evaluate_buf(module, buf, &mut required_env)?;
             }
             Err(e) => {
-                println!("Error when requiring '{module}': {e}");
-                return Sexp::nil();
+                return Err(EvalError::Io(format!("Error when requiring '{module}': {e}")));
             }
         }
     }
 
     if let Sexp::Identifier(module) = module_name.as_ref() {
         let mut module_path: PathBuf = std::env::var("RISP_LIB")
-            .expect("Cannot found $RISP_LIB. Please set the environment var before running")
+            .map_err(|_| {
+                EvalError::Io(
+                    "Cannot found $RISP_LIB. Please set the environment var before running"
+                        .to_string(),
+                )
+            })?
             .into();
         module_path.push(format!("{module}.risp"));
         match std::fs::read_to_string(module_path.clone()) {
             Ok(content) => {
                 let buf = content.as_str();
-                evaluate_buf(buf, &mut required_env);
+                evaluate_buf(module_path.to_string_lossy(), buf, &mut required_env)?;
             }
             Err(e) => {
-                println!("Error when requiring '{}': {e}", module_path.to_str().unwrap());
-                return Sexp::nil();
+                return Err(EvalError::Io(format!(
+                    "Error when requiring '{}': {e}",
+                    module_path.to_string_lossy()
+                )));
             }
         }
     }
 
-    let prefix = if let Sexp::SString(s) | Sexp::Identifier(s) = prefix.as_ref() {
-        s.as_str()
-    } else {
-        ""
+    let prefix = match prefix.as_ref() {
+        Sexp::SString(s) => s.as_str(),
+        Sexp::Identifier(s) => s.as_str(),
+        _ => "",
     };
     for (k, v) in required_env.get_provided().into_iter() {
-        let import_ident = Sexp::identifier(format!("{prefix}{k}"));
-        env.set_global(import_ident.clone(), v.clone());
+        let import_ident = format!("{prefix}{k}");
+        env.set_global(&import_ident, v.clone());
         env.add_provided(import_ident, v);
     }
 
-    Sexp::nil()
+    Ok(Sexp::nil())
 }
 
-fn evaluate_buf(mut buf: &str, env: &mut Env) {
-    while !buf.is_empty() {
-        match crate::sexp::parse::parse_sexp(buf) {
-            Ok((remaining_buf, s)) => {
-                env.evaluate(s);
-                buf = remaining_buf;
-            }
-            Err(e) => {
-                println!("Error when parsing: {e}");
-                break;
-            }
-        }
+fn evaluate_buf(name: impl ToString, buf: &str, env: &mut Env) -> Result<(), EvalError> {
+    let spans = env.spans().clone();
+    let exprs = Reader::new(name, buf, &spans)
+        .read_all()
+        .map_err(EvalError::Syntax)?;
+    for s in exprs {
+        env.try_evaluate(s)?;
     }
+
+    Ok(())
 }
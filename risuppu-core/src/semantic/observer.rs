@@ -0,0 +1,229 @@
+use std::{cell::RefCell, rc::Rc};
+
+use gc::{Gc, GcCell};
+
+use crate::sexp::{Ptr, Sexp};
+
+use super::{frame::Frame, CallKind, EvalResult};
+
+/// Hooks called by the evaluator, registered with [`Env::add_observer`](super::Env::add_observer).
+///
+/// Every hook does nothing by default.
+pub trait EvalObserver {
+    /// An expression is going to be evaluated. `depth` starts from 1 at the top level.
+    fn eval_enter(&mut self, _expr: &Ptr<Sexp>, _depth: usize) {}
+
+    /// The evaluation of an expression finished.
+    fn eval_exit(&mut self, _expr: &Ptr<Sexp>, _result: &EvalResult, _depth: usize) {}
+
+    /// A lambda, macro or Rust function is applied to the arguments.
+    fn apply(&mut self, _kind: CallKind, _callee: Option<&Ptr<Sexp>>, _args: &Ptr<Sexp>) {}
+
+    /// A macro form has been expanded.
+    fn macro_expand(&mut self, _form: &Ptr<Sexp>, _expansion: &Ptr<Sexp>) {}
+
+    /// The stack frame pointer was switched.
+    fn frame_switch(&mut self, _frame: Option<&Gc<GcCell<Frame>>>) {}
+}
+
+/// Print the evaluation steps to stdout.
+#[derive(Default)]
+pub struct StdoutTracer {
+    depth: usize,
+}
+
+impl StdoutTracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn indent(&self) -> String {
+        "  ".repeat(self.depth.saturating_sub(1))
+    }
+}
+
+impl EvalObserver for StdoutTracer {
+    fn eval_enter(&mut self, expr: &Ptr<Sexp>, depth: usize) {
+        self.depth = depth;
+        println!("{}Eval: {}", self.indent(), expr);
+    }
+
+    fn eval_exit(&mut self, expr: &Ptr<Sexp>, result: &EvalResult, depth: usize) {
+        self.depth = depth;
+        match result {
+            Ok(evaluated) => println!("{}Evaluated: {} => {}", self.indent(), expr, evaluated),
+            Err(e) => println!("{}Failed: {} => {}", self.indent(), expr, e),
+        }
+        self.depth = depth - 1;
+    }
+
+    fn apply(&mut self, kind: CallKind, callee: Option<&Ptr<Sexp>>, args: &Ptr<Sexp>) {
+        match callee {
+            Some(callee) => println!("{}Apply {:?} {}: {}", self.indent(), kind, callee, args),
+            None => println!("{}Apply {:?}: {}", self.indent(), kind, args),
+        }
+    }
+
+    fn macro_expand(&mut self, form: &Ptr<Sexp>, expansion: &Ptr<Sexp>) {
+        println!("{}Expanded: {} => {}", self.indent(), form, expansion);
+    }
+
+    fn frame_switch(&mut self, frame: Option<&Gc<GcCell<Frame>>>) {
+        if let Some(frame) = frame {
+            println!("{}Switched frame ptr to {:p}", self.indent(), &**frame);
+        }
+    }
+}
+
+/// An evaluation step recorded by [`EventCollector`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvalEvent {
+    EvalEnter {
+        expr: Ptr<Sexp>,
+        depth: usize,
+    },
+    EvalExit {
+        expr: Ptr<Sexp>,
+        result: EvalResult,
+        depth: usize,
+    },
+    Apply {
+        kind: CallKind,
+        callee: Option<Ptr<Sexp>>,
+        args: Ptr<Sexp>,
+    },
+    MacroExpand {
+        form: Ptr<Sexp>,
+        expansion: Ptr<Sexp>,
+    },
+    FrameSwitch {
+        frame: Option<Gc<GcCell<Frame>>>,
+    },
+}
+
+/// Record the evaluation steps.
+///
+/// The events are shared between the clones of the collector,
+/// so keep a clone to read the events after registering it.
+#[derive(Clone, Default)]
+pub struct EventCollector {
+    events: Rc<RefCell<Vec<EvalEvent>>>,
+}
+
+impl EventCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> Vec<EvalEvent> {
+        self.events.borrow().clone()
+    }
+
+    pub fn clear(&self) {
+        self.events.borrow_mut().clear();
+    }
+
+    fn push(&self, event: EvalEvent) {
+        self.events.borrow_mut().push(event);
+    }
+}
+
+impl EvalObserver for EventCollector {
+    fn eval_enter(&mut self, expr: &Ptr<Sexp>, depth: usize) {
+        self.push(EvalEvent::EvalEnter {
+            expr: expr.clone(),
+            depth,
+        });
+    }
+
+    fn eval_exit(&mut self, expr: &Ptr<Sexp>, result: &EvalResult, depth: usize) {
+        self.push(EvalEvent::EvalExit {
+            expr: expr.clone(),
+            result: result.clone(),
+            depth,
+        });
+    }
+
+    fn apply(&mut self, kind: CallKind, callee: Option<&Ptr<Sexp>>, args: &Ptr<Sexp>) {
+        self.push(EvalEvent::Apply {
+            kind,
+            callee: callee.cloned(),
+            args: args.clone(),
+        });
+    }
+
+    fn macro_expand(&mut self, form: &Ptr<Sexp>, expansion: &Ptr<Sexp>) {
+        self.push(EvalEvent::MacroExpand {
+            form: form.clone(),
+            expansion: expansion.clone(),
+        });
+    }
+
+    fn frame_switch(&mut self, frame: Option<&Gc<GcCell<Frame>>>) {
+        self.push(EvalEvent::FrameSwitch {
+            frame: frame.cloned(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        semantic::{CallKind, Env},
+        sexp::{parse::parse_sexp, Sexp},
+    };
+
+    use super::{EvalEvent, EventCollector};
+
+    #[test]
+    fn collect_events() {
+        let mut env = Env::new();
+        let collector = EventCollector::new();
+        env.add_observer(collector.clone());
+
+        let expr = parse_sexp("((lambda (a) a) 1)").unwrap().1;
+        env.evaluate(expr.clone());
+        let events = collector.events();
+
+        assert_eq!(events.first(), Some(&EvalEvent::EvalEnter { expr: expr.clone(), depth: 1 }));
+        assert_eq!(
+            events.last(),
+            Some(&EvalEvent::EvalExit {
+                expr,
+                result: Ok(Sexp::int(1)),
+                depth: 1
+            })
+        );
+        assert!(events.contains(&EvalEvent::Apply {
+            kind: CallKind::Lambda,
+            callee: None,
+            args: Sexp::from_vec([Sexp::int(1)]),
+        }));
+    }
+
+    #[test]
+    fn collect_macro_expansion() {
+        let mut env = Env::new();
+        let collector = EventCollector::new();
+        env.add_observer(collector.clone());
+
+        env.evaluate(parse_sexp("(define m (macro (a) (cons 'car (cons a '()))))").unwrap().1);
+        collector.clear();
+        env.evaluate(parse_sexp("(m '(1 2))").unwrap().1);
+
+        let expansions = collector
+            .events()
+            .into_iter()
+            .filter(|event| matches!(event, EvalEvent::MacroExpand { .. }))
+            .collect::<Vec<_>>();
+        assert_eq!(expansions.len(), 1);
+        if let EvalEvent::MacroExpand { expansion, .. } = &expansions[0] {
+            assert_eq!(expansion, &parse_sexp("(car '(1 2))").unwrap().1);
+        }
+        assert!(collector.events().contains(&EvalEvent::Apply {
+            kind: CallKind::Macro,
+            callee: Some(Sexp::identifier("m")),
+            args: parse_sexp("('(1 2))").unwrap().1,
+        }));
+    }
+}
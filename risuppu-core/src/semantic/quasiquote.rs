@@ -0,0 +1,80 @@
+use crate::sexp::{Cons, Ptr, Sexp};
+
+use super::{check_arity, Arity, Env, EvalError, EvalResult};
+
+/// Rewrite the template of `(quasiquote template)` into the forms building its value.
+///
+/// `(unquote expr)` is replaced with the value of `expr`, and `(unquote-splicing expr)` in a list
+/// with the items of the value. A nested `quasiquote` adds a level, which its `unquote` removes,
+/// and only the unquotes of the outermost level are evaluated.
+pub(crate) fn quasiquote(args: &Ptr<Sexp>) -> EvalResult {
+    check_arity("quasiquote", args, Arity::exactly(1))?;
+    template(&args.car(), 0)
+}
+
+/// The error of `unquote` or `unquote-splicing` evaluated outside of a quasiquote.
+pub(crate) fn unquote_outside(keyword: &Sexp) -> EvalError {
+    EvalError::raise("syntax-error", format!("{keyword} is outside of quasiquote"))
+}
+
+fn template(template: &Ptr<Sexp>, depth: usize) -> EvalResult {
+    if !has_unquote(template) {
+        return Ok(quote(template.clone()));
+    }
+    let Sexp::Form(Cons { car, cdr }) = template.as_ref() else {
+        return Ok(quote(template.clone()));
+    };
+
+    match car.as_ref() {
+        Sexp::Unquote if depth == 0 => {
+            check_arity("unquote", cdr, Arity::exactly(1))?;
+            Ok(cdr.car())
+        }
+        Sexp::UnquoteSplicing if depth == 0 => Err(EvalError::raise(
+            "syntax-error",
+            format!("unquote-splicing is not in a list: {template}"),
+        )),
+        // Keep the unquotes of the inner levels.
+        Sexp::Quasiquote => Ok(cons(quote(car.clone()), self::template(cdr, depth + 1)?)),
+        Sexp::Unquote | Sexp::UnquoteSplicing => Ok(cons(quote(car.clone()), self::template(cdr, depth - 1)?)),
+        Sexp::Form(Cons { car: head, cdr: spliced }) if depth == 0 && matches!(head.as_ref(), Sexp::UnquoteSplicing) => {
+            check_arity("unquote-splicing", spliced, Arity::exactly(1))?;
+            let append = unsafe { Sexp::rust_fn_with_evaluated_args(append) };
+            Ok(Sexp::from_vec([append, spliced.car(), self::template(cdr, depth)?]))
+        }
+        _ => Ok(cons(self::template(car, depth)?, self::template(cdr, depth)?)),
+    }
+}
+
+fn has_unquote(template: &Ptr<Sexp>) -> bool {
+    match template.as_ref() {
+        Sexp::Unquote | Sexp::UnquoteSplicing => true,
+        Sexp::Form(Cons { car, cdr }) => has_unquote(car) || has_unquote(cdr),
+        _ => false,
+    }
+}
+
+fn quote(expr: Ptr<Sexp>) -> Ptr<Sexp> {
+    Sexp::from_vec([Sexp::quote(), expr])
+}
+
+fn cons(car: Ptr<Sexp>, cdr: Ptr<Sexp>) -> Ptr<Sexp> {
+    Sexp::from_vec([Sexp::wrap(Sexp::Cons), car, cdr])
+}
+
+/// `(append list rest)`: The items of `list` followed by `rest`.
+fn append(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    let (list, rest) = (args.car(), args.cdr().car());
+    let mut items = vec![];
+    let mut tail = list.clone();
+    while let Sexp::Form(Cons { car, cdr }) = tail.as_ref() {
+        items.push(car.clone());
+        let cdr = cdr.clone();
+        tail = cdr;
+    }
+    if !tail.is_nil() {
+        return Err(EvalError::raise("type-error", format!("Expected a list to splice, found {list}")));
+    }
+    let spliced = items.into_iter().rev().fold(rest, |list, item| Sexp::cons(item, list));
+    Ok(quote(spliced))
+}
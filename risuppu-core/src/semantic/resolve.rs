@@ -0,0 +1,267 @@
+use gc::{Finalize, Gc, Trace};
+
+use crate::sexp::{span::SpanTable, symbol::Symbol, Cons, Ptr, Sexp};
+
+use super::compile::defines;
+use super::env::GlobalCache;
+use super::frame::Layout;
+use super::quasiquote::quasiquote;
+use super::{is_marker, keyword_name, Env};
+
+/// A reference to a variable bound by an enclosing lambda or `letrec`.
+///
+/// `depth` counts the scope frames from the innermost one, and `index` is the slot in the frame.
+#[derive(Debug, Clone, Trace, Finalize, PartialEq, Eq)]
+pub struct LocalRef {
+    pub name: Symbol,
+    #[unsafe_ignore_trace]
+    pub depth: usize,
+    #[unsafe_ignore_trace]
+    pub index: usize,
+    /// The layout of the frame, which tells whether the frame found at `depth` is the expected one.
+    pub layout: Gc<Layout>,
+}
+
+/// A reference to a global variable from inside `depth` scope frames.
+///
+/// The cache holds the slot of the variable, and the version it had when it was looked up.
+#[derive(Debug, Clone, Trace, Finalize)]
+pub struct GlobalRef {
+    pub name: Symbol,
+    #[unsafe_ignore_trace]
+    pub depth: usize,
+    #[unsafe_ignore_trace]
+    pub(crate) cache: GlobalCache,
+}
+
+impl PartialEq for GlobalRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.depth == other.depth
+    }
+}
+
+impl Eq for GlobalRef {}
+
+/// Whether the expressions evaluated by the environment are resolved.
+///
+/// Only the top level is resolved, where no frame binds a variable.
+/// Evaluations watched by observers stay as they are written.
+pub(crate) fn resolves(env: &Env) -> bool {
+    env.depth() == 0 && env.top_frame().is_none() && !env.has_observers()
+}
+
+/// Rewrite the references to variables in a top-level expression to the slots where they live.
+///
+/// The forms whose arguments aren't evaluated, e.g. `quote` and macro calls, are left as they are,
+/// and so are the lambdas sharing their enclosing scope with `#:block`.
+/// A reference falls back to looking up its name when the frames differ from the expected ones,
+/// e.g. a variable defined by `eval`.
+pub(crate) fn resolve(expr: &Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+    let spans = env.spans().clone();
+    let mut resolver = Resolver {
+        env,
+        spans,
+        scopes: vec![],
+    };
+    resolver.expr(expr)
+}
+
+struct Resolver<'a> {
+    env: &'a mut Env,
+    spans: SpanTable,
+    /// The layouts of the enclosing scopes, the innermost one last.
+    scopes: Vec<Gc<Layout>>,
+}
+
+impl Resolver<'_> {
+    fn expr(&mut self, expr: &Ptr<Sexp>) -> Ptr<Sexp> {
+        match expr.as_ref() {
+            Sexp::Identifier(ident) if keyword_name(ident).is_none() => {
+                let reference = self.reference(*ident);
+                self.located(expr, reference)
+            }
+            Sexp::Form(Cons { car, cdr }) => match self.form(car, cdr) {
+                Some(form) => self.located(expr, form),
+                None => expr.clone(),
+            },
+            _ => expr.clone(),
+        }
+    }
+
+    /// Keep the span of the node read from a source file on the node replacing it.
+    fn located(&self, old: &Ptr<Sexp>, new: Ptr<Sexp>) -> Ptr<Sexp> {
+        if let Some((file, span)) = self.spans.span_of(old) {
+            self.spans.insert(new.clone(), file, span);
+        }
+        new
+    }
+
+    fn reference(&mut self, ident: Symbol) -> Ptr<Sexp> {
+        for (depth, layout) in self.scopes.iter().rev().enumerate() {
+            if let Some(index) = layout.slot_of(ident) {
+                return Sexp::wrap(Sexp::Local(LocalRef {
+                    name: ident,
+                    depth,
+                    index,
+                    layout: layout.clone(),
+                }));
+            }
+        }
+        Sexp::wrap(Sexp::Global(GlobalRef {
+            name: ident,
+            depth: self.scopes.len(),
+            cache: self.env.global_cache(ident),
+        }))
+    }
+
+    /// The resolved form, or `None` if it's left as it is.
+    fn form(&mut self, car: &Ptr<Sexp>, cdr: &Ptr<Sexp>) -> Option<Ptr<Sexp>> {
+        let args = match car.as_ref() {
+            Sexp::If | Sexp::Eq | Sexp::Cons | Sexp::Car | Sexp::Cdr | Sexp::Print => self.list(cdr),
+            Sexp::Eval | Sexp::Begin | Sexp::CallCC => self.list(cdr),
+            // The clauses bind the errors by name.
+            Sexp::Try if !cdr.is_nil() => Sexp::cons(self.expr(&cdr.car()), cdr.cdr()),
+            Sexp::Set if !cdr.is_nil() => Sexp::cons(cdr.car(), self.list(&cdr.cdr())),
+            Sexp::Define => self.define(cdr)?,
+            Sexp::Lambda => self.lambda(cdr, false)?,
+            Sexp::Letrec | Sexp::LetrecStar => self.letrec(cdr)?,
+            Sexp::Quasiquote => return quasiquote(cdr).ok().map(|expr| self.expr(&expr)),
+
+            Sexp::Identifier(ident) if self.is_syntax(*ident) => return None,
+            Sexp::Identifier(_) => return Some(Sexp::cons(self.expr(car), self.list(cdr))),
+            Sexp::Form(head) if head.car.is_macro() => return None,
+            Sexp::Form(_) => return Some(Sexp::cons(self.expr(car), self.list(cdr))),
+            Sexp::RustFn(f) if f.evaluates_args() => self.list(cdr),
+            Sexp::Continuation(_) | Sexp::Closure(_) => self.list(cdr),
+            _ => return None,
+        };
+        Some(Sexp::cons(car.clone(), args))
+    }
+
+    /// Whether the global applied to the unevaluated arguments, e.g. a macro or `let`.
+    fn is_syntax(&self, ident: Symbol) -> bool {
+        if self.scopes.iter().any(|layout| layout.slot_of(ident).is_some()) {
+            return false;
+        }
+        match self.env.get(ident).as_deref() {
+            Some(Sexp::Form(Cons { car, .. })) => car.is_macro(),
+            Some(Sexp::RustFn(f)) => !f.evaluates_args(),
+            _ => false,
+        }
+    }
+
+    /// Resolve the items of a list, leaving a dotted tail as it is.
+    fn list(&mut self, list: &Ptr<Sexp>) -> Ptr<Sexp> {
+        match list.as_ref() {
+            Sexp::Form(Cons { car, cdr }) => {
+                let resolved = Sexp::cons(self.expr(car), self.list(cdr));
+                self.located(list, resolved)
+            }
+            _ => list.clone(),
+        }
+    }
+
+    /// `(params body ...)` of a lambda, with a layout marker added before the params.
+    fn lambda(&mut self, lambda: &Ptr<Sexp>, dynamic: bool) -> Option<Ptr<Sexp>> {
+        let Sexp::Form(Cons { car: params, cdr: body }) = lambda.as_ref() else {
+            return None;
+        };
+        let mut names = param_names(params)?;
+        for ident in defines(body) {
+            if !names.contains(&ident) {
+                names.push(ident);
+            }
+        }
+
+        let layout = Gc::new(Layout { names, dynamic });
+        self.scopes.push(layout.clone());
+        let body = self.list(body);
+        self.scopes.pop();
+
+        let params = Sexp::cons(Sexp::wrap(Sexp::Layout(layout)), params.clone());
+        Some(Sexp::cons(params, body))
+    }
+
+    /// `(ident expr)` or `((ident params ...) body ...)` of `define`.
+    fn define(&mut self, body: &Ptr<Sexp>) -> Option<Ptr<Sexp>> {
+        let Sexp::Form(Cons { car: target, cdr: rest }) = body.as_ref() else {
+            return None;
+        };
+        match target.as_ref() {
+            Sexp::Identifier(_) => Some(Sexp::cons(target.clone(), self.list(rest))),
+            Sexp::Form(Cons { car: ident, cdr: params }) => {
+                // A function defined outside of any scope is applied in the frame of its caller.
+                let dynamic = self.scopes.is_empty();
+                let lambda = self.lambda(&Sexp::cons(params.clone(), rest.clone()), dynamic)?;
+                let target = self.located(target, Sexp::cons(ident.clone(), lambda.car()));
+                Some(Sexp::cons(target, lambda.cdr()))
+            }
+            _ => None,
+        }
+    }
+
+    /// `(((ident init) ...) body ...)` of `letrec`, with a layout marker added before the bindings.
+    fn letrec(&mut self, letrec: &Ptr<Sexp>) -> Option<Ptr<Sexp>> {
+        let Sexp::Form(Cons { car: bindings, cdr: body }) = letrec.as_ref() else {
+            return None;
+        };
+        let mut names = vec![];
+        for binding in Sexp::iter(bindings.clone()) {
+            match binding.car().as_ref() {
+                Sexp::Identifier(ident) => names.push(*ident),
+                _ => return None,
+            }
+        }
+        for ident in defines(body) {
+            if !names.contains(&ident) {
+                names.push(ident);
+            }
+        }
+
+        let layout = Gc::new(Layout { names, dynamic: false });
+        self.scopes.push(layout.clone());
+        let resolved = Sexp::iter(bindings.clone())
+            .map(|binding| {
+                let resolved = Sexp::cons(binding.car(), self.list(&binding.cdr()));
+                self.located(&binding, resolved)
+            })
+            .collect::<Vec<_>>();
+        let body = self.list(body);
+        self.scopes.pop();
+
+        let bindings = self.located(bindings, Sexp::from_vec(resolved));
+        let bindings = Sexp::cons(Sexp::wrap(Sexp::Layout(layout)), bindings);
+        Some(Sexp::cons(bindings, body))
+    }
+}
+
+/// The names bound by the params, or `None` if the lambda shares the enclosing scope.
+fn param_names(params: &Ptr<Sexp>) -> Option<Vec<Symbol>> {
+    let mut names = vec![];
+    let mut params = params.clone();
+    loop {
+        let next = match params.as_ref() {
+            Sexp::Nil => return Some(names),
+            Sexp::Identifier(rest) => {
+                names.push(*rest);
+                return Some(names);
+            }
+            Sexp::Form(Cons { car, .. }) if is_marker(car, "#:block") => return None,
+            Sexp::Form(Cons { car, cdr }) => {
+                match car.as_ref() {
+                    Sexp::Identifier(param) if param.starts_with("#:") || *param == "&rest" => {}
+                    Sexp::Identifier(param) => names.push(*param),
+                    // An optional param with its default value.
+                    Sexp::Form(Cons { car, .. }) => match car.as_ref() {
+                        Sexp::Identifier(param) => names.push(*param),
+                        _ => return None,
+                    },
+                    _ => return None,
+                }
+                cdr.clone()
+            }
+            _ => return None,
+        };
+        params = next;
+    }
+}
@@ -0,0 +1,621 @@
+use gc::{Gc, GcCell};
+
+use crate::sexp::{span::SpanTable, Cons, Ptr, Sexp};
+
+use super::backtrace::Call;
+use super::bytecode::{Closure, ClosureData, Loc, Op, Proto, Site, Upvalue};
+use super::compile::compile_in;
+use super::frame::{Frame, FrameKind};
+use super::{keyword_name, walk, walk_apply, Arity, CallKind, Env, EvalError, EvalResult};
+
+/// A local of a frame.
+#[derive(Clone)]
+enum Slot {
+    Unbound,
+    Value(Ptr<Sexp>),
+    /// A local captured by a closure, which shares it with the frame.
+    Boxed(Upvalue),
+}
+
+/// The activation of a closure, or of compiled code.
+struct CallFrame {
+    proto: Gc<Proto>,
+    ip: usize,
+    /// The height of the value stack when the frame started.
+    base: usize,
+    /// The frame whose locals and upvalues the code uses, which is the frame itself,
+    /// unless the code was built at runtime in the scope of an outer frame.
+    owner: usize,
+    locals: Vec<Slot>,
+    closure: Option<Closure>,
+    /// The frame of the environment where the globals are looked up.
+    env_frame: Option<Gc<GcCell<Frame>>>,
+    /// Whether the frame has made a call on the shadow stack, which is replaced by its tail calls.
+    in_call: bool,
+    /// Whether the frame counts as a nested evaluation.
+    nested: bool,
+    /// The innermost form read from a source file when the frame was called.
+    form: Option<Ptr<Sexp>>,
+}
+
+/// What happens after a step of the VM.
+type Stepped = Result<Option<Ptr<Sexp>>, EvalError>;
+
+/// The virtual machine running the compiled code, which keeps its frames on the heap.
+struct Vm {
+    stack: Vec<Ptr<Sexp>>,
+    frames: Vec<CallFrame>,
+    spans: SpanTable,
+    /// The depth of the evaluations when the VM started.
+    depth: usize,
+    /// The depth of the shadow call stack when the VM started.
+    calls: usize,
+}
+
+/// Run the code compiled from a top-level expression.
+pub(crate) fn run(code: Gc<Proto>, env: &mut Env) -> EvalResult {
+    let mut vm = Vm::new(env);
+    let locals = vec![Slot::Unbound; code.locals];
+    vm.frames.push(CallFrame {
+        proto: code,
+        ip: 0,
+        base: 0,
+        owner: 0,
+        locals,
+        closure: None,
+        env_frame: env.top_frame(),
+        in_call: false,
+        nested: false,
+        form: None,
+    });
+    vm.execute(env)
+}
+
+/// Apply a closure to the evaluated arguments, for the tree walker which has made the call.
+pub(crate) fn apply(closure: &Closure, args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    env.consume_fuel()?;
+    let locals = match bind(closure, Sexp::iter(args).collect(), env)? {
+        Bound::Locals(locals) => locals,
+        Bound::Curried(curried) => return Ok(curried),
+    };
+
+    let mut vm = Vm::new(env);
+    let data = closure.data();
+    vm.frames.push(CallFrame {
+        proto: data.proto.clone(),
+        ip: 0,
+        base: 0,
+        owner: 0,
+        locals,
+        closure: Some(closure.clone()),
+        env_frame: data.frame.clone(),
+        in_call: false,
+        nested: false,
+        form: None,
+    });
+    let outer = env.set_frame_ptr(data.frame.clone());
+    let res = vm.execute(env);
+    env.set_frame_ptr(outer);
+    res
+}
+
+enum Bound {
+    Locals(Vec<Slot>),
+    /// The closure waiting for the rest of the required arguments.
+    Curried(Ptr<Sexp>),
+}
+
+/// Bind the arguments to the params of the closure.
+fn bind(closure: &Closure, args: Vec<Ptr<Sexp>>, env: &Env) -> Result<Bound, EvalError> {
+    let data = closure.data();
+    let args = if data.bound.is_empty() {
+        args
+    } else {
+        data.bound.iter().cloned().chain(args).collect()
+    };
+    let proto = &data.proto;
+
+    if env.strict_arity() {
+        let expected = if proto.rest {
+            Arity::at_least(proto.params)
+        } else {
+            Arity::exactly(proto.params)
+        };
+        if !expected.accepts(args.len()) {
+            let name = env.current_call().map(|call| call.name());
+            return Err(EvalError::WrongArity {
+                name: name.unwrap_or_else(|| Sexp::Lambda.to_string()),
+                expected,
+                found: args.len(),
+            });
+        }
+    } else if args.len() < proto.params {
+        let curried = ClosureData {
+            proto: proto.clone(),
+            upvalues: data.upvalues.clone(),
+            frame: data.frame.clone(),
+            bound: args,
+        };
+        return Ok(Bound::Curried(Sexp::wrap(Sexp::Closure(Closure::new(curried)))));
+    }
+
+    let mut locals = vec![Slot::Unbound; proto.locals];
+    let mut args = args.into_iter();
+    for (local, arg) in locals.iter_mut().zip(args.by_ref().take(proto.params)) {
+        *local = Slot::Value(arg);
+    }
+    if proto.rest {
+        locals[proto.params] = Slot::Value(Sexp::from_vec(args.collect::<Vec<_>>()));
+    }
+    Ok(Bound::Locals(locals))
+}
+
+impl Vm {
+    fn new(env: &Env) -> Self {
+        Self {
+            stack: vec![],
+            frames: vec![],
+            spans: env.spans().clone(),
+            depth: env.depth(),
+            calls: env.call_depth(),
+        }
+    }
+
+    fn execute(&mut self, env: &mut Env) -> EvalResult {
+        loop {
+            match self.step(env) {
+                Ok(None) => {}
+                Ok(Some(value)) => return Ok(value),
+                Err(e) => return Err(self.unwind(e, env)),
+            }
+        }
+    }
+
+    fn top(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn pop(&mut self) -> Ptr<Sexp> {
+        self.stack.pop().unwrap()
+    }
+
+    fn step(&mut self, env: &mut Env) -> Stepped {
+        let frame = self.frames.last_mut().unwrap();
+        let op = frame.proto.code[frame.ip];
+        frame.ip += 1;
+
+        match op {
+            Op::Const(index) => {
+                let value = frame.proto.constants[index as usize].clone();
+                self.stack.push(value);
+            }
+            Op::Local(slot, name) => {
+                let value = match self.read(Loc::Local(slot)) {
+                    Some(value) => value,
+                    None => self.global(name, env)?,
+                };
+                self.stack.push(value);
+            }
+            Op::SetLocal(slot) => {
+                let value = self.pop();
+                self.write(Loc::Local(slot), value);
+            }
+            Op::InitLocal(slot) => {
+                let owner = frame.owner;
+                self.frames[owner].locals[slot as usize] = Slot::Unbound;
+            }
+            Op::Upvalue(index, name) => {
+                let value = match self.read(Loc::Upvalue(index)) {
+                    Some(value) => value,
+                    None => self.global(name, env)?,
+                };
+                self.stack.push(value);
+            }
+            Op::SetUpvalue(index) => {
+                let value = self.pop();
+                self.write(Loc::Upvalue(index), value);
+            }
+            Op::Global(name) => {
+                let value = self.global(name, env)?;
+                self.stack.push(value);
+            }
+            Op::SetGlobal(name) => {
+                let value = self.pop();
+                env.assign(self.top().proto.name(name), value)?;
+            }
+            Op::DefineGlobal(name) => {
+                let value = self.pop();
+                env.define(self.top().proto.name(name), value);
+            }
+            Op::Pop => {
+                self.pop();
+            }
+            Op::Jump(target) => frame.ip = target as usize,
+            Op::JumpUnlessTrue(target) => {
+                if !matches!(self.stack.pop().as_deref(), Some(Sexp::Bool(true))) {
+                    frame.ip = target as usize;
+                }
+            }
+            Op::EqNext(target) => {
+                let value = self.stack.pop().unwrap();
+                let first = self.stack.last_mut().unwrap();
+                if value != *first {
+                    *first = Sexp::bool(false);
+                    frame.ip = target as usize;
+                }
+            }
+            Op::EqEnd => *self.stack.last_mut().unwrap() = Sexp::bool(true),
+            Op::Cons => {
+                let cdr = self.pop();
+                let car = self.pop();
+                self.stack.push(Sexp::cons(car, cdr));
+            }
+            Op::Car => {
+                let value = self.pop();
+                self.stack.push(value.car());
+            }
+            Op::Cdr => {
+                let value = self.pop();
+                self.stack.push(value.cdr());
+            }
+            Op::Print => {
+                let value = self.pop();
+                if let Sexp::SString(content) = value.as_ref() {
+                    print!("{}", content);
+                } else {
+                    print!("{}", value);
+                }
+            }
+            Op::Closure(index) => {
+                let proto = frame.proto.protos[index as usize].clone();
+                let env_frame = frame.env_frame.clone();
+                let upvalues = proto.captures.iter().map(|loc| self.capture(*loc)).collect();
+                let closure = Closure::new(ClosureData {
+                    proto,
+                    upvalues,
+                    frame: env_frame,
+                    bound: vec![],
+                });
+                self.stack.push(Sexp::wrap(Sexp::Closure(closure)));
+            }
+            Op::Dispatch(site, skip) => {
+                let callee = self.stack.last().unwrap().clone();
+                let (is_macro, expands) = match callee.as_ref() {
+                    Sexp::Form(Cons { car, .. }) => (car.is_macro(), false),
+                    Sexp::RustFn(f) => (!f.evaluates_args(), !f.has_preprocess()),
+                    _ => (false, false),
+                };
+                if is_macro {
+                    frame.ip = skip as usize;
+                    let proto = frame.proto.clone();
+                    let site = &proto.sites[site as usize];
+                    self.pop();
+                    // The expansion of a Rust function is compiled in the scope of the site.
+                    if let (true, Sexp::RustFn(f)) = (expands, callee.as_ref()) {
+                        self.enter_call(CallKind::RustFn, site, site.args.clone(), site.tail, env);
+                        env.consume_fuel()?;
+                        let expr = f.apply(site.args.clone(), env)?;
+                        return self.run_in_scope(expr, site, site.tail, !site.tail, env);
+                    }
+                    let form = Sexp::cons(callee, site.args.clone());
+                    let value = self.walk_in_scope(form, site, env)?;
+                    return self.complete(value, site.tail, env);
+                }
+            }
+            Op::Call(argc, site) | Op::TailCall(argc, site) => {
+                let args = self.stack.split_off(self.stack.len() - argc as usize);
+                let callee = self.pop();
+                return self.call(callee, args, site, matches!(op, Op::TailCall(..)), env);
+            }
+            Op::Eval(site) => {
+                let proto = frame.proto.clone();
+                let site = &proto.sites[site as usize];
+                let expr = self.pop();
+                return self.run_in_scope(expr, site, site.tail, false, env);
+            }
+            Op::Return => {
+                let value = self.pop();
+                return Ok(self.return_value(value, env));
+            }
+        }
+        Ok(None)
+    }
+
+    fn global(&self, name: u32, env: &Env) -> Result<Ptr<Sexp>, EvalError> {
+        let proto = &self.top().proto;
+        let (ident, cache) = (proto.name(name), &proto.caches[name as usize]);
+        let value = match proto.in_frames {
+            true => env.get_in_frames(ident).or_else(|| env.get_cached(ident, cache)),
+            false => env.get_cached(ident, cache),
+        };
+        value.ok_or_else(|| EvalError::UnboundIdentifier(ident.to_string()))
+    }
+
+    /// The value of a variable of the current code, or `None` if it hasn't been bound.
+    fn read(&self, loc: Loc) -> Option<Ptr<Sexp>> {
+        let owner = &self.frames[self.top().owner];
+        match loc {
+            Loc::Local(slot) => match &owner.locals[slot as usize] {
+                Slot::Unbound => None,
+                Slot::Value(value) => Some(value.clone()),
+                Slot::Boxed(upvalue) => upvalue.borrow().clone(),
+            },
+            Loc::Upvalue(index) => owner.closure.as_ref().unwrap().data().upvalues[index as usize].borrow().clone(),
+        }
+    }
+
+    fn write(&mut self, loc: Loc, value: Ptr<Sexp>) {
+        let owner = self.top().owner;
+        let owner = &mut self.frames[owner];
+        match loc {
+            Loc::Local(slot) => match &mut owner.locals[slot as usize] {
+                Slot::Boxed(upvalue) => *upvalue.borrow_mut() = Some(value),
+                local => *local = Slot::Value(value),
+            },
+            Loc::Upvalue(index) => {
+                *owner.closure.as_ref().unwrap().data().upvalues[index as usize].borrow_mut() = Some(value)
+            }
+        }
+    }
+
+    /// Share a variable of the current code with a new closure.
+    fn capture(&mut self, loc: Loc) -> Upvalue {
+        let owner = self.top().owner;
+        let owner = &mut self.frames[owner];
+        match loc {
+            Loc::Local(slot) => {
+                let local = &mut owner.locals[slot as usize];
+                let upvalue = match local {
+                    Slot::Boxed(upvalue) => return upvalue.clone(),
+                    Slot::Value(value) => Gc::new(GcCell::new(Some(value.clone()))),
+                    Slot::Unbound => Gc::new(GcCell::new(None)),
+                };
+                *local = Slot::Boxed(upvalue.clone());
+                upvalue
+            }
+            Loc::Upvalue(index) => owner.closure.as_ref().unwrap().data().upvalues[index as usize].clone(),
+        }
+    }
+
+    /// Push the value of a call, or return it from the frame of a tail call.
+    fn complete(&mut self, value: Ptr<Sexp>, tail: bool, env: &mut Env) -> Stepped {
+        if tail {
+            Ok(self.return_value(value, env))
+        } else {
+            self.stack.push(value);
+            Ok(None)
+        }
+    }
+
+    /// Return from the current frame, or from the VM if it's the last one.
+    fn return_value(&mut self, value: Ptr<Sexp>, env: &mut Env) -> Option<Ptr<Sexp>> {
+        let frame = self.frames.pop().unwrap();
+        if frame.in_call {
+            env.pop_call();
+        }
+        if frame.nested {
+            env.leave_evaluation();
+        }
+        self.stack.truncate(frame.base);
+
+        if self.frames.is_empty() {
+            return Some(value);
+        }
+        self.switch_env_frame(&frame.env_frame, env);
+        self.stack.push(value);
+        None
+    }
+
+    /// Look up the globals of the current frame, after leaving the frame of `from`.
+    fn switch_env_frame(&self, from: &Option<Gc<GcCell<Frame>>>, env: &mut Env) {
+        let to = &self.top().env_frame;
+        let same = match (from, to) {
+            (Some(from), Some(to)) => Gc::ptr_eq(from, to),
+            (None, None) => true,
+            _ => false,
+        };
+        if !same {
+            env.set_frame_ptr(to.clone());
+        }
+    }
+
+    fn enter_call(&mut self, kind: CallKind, site: &Site, args: Ptr<Sexp>, tail: bool, env: &mut Env) {
+        let call = Call {
+            kind,
+            callee: site.callee.clone(),
+            args,
+            form: site.form.clone(),
+        };
+        let frame = self.frames.last_mut().unwrap();
+        if tail && frame.in_call {
+            env.replace_call(call);
+        } else {
+            env.push_call(call);
+            frame.in_call |= tail;
+        }
+    }
+
+    /// Start a frame for the call, in place of the current one if it's a tail call.
+    ///
+    /// The call has been entered on the shadow stack.
+    fn push_frame(&mut self, mut frame: CallFrame, tail: bool, env: &mut Env) -> Result<(), EvalError> {
+        let from = self.top().env_frame.clone();
+        if tail {
+            let old = self.frames.pop().unwrap();
+            self.stack.truncate(old.base);
+            frame.base = old.base;
+            frame.nested = old.nested;
+            if frame.owner == self.frames.len() + 1 {
+                frame.owner -= 1;
+            }
+        } else {
+            env.enter_evaluation()?;
+            frame.base = self.stack.len();
+            frame.nested = true;
+        }
+        self.frames.push(frame);
+        self.switch_env_frame(&from, env);
+        Ok(())
+    }
+
+    fn call(&mut self, callee: Ptr<Sexp>, args: Vec<Ptr<Sexp>>, site: u32, tail: bool, env: &mut Env) -> Stepped {
+        let proto = self.top().proto.clone();
+        let site = &proto.sites[site as usize];
+        match callee.as_ref() {
+            Sexp::Closure(closure) => {
+                self.enter_call(CallKind::Lambda, site, Sexp::from_vec(args.iter().cloned()), tail, env);
+                env.consume_fuel()?;
+                let locals = match bind(closure, args, env)? {
+                    Bound::Locals(locals) => locals,
+                    Bound::Curried(curried) => {
+                        if !tail {
+                            env.pop_call();
+                        }
+                        return self.complete(curried, tail, env);
+                    }
+                };
+
+                let data = closure.data();
+                let frame = CallFrame {
+                    proto: data.proto.clone(),
+                    ip: 0,
+                    base: 0,
+                    owner: self.frames.len(),
+                    locals,
+                    closure: Some(closure.clone()),
+                    env_frame: data.frame.clone(),
+                    in_call: true,
+                    nested: true,
+                    form: site.form.clone(),
+                };
+                self.push_frame(frame, tail, env)?;
+                Ok(None)
+            }
+            Sexp::RustFn(f) => {
+                let args = Sexp::from_vec(args);
+                self.enter_call(CallKind::RustFn, site, args.clone(), tail, env);
+                env.consume_fuel()?;
+                let expr = f.apply(args, env)?;
+                self.run_in_scope(expr, site, tail, !tail, env)
+            }
+            Sexp::Form(Cons { car, .. }) if car.is_lambda() => {
+                let value = walk_apply(callee.clone(), Sexp::from_vec(args), site.callee.clone(), env)?;
+                self.complete(value, tail, env)
+            }
+            Sexp::Continuation(_) => {
+                let value = walk_apply(callee.clone(), Sexp::from_vec(args), site.callee.clone(), env)?;
+                self.complete(value, tail, env)
+            }
+            Sexp::Nil => self.complete(callee, tail, env),
+            _ => Err(EvalError::NotApplicable(callee.to_string())),
+        }
+    }
+
+    /// Evaluate an expression built at runtime in the scope of the site,
+    /// e.g. the result of a Rust function.
+    ///
+    /// `in_call` is whether the expression is the result of a non-tail call,
+    /// which is left on the shadow stack until the expression is evaluated.
+    fn run_in_scope(&mut self, expr: Ptr<Sexp>, site: &Site, tail: bool, in_call: bool, env: &mut Env) -> Stepped {
+        let value = match expr.as_ref() {
+            Sexp::Identifier(ident) if keyword_name(ident).is_none() => None,
+            Sexp::Local(_) | Sexp::Global(_) => None,
+            Sexp::Form(Cons { car, cdr }) => match car.as_ref() {
+                Sexp::Quote if cdr.cdr().is_nil() => Some(cdr.car()),
+                _ => None,
+            },
+            _ => Some(expr.clone()),
+        };
+        let value = match value {
+            Some(value) => value,
+            None => match compile_in(&expr, site.scope.clone(), env) {
+                Ok(code) => {
+                    let owner = self.top().owner;
+                    let locals = &mut self.frames[owner].locals;
+                    if locals.len() < code.locals {
+                        locals.resize(code.locals, Slot::Unbound);
+                    }
+
+                    if tail {
+                        let frame = self.frames.last_mut().unwrap();
+                        frame.proto = code;
+                        frame.ip = 0;
+                        return Ok(None);
+                    }
+                    let frame = CallFrame {
+                        proto: code,
+                        ip: 0,
+                        base: 0,
+                        owner,
+                        locals: vec![],
+                        closure: None,
+                        env_frame: self.top().env_frame.clone(),
+                        in_call,
+                        nested: true,
+                        form: site.form.clone(),
+                    };
+                    self.push_frame(frame, false, env)?;
+                    return Ok(None);
+                }
+                Err(_) => self.walk_in_scope(expr, site, env)?,
+            },
+        };
+
+        if in_call {
+            env.pop_call();
+        }
+        self.complete(value, tail, env)
+    }
+
+    /// Evaluate an expression on the tree walker, with the variables of the site in a frame.
+    ///
+    /// The variables assigned by the expression are written back.
+    fn walk_in_scope(&mut self, expr: Ptr<Sexp>, site: &Site, env: &mut Env) -> EvalResult {
+        let names = &site.scope.names;
+        if names.is_empty() {
+            return walk(expr, env);
+        }
+
+        let frame = Frame::push_with_kind(env.top_frame(), FrameKind::Scope);
+        let values = names.iter().map(|(_, loc)| self.read(*loc)).collect::<Vec<_>>();
+        for ((name, _), value) in names.iter().zip(values.iter()) {
+            if let Some(value) = value {
+                frame.borrow_mut().insert(*name, value.clone());
+            }
+        }
+
+        let outer = env.set_frame_ptr(Some(frame.clone()));
+        let res = walk(expr, env);
+        env.set_frame_ptr(outer);
+
+        for ((name, loc), old) in names.iter().zip(values) {
+            let new = frame.borrow().inner.get(name).cloned();
+            match (new, old) {
+                (Some(new), Some(old)) if Gc::ptr_eq(&new, &old) => {}
+                (Some(new), _) => self.write(*loc, new),
+                (None, _) => {}
+            }
+        }
+        res
+    }
+
+    /// Clean up after an error, which is located at the innermost form read from a source file.
+    fn unwind(&mut self, e: EvalError, env: &mut Env) -> EvalError {
+        if !matches!(e, EvalError::Escape { .. }) {
+            env.capture_backtrace();
+        }
+        let location = self.frames.iter().rev().find_map(|frame| {
+            let form = frame.proto.located_at(frame.ip.saturating_sub(1)).or(frame.form.as_ref());
+            form.and_then(|form| self.spans.location_of(form))
+        });
+
+        env.restore_calls(self.calls, &[]);
+        env.set_depth(self.depth);
+        self.frames.clear();
+        self.stack.clear();
+        match location {
+            Some(location) => e.at(location),
+            None => e,
+        }
+    }
+}
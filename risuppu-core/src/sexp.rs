@@ -3,15 +3,23 @@ pub mod iter;
 pub mod parse;
 pub mod pattern;
 pub mod rustfn;
+pub mod span;
+pub mod symbol;
 use gc::{Finalize, Gc, GcCell, Trace};
 use std::fmt::Display;
 
-use self::{iter::SexpListIter, rustfn::RustFn};
-use crate::semantic::{frame::Frame, Env};
+use self::{iter::SexpListIter, rustfn::RustFn, symbol::Symbol};
+use crate::semantic::{
+    bytecode::Closure,
+    continuation::Continuation,
+    frame::{Frame, Layout},
+    resolve::{GlobalRef, LocalRef},
+    Env, EvalResult,
+};
 
 pub type Ptr<T> = Gc<T>;
 
-#[derive(Debug, PartialEq, Eq, Trace, Finalize)]
+#[derive(Debug, PartialEq, Eq, Trace)]
 pub enum Sexp {
     // IO
     Read,
@@ -23,6 +31,9 @@ pub enum Sexp {
 
     // Quote
     Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
 
     // List, Tree
     Cons,
@@ -32,6 +43,8 @@ pub enum Sexp {
     // Lambda
     Lambda,
     CapturedLambda(Gc<GcCell<Frame>>),
+    // Lambda compiled for the VM
+    Closure(Closure),
     // Macro, a kind of special lambdas, with every param quoted
     Macro,
     // Rust function
@@ -39,9 +52,13 @@ pub enum Sexp {
 
     // Evaluate
     Eval,
+    Begin,
 
     // Define
     Define,
+    Set,
+    Letrec,
+    LetrecStar,
 
     // Data
     I32(i32),
@@ -53,13 +70,33 @@ pub enum Sexp {
     Provide,
     Require,
 
+    // Exception
+    Try,
+    Error(ErrorValue),
+
+    // Continuation
+    CallCC,
+    Continuation(Continuation),
+
     // Identity
-    Identifier(String),
+    Identifier(Symbol),
+    // Identity resolved to the slot of a variable
+    Local(LocalRef),
+    Global(GlobalRef),
+    // The layout of the frame of a resolved lambda or `letrec`, in front of its params or bindings
+    Layout(Gc<Layout>),
 
     Nil,
     Form(Cons),
 }
 
+// Drop the span of a collected node, whose address may be reused.
+impl Finalize for Sexp {
+    fn finalize(&self) {
+        span::forget(self);
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Trace, Finalize)]
 pub struct Cons {
     pub car: Ptr<Sexp>,
@@ -72,6 +109,14 @@ impl Cons {
     }
 }
 
+/// An error raised by scripts or builtins, which can be caught by `try`.
+#[derive(Debug, PartialEq, Eq, Trace, Finalize)]
+pub struct ErrorValue {
+    /// The kind of the error, e.g. `type-error`, which is matched by the handler clauses.
+    pub kind: String,
+    pub message: Ptr<Sexp>,
+}
+
 macro_rules! keyword_wrapper {
     ($f:ident, $k:expr) => {
         pub fn $f() -> Ptr<Sexp> {
@@ -145,14 +190,23 @@ impl Sexp {
     keyword_wrapper!(r#if, Sexp::If);
     keyword_wrapper!(eq, Sexp::Eq);
     keyword_wrapper!(quote, Sexp::Quote);
+    keyword_wrapper!(quasiquote, Sexp::Quasiquote);
+    keyword_wrapper!(unquote, Sexp::Unquote);
+    keyword_wrapper!(unquote_splicing, Sexp::UnquoteSplicing);
     keyword_wrapper!(car_token, Sexp::Car);
     keyword_wrapper!(cdr_token, Sexp::Cdr);
     keyword_wrapper!(lambda, Sexp::Lambda);
     keyword_wrapper!(r#macro, Sexp::Macro);
     keyword_wrapper!(eval, Sexp::Eval);
+    keyword_wrapper!(begin, Sexp::Begin);
     keyword_wrapper!(define, Sexp::Define);
+    keyword_wrapper!(set, Sexp::Set);
+    keyword_wrapper!(letrec, Sexp::Letrec);
+    keyword_wrapper!(letrec_star, Sexp::LetrecStar);
     keyword_wrapper!(require, Sexp::Require);
     keyword_wrapper!(provide, Sexp::Provide);
+    keyword_wrapper!(r#try, Sexp::Try);
+    keyword_wrapper!(call_cc, Sexp::CallCC);
 
     literal_wrapper!(int, i32, Sexp::I32);
     literal_wrapper!(r#char, char, Sexp::Char);
@@ -163,7 +217,14 @@ impl Sexp {
     }
 
     pub fn identifier(s: impl ToString) -> Ptr<Self> {
-        Sexp::wrap(Sexp::Identifier(s.to_string()))
+        Sexp::wrap(Sexp::Identifier(Symbol::intern(&s.to_string())))
+    }
+
+    pub fn error(kind: impl ToString, message: Ptr<Sexp>) -> Ptr<Self> {
+        Sexp::wrap(Sexp::Error(ErrorValue {
+            kind: kind.to_string(),
+            message,
+        }))
     }
 
     pub fn lambda_capture(frame_ptr: Gc<GcCell<Frame>>) -> Ptr<Self> {
@@ -174,7 +235,7 @@ impl Sexp {
     /// Don't capture `Gc` value in the closure, which will escape from the gc management.
     /// Don't recurse in the function body.
     /// Quote the ret-value if it might be a list and this function is not a macro.
-    pub unsafe fn rust_fn(f: impl FnMut(Ptr<Sexp>, &mut Env) -> Ptr<Sexp> + 'static) -> Ptr<Self> {
+    pub unsafe fn rust_fn(f: impl FnMut(Ptr<Sexp>, &mut Env) -> EvalResult + 'static) -> Ptr<Self> {
         Sexp::wrap(Sexp::RustFn(RustFn::new(f)))
     }
 
@@ -183,12 +244,29 @@ impl Sexp {
     /// Don't recurse in f's body.
     /// Quote the ret-value if it might be a list and this function is not a macro.
     pub unsafe fn rust_fn_with_preprocess(
-        f: impl FnMut(Ptr<Sexp>, &mut Env) -> Ptr<Sexp> + 'static,
-        p: impl Fn(Ptr<Sexp>, &mut Env) -> Ptr<Sexp> + 'static,
+        f: impl FnMut(Ptr<Sexp>, &mut Env) -> EvalResult + 'static,
+        p: impl Fn(Ptr<Sexp>, &mut Env) -> EvalResult + 'static,
     ) -> Ptr<Self> {
         Sexp::wrap(Sexp::RustFn(RustFn::new_with_preprocess(f, p)))
     }
 
+    /// # Safety
+    /// Don't capture `Gc` value in the closure, which will escape from the gc management.
+    /// Don't recurse in f's body.
+    /// Quote the ret-value if it might be a list.
+    pub unsafe fn rust_fn_with_evaluated_args(
+        f: impl FnMut(Ptr<Sexp>, &mut Env) -> EvalResult + 'static,
+    ) -> Ptr<Self> {
+        Sexp::wrap(Sexp::RustFn(RustFn::new_with_evaluated_args(f)))
+    }
+
+    /// # Safety
+    /// Don't capture `Gc` value in the closure, which will escape from the gc management.
+    /// Don't recurse in f's body.
+    pub unsafe fn rust_fn_expander(f: impl FnMut(Ptr<Sexp>, &mut Env) -> EvalResult + 'static) -> Ptr<Self> {
+        Sexp::wrap(Sexp::RustFn(RustFn::new_expander(f)))
+    }
+
     pub fn iter(list: Ptr<Sexp>) -> SexpListIter {
         SexpListIter::new(list)
     }
@@ -218,6 +296,9 @@ impl Display for Sexp {
             Sexp::If => write!(f, "if"),
             Sexp::Eq => write!(f, "eq"),
             Sexp::Quote => write!(f, "quote"),
+            Sexp::Quasiquote => write!(f, "quasiquote"),
+            Sexp::Unquote => write!(f, "unquote"),
+            Sexp::UnquoteSplicing => write!(f, "unquote-splicing"),
             Sexp::Cons => write!(f, "cons"),
             Sexp::Car => write!(f, "car"),
             Sexp::Cdr => write!(f, "cdr"),
@@ -225,15 +306,27 @@ impl Display for Sexp {
             Sexp::Macro => write!(f, "macro"),
             Sexp::RustFn(_) => write!(f, "rustfn"),
             Sexp::Eval => write!(f, "eval"),
+            Sexp::Begin => write!(f, "begin"),
             Sexp::Define => write!(f, "define"),
+            Sexp::Set => write!(f, "set!"),
+            Sexp::Letrec => write!(f, "letrec"),
+            Sexp::LetrecStar => write!(f, "letrec*"),
             Sexp::Require => write!(f, "require"),
             Sexp::Provide => write!(f, "provide"),
+            Sexp::Try => write!(f, "try"),
+            Sexp::Error(e) => write!(f, "#<error {}>", e),
+            Sexp::CallCC => write!(f, "call/cc"),
+            Sexp::Continuation(_) => write!(f, "#<continuation>"),
+            Sexp::Closure(closure) => write!(f, "{closure}"),
             Sexp::Nil => write!(f, "()"),
             Sexp::I32(n) => write!(f, "{}", n),
             Sexp::Char(c) => write!(f, "'{}'", c),
             Sexp::SString(s) => write!(f, "\"{}\"", s),
             Sexp::Bool(b) => write!(f, "{}", b),
             Sexp::Identifier(ident) => write!(f, "{}", ident),
+            Sexp::Local(local) => write!(f, "{}", local.name),
+            Sexp::Global(global) => write!(f, "{}", global.name),
+            Sexp::Layout(_) => write!(f, "#:scope"),
             Sexp::Form(cons) => {
                 write!(f, "(")?;
                 write!(f, "{}", cons)?;
@@ -267,9 +360,23 @@ impl From<char> for Sexp {
     }
 }
 
+impl Display for ErrorValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Sexp::SString(message) = self.message.as_ref() {
+            write!(f, "{}: {}", self.kind, message)
+        } else {
+            write!(f, "{}: {}", self.kind, self.message)
+        }
+    }
+}
+
 impl Display for Cons {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match (self.car.as_ref(), self.cdr.as_ref()) {
+            // The layout added by the resolver isn't part of the params or the bindings as written.
+            (Sexp::Layout(_), Sexp::Form(tail_cons)) => write!(f, "{}", tail_cons),
+            (Sexp::Layout(_), Sexp::Nil) => Ok(()),
+            (Sexp::Layout(_), rest) => write!(f, "&rest {}", rest),
             (Sexp::Form(_), Sexp::Form(tail_cons)) => {
                 write!(f, "{} {}", self.car.clone(), tail_cons)
             }
@@ -1,17 +1,23 @@
 use nom::branch::alt;
-use nom::bytes::complete::tag;
-use nom::character::complete::anychar;
-use nom::combinator::{eof, map, peek};
+use nom::bytes::complete::{escaped, tag};
+use nom::character::complete::{anychar, none_of};
+use nom::combinator::{eof, map, peek, verify};
 use nom::multi::{fold_many0, many_till};
-use nom::sequence::{delimited, preceded, tuple};
+use nom::sequence::{delimited, preceded, terminated, tuple};
 use nom::{IResult, Parser};
+use std::cell::RefCell;
 
+use crate::sexp::span::{FileId, Span, SpanTable};
+use crate::sexp::symbol::Symbol;
 use crate::sexp::{Ptr, Sexp};
 
+mod reader;
+pub use reader::{ParseError, ParseErrorKind, Reader};
+
 macro_rules! parse_sexp_keyword {
     ($s:expr, $res:expr) => {
         map(
-            delimited(discard_seperator_many0, tag($s), object_tail),
+            delimited(discard_seperator_many0, terminated(tag($s), token_end), object_tail),
             |_| $res,
         )
     };
@@ -27,35 +33,103 @@ pub fn parse_sexp(input: &str) -> IResult<&str, Ptr<Sexp>> {
     object(input)
 }
 
+// Parsed objects and the addresses where they start and end.
+type RecordedSpans = Vec<(Ptr<Sexp>, usize, usize)>;
+
+thread_local! {
+    static SPAN_RECORDER: RefCell<Option<RecordedSpans>> = const { RefCell::new(None) };
+}
+
+fn record_span(start: &str, remaining: &str, obj: &Ptr<Sexp>) {
+    SPAN_RECORDER.with(|recorder| {
+        if let Some(recorder) = recorder.borrow_mut().as_mut() {
+            let len = start.len() - remaining.len();
+            let len = start[..len].trim_end().len();
+            let start = start.as_ptr() as usize;
+            recorder.push((obj.clone(), start, start + len));
+        }
+    })
+}
+
+/// Parse an expression like [`parse_sexp`], recording the span of every parsed node.
+///
+/// `input` must be a slice of `source`, which has been added to `spans` as `file`.
+pub fn parse_sexp_with_spans<'a>(
+    input: &'a str,
+    source: &str,
+    file: FileId,
+    spans: &SpanTable,
+) -> IResult<&'a str, Ptr<Sexp>> {
+    SPAN_RECORDER.with(|recorder| *recorder.borrow_mut() = Some(vec![]));
+    let res = object(input);
+    let recorded = SPAN_RECORDER.with(|recorder| recorder.borrow_mut().take());
+
+    let base = source.as_ptr() as usize;
+    if res.is_ok() {
+        for (obj, start, end) in recorded.into_iter().flatten() {
+            let span = Span {
+                start: start - base,
+                end: end - base,
+            };
+            spans.insert(obj, file, span);
+        }
+    }
+
+    res
+}
+
 fn list(input: &str) -> IResult<&str, Ptr<Sexp>> {
-    let (remaining, (object_vec, _)) = many_till(object, peek(tag(")")))(input)?;
+    let (input, _) = discard_seperator_many0(input)?;
+    let list_end = alt((map(peek(tag(")")), |_| Sexp::nil()), dotted_tail));
+    let (remaining, (object_vec, tail)) = verify(many_till(object, list_end), |(object_vec, tail)| {
+        !object_vec.is_empty() || tail.is_nil()
+    })(input)?;
     Ok((
         remaining,
         object_vec
             .into_iter()
             .rev()
-            .fold(Sexp::nil(), |cur, obj| Sexp::cons(obj, cur)),
+            .fold(tail, |cur, obj| Sexp::cons(obj, cur)),
     ))
 }
 
+/// The tail of a dotted list, e.g. `. c` in `(a b . c)`.
+fn dotted_tail(input: &str) -> IResult<&str, Ptr<Sexp>> {
+    delimited(terminated(tag("."), discard_seperator_1), object, peek(tag(")")))(input)
+}
+
 fn object(input: &str) -> IResult<&str, Ptr<Sexp>> {
     let right_paren = map(tag(")"), |_| ());
 
-    alt((
+    let (start, _) = discard_seperator_many0(input)?;
+    let (remaining, obj) = alt((
         wrap_seperator!(delimited(tag("("), list, right_paren)),
         wrap_seperator!(map(preceded(tag("'"), object), |obj| {
             Sexp::from_vec([Sexp::quote(), obj])
         })),
+        wrap_seperator!(map(preceded(tag("`"), object), |obj| {
+            Sexp::from_vec([Sexp::quasiquote(), obj])
+        })),
+        wrap_seperator!(map(preceded(tag(",@"), object), |obj| {
+            Sexp::from_vec([Sexp::unquote_splicing(), obj])
+        })),
+        wrap_seperator!(map(preceded(tag(","), object), |obj| {
+            Sexp::from_vec([Sexp::unquote(), obj])
+        })),
         atom,
-    ))(input)
+    ))(start)?;
+    record_span(start, remaining, &obj);
+
+    Ok((remaining, obj))
 }
 
 fn comment(input: &str) -> IResult<&str, ()> {
-    map(tuple((tag(";;"), many_till(anychar, tag("\n")))), |_| ())(input)
+    let line_end = alt((map(tag("\n"), |_| ()), map(eof, |_| ())));
+    map(tuple((tag(";;"), many_till(anychar, line_end))), |_| ())(input)
 }
 
 fn seperator(input: &str) -> IResult<&str, ()> {
-    (map(alt((tag(" "), tag("\t"), tag("\n"))), |_| ()).or(comment)).parse(input)
+    (map(alt((tag(" "), tag("\t"), tag("\r"), tag("\n"))), |_| ()).or(comment)).parse(input)
 }
 
 fn discard_seperator_many0(input: &str) -> IResult<&str, ()> {
@@ -70,33 +144,66 @@ fn object_tail(input: &str) -> IResult<&str, ()> {
     discard_seperator_many0(input)
 }
 
+/// Check that a token is not followed by other characters of the same token.
+fn token_end(input: &str) -> IResult<&str, ()> {
+    let paren = map(alt((tag("("), tag(")"))), |_| ());
+    let eof = map(eof, |_| ());
+    peek(alt((eof, paren, discard_seperator_1)))(input)
+}
+
+fn keyword(input: &str) -> IResult<&str, Sexp> {
+    // `alt` takes at most 21 parsers.
+    alt((special_form, builtin_keyword))(input)
+}
+
+fn builtin_keyword(input: &str) -> IResult<&str, Sexp> {
+    alt((
+        parse_sexp_keyword!("read", Sexp::Read),
+        parse_sexp_keyword!("print", Sexp::Print),
+        parse_sexp_keyword!("if", Sexp::If),
+        parse_sexp_keyword!("eq", Sexp::Eq),
+        parse_sexp_keyword!("quote", Sexp::Quote),
+        parse_sexp_keyword!("quasiquote", Sexp::Quasiquote),
+        parse_sexp_keyword!("unquote-splicing", Sexp::UnquoteSplicing),
+        parse_sexp_keyword!("unquote", Sexp::Unquote),
+        parse_sexp_keyword!("cons", Sexp::Cons),
+        parse_sexp_keyword!("car", Sexp::Car),
+        parse_sexp_keyword!("cdr", Sexp::Cdr),
+        parse_sexp_keyword!("eval", Sexp::Eval),
+        parse_sexp_keyword!("provide", Sexp::Provide),
+        parse_sexp_keyword!("require", Sexp::Require),
+    ))(input)
+}
+
+fn special_form(input: &str) -> IResult<&str, Sexp> {
+    alt((
+        parse_sexp_keyword!("lambda", Sexp::Lambda),
+        parse_sexp_keyword!("macro", Sexp::Macro),
+        parse_sexp_keyword!("define", Sexp::Define),
+        parse_sexp_keyword!("set!", Sexp::Set),
+        parse_sexp_keyword!("letrec*", Sexp::LetrecStar),
+        parse_sexp_keyword!("letrec", Sexp::Letrec),
+        parse_sexp_keyword!("begin", Sexp::Begin),
+        parse_sexp_keyword!("try", Sexp::Try),
+        parse_sexp_keyword!("call-with-current-continuation", Sexp::CallCC),
+        parse_sexp_keyword!("call/cc", Sexp::CallCC),
+    ))(input)
+}
+
 fn atom(input: &str) -> IResult<&str, Ptr<Sexp>> {
     map(
         alt((
-            parse_sexp_keyword!("read", Sexp::Read),
-            parse_sexp_keyword!("print", Sexp::Print),
-            parse_sexp_keyword!("if", Sexp::If),
-            parse_sexp_keyword!("eq", Sexp::Eq),
-            parse_sexp_keyword!("quote", Sexp::Quote),
-            parse_sexp_keyword!("cons", Sexp::Cons),
-            parse_sexp_keyword!("car", Sexp::Car),
-            parse_sexp_keyword!("cdr", Sexp::Cdr),
-            parse_sexp_keyword!("lambda", Sexp::Lambda),
-            parse_sexp_keyword!("macro", Sexp::Macro),
-            parse_sexp_keyword!("eval", Sexp::Eval),
-            parse_sexp_keyword!("define", Sexp::Define),
-            parse_sexp_keyword!("provide", Sexp::Provide),
-            parse_sexp_keyword!("require", Sexp::Require),
-            wrap_seperator!(map(preceded(tag("#\\"), anychar), Sexp::Char)),
+            keyword,
+            wrap_seperator!(map(terminated(preceded(tag("#\\"), anychar), token_end), Sexp::Char)),
             wrap_seperator!(map(
-                preceded(tag("#"), alt((tag("t"), tag("f")))),
+                terminated(preceded(tag("#"), alt((tag("t"), tag("f")))), token_end),
                 |s| match s {
                     "t" => Sexp::Bool(true),
                     "f" => Sexp::Bool(false),
                     _ => Sexp::Nil,
                 }
             )),
-            wrap_seperator!(map(nom::character::complete::i32, Sexp::I32)),
+            wrap_seperator!(map(terminated(nom::character::complete::i32, token_end), Sexp::I32)),
             wrap_seperator!(sstring),
             wrap_seperator!(identifier),
         )),
@@ -106,29 +213,32 @@ fn atom(input: &str) -> IResult<&str, Ptr<Sexp>> {
 
 fn identifier(input: &str) -> IResult<&str, Sexp> {
     let right_paren = map(tag(")"), |_| ());
+    let left_paren = map(tag("("), |_| ());
     let eof = map(eof, |_| ());
 
     map(
-        many_till(anychar, peek(alt((eof, right_paren, discard_seperator_1)))),
-        |(res, _)| Sexp::Identifier(res.into_iter().collect()),
+        verify(
+            many_till(anychar, peek(alt((eof, right_paren, left_paren, discard_seperator_1)))),
+            // A single `.` is the delimiter of dotted lists.
+            |(res, _): &(Vec<char>, ())| !res.is_empty() && res[0] != '"' && res[..] != ['.'],
+        ),
+        |(res, _)| Sexp::Identifier(Symbol::intern(&res.into_iter().collect::<String>())),
     )(input)
 }
 
 fn sstring(input: &str) -> IResult<&str, Sexp> {
-    map(
-        delimited(tag("\""), many_till(anychar, peek(tag("\""))), tag("\"")),
-        |(res, _)| {
-            let s: String = res.into_iter().collect();
-            let s = unescaper::unescape(&s).unwrap_or("ERROR when unescaping".to_string());
-            Sexp::SString(s)
-        },
-    )(input)
+    let content = alt((escaped(none_of("\\\""), '\\', anychar), tag("")));
+
+    map(delimited(tag("\""), content, tag("\"")), |s: &str| {
+        let s = unescaper::unescape(s).unwrap_or("ERROR when unescaping".to_string());
+        Sexp::SString(s)
+    })(input)
 }
 
 #[cfg(test)]
 mod test {
-    use super::parse_sexp;
-    use crate::sexp::Sexp;
+    use super::{parse_sexp, ParseErrorKind, Reader};
+    use crate::sexp::{span::SpanTable, symbol::Symbol, Sexp};
 
     #[test]
     fn parse_keyword() {
@@ -137,6 +247,9 @@ mod test {
         assert_eq!(parse_sexp("if").unwrap().1, Sexp::wrap(Sexp::If));
         assert_eq!(parse_sexp("eq").unwrap().1, Sexp::wrap(Sexp::Eq));
         assert_eq!(parse_sexp("quote").unwrap().1, Sexp::wrap(Sexp::Quote));
+        assert_eq!(parse_sexp("quasiquote").unwrap().1, Sexp::wrap(Sexp::Quasiquote));
+        assert_eq!(parse_sexp("unquote").unwrap().1, Sexp::wrap(Sexp::Unquote));
+        assert_eq!(parse_sexp("unquote-splicing").unwrap().1, Sexp::wrap(Sexp::UnquoteSplicing));
         assert_eq!(parse_sexp("cons").unwrap().1, Sexp::wrap(Sexp::Cons));
         assert_eq!(parse_sexp("car").unwrap().1, Sexp::wrap(Sexp::Car));
         assert_eq!(parse_sexp("cdr").unwrap().1, Sexp::wrap(Sexp::Cdr));
@@ -144,8 +257,18 @@ mod test {
         assert_eq!(parse_sexp("macro").unwrap().1, Sexp::wrap(Sexp::Macro));
         assert_eq!(parse_sexp("eval").unwrap().1, Sexp::wrap(Sexp::Eval));
         assert_eq!(parse_sexp("define").unwrap().1, Sexp::wrap(Sexp::Define));
+        assert_eq!(parse_sexp("set!").unwrap().1, Sexp::wrap(Sexp::Set));
+        assert_eq!(parse_sexp("begin").unwrap().1, Sexp::wrap(Sexp::Begin));
+        assert_eq!(parse_sexp("letrec").unwrap().1, Sexp::wrap(Sexp::Letrec));
+        assert_eq!(parse_sexp("letrec*").unwrap().1, Sexp::wrap(Sexp::LetrecStar));
         assert_eq!(parse_sexp("provide").unwrap().1, Sexp::wrap(Sexp::Provide));
         assert_eq!(parse_sexp("require").unwrap().1, Sexp::wrap(Sexp::Require));
+        assert_eq!(parse_sexp("try").unwrap().1, Sexp::wrap(Sexp::Try));
+        assert_eq!(parse_sexp("call/cc").unwrap().1, Sexp::wrap(Sexp::CallCC));
+        assert_eq!(
+            parse_sexp("call-with-current-continuation").unwrap().1,
+            Sexp::wrap(Sexp::CallCC)
+        );
     }
 
     #[test]
@@ -178,18 +301,18 @@ mod test {
         assert_eq!(
             parse_sexp("(this-is-a-ident)").unwrap().1,
             Sexp::cons(
-                Sexp::wrap(Sexp::Identifier("this-is-a-ident".to_string())),
+                Sexp::wrap(Sexp::Identifier(Symbol::intern("this-is-a-ident"))),
                 Sexp::nil()
             )
         );
         assert_eq!(
             parse_sexp("(ident ident ident)").unwrap().1,
             Sexp::cons(
-                Sexp::wrap(Sexp::Identifier("ident".to_string())),
+                Sexp::wrap(Sexp::Identifier(Symbol::intern("ident"))),
                 Sexp::cons(
-                    Sexp::wrap(Sexp::Identifier("ident".to_string())),
+                    Sexp::wrap(Sexp::Identifier(Symbol::intern("ident"))),
                     Sexp::cons(
-                        Sexp::wrap(Sexp::Identifier("ident".to_string())),
+                        Sexp::wrap(Sexp::Identifier(Symbol::intern("ident"))),
                         Sexp::nil()
                     )
                 )
@@ -197,7 +320,7 @@ mod test {
         );
         assert_eq!(
             parse_sexp("ident").unwrap().1,
-            Sexp::wrap(Sexp::Identifier("ident".to_string()))
+            Sexp::wrap(Sexp::Identifier(Symbol::intern("ident")))
         );
     }
 
@@ -230,9 +353,9 @@ mod test {
             Sexp::cons(
                 Sexp::wrap(Sexp::Lambda),
                 Sexp::cons(
-                    Sexp::cons(Sexp::wrap(Sexp::Identifier("a".to_string())), Sexp::nil()),
+                    Sexp::cons(Sexp::wrap(Sexp::Identifier(Symbol::intern("a"))), Sexp::nil()),
                     Sexp::cons(
-                        Sexp::cons(Sexp::wrap(Sexp::Identifier("a".to_string())), Sexp::nil()),
+                        Sexp::cons(Sexp::wrap(Sexp::Identifier(Symbol::intern("a"))), Sexp::nil()),
                         Sexp::nil()
                     )
                 )
@@ -274,4 +397,122 @@ mod test {
         let expected = Sexp::from_vec([Sexp::quote(), Sexp::int(1)]);
         assert_eq!(expr, expected);
     }
+
+    #[test]
+    fn parse_quasiquoted() {
+        let expr = parse_sexp("`(a ,b ,@c)").unwrap().1;
+        let expected = Sexp::from_vec([
+            Sexp::quasiquote(),
+            Sexp::from_vec([
+                Sexp::identifier("a"),
+                Sexp::from_vec([Sexp::unquote(), Sexp::identifier("b")]),
+                Sexp::from_vec([Sexp::unquote_splicing(), Sexp::identifier("c")]),
+            ]),
+        ]);
+        assert_eq!(expr, expected);
+        assert_eq!(parse_sexp("(quasiquote (unquote-splicing c))").unwrap().1, parse_sexp("`,@c").unwrap().1);
+    }
+
+    #[test]
+    fn read_spans() {
+        let spans = SpanTable::new();
+        let source = "(define a 1)\n;; comment\n  (f\n    'a)\n";
+        let exprs: Vec<_> = Reader::new("test.risp", source, &spans)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(exprs.len(), 2);
+
+        let location = |s| spans.location_of(&s).unwrap().to_string();
+        assert_eq!(location(exprs[0].clone()), "test.risp:1:1");
+        assert_eq!(location(exprs[0].cdr().car()), "test.risp:1:9");
+        assert_eq!(location(exprs[1].clone()), "test.risp:3:3");
+        assert_eq!(location(exprs[1].cdr().car()), "test.risp:4:5");
+
+        let (_, span) = spans.span_of(&exprs[1]).unwrap();
+        assert_eq!(&source[span.start..span.end], "(f\n    'a)");
+    }
+
+    #[test]
+    fn read_error_location() {
+        let spans = SpanTable::new();
+        let mut reader = Reader::new("test.risp", "(a)\n  )", &spans);
+        assert!(reader.next().unwrap().is_ok());
+        let e = reader.next().unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "test.risp:2:3: unexpected `)`");
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn unclosed_paren() {
+        let spans = SpanTable::new();
+        let source = "(define (f a)\n  (g a)\n(define b 1)\n";
+        let mut reader = Reader::new("test.risp", source, &spans);
+        let e = reader.next().unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "test.risp:3:1: unclosed `(` opened at 1:1");
+        let expected = parse_sexp("(define b 1)").unwrap().1;
+        assert_eq!(reader.next().unwrap().unwrap(), expected);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let spans = SpanTable::new();
+        let source = "(print 1)\n(print \"a)\n";
+        let errors = Reader::new("test.risp", source, &spans).read_all().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind, ParseErrorKind::UnterminatedString { .. }));
+        assert_eq!(errors[0].to_string(), "test.risp:3:1: unterminated string opened at 2:8");
+    }
+
+    #[test]
+    fn report_every_error() {
+        let spans = SpanTable::new();
+        let source = "(a (b\n(c)\n)\n(d\n(e)";
+        let errors = Reader::new("test.risp", source, &spans).read_all().unwrap_err();
+        let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "test.risp:2:1: unclosed `(` opened at 1:4",
+                "test.risp:3:1: unexpected `)`",
+                "test.risp:5:1: unclosed `(` opened at 4:1",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_dotted_list() {
+        let expected = Sexp::cons(
+            Sexp::identifier("a"),
+            Sexp::cons(Sexp::identifier("b"), Sexp::identifier("c")),
+        );
+        assert_eq!(parse_sexp("(a b . c)").unwrap().1, expected);
+        assert_eq!(parse_sexp("(a b . c)").unwrap().1.to_string(), "(a b . c)");
+        assert_eq!(parse_sexp("(a . (b c))").unwrap().1, parse_sexp("(a b c)").unwrap().1);
+        assert_eq!(
+            parse_sexp("(a .b)").unwrap().1,
+            Sexp::from_vec([Sexp::identifier("a"), Sexp::identifier(".b")])
+        );
+        assert!(parse_sexp("(. a)").is_err());
+        assert!(parse_sexp("(a . b c)").is_err());
+    }
+
+    #[test]
+    fn keyword_prefix() {
+        assert_eq!(parse_sexp("reader").unwrap().1, Sexp::identifier("reader"));
+        assert_eq!(parse_sexp("(iffy)").unwrap().1, Sexp::from_vec([Sexp::identifier("iffy")]));
+        assert_eq!(parse_sexp("1+").unwrap().1, Sexp::identifier("1+"));
+    }
+
+    #[test]
+    fn parse_escaped_string() {
+        assert_eq!(parse_sexp("\"a\\\"b\"").unwrap().1, Sexp::string("a\"b"));
+        assert_eq!(parse_sexp("\"\"").unwrap().1, Sexp::string(""));
+    }
+
+    #[test]
+    fn parse_spaced_list() {
+        assert_eq!(parse_sexp("( )").unwrap().1, Sexp::nil());
+        assert_eq!(parse_sexp("( a )").unwrap().1, Sexp::from_vec([Sexp::identifier("a")]));
+    }
 }
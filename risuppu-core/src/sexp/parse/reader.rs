@@ -0,0 +1,222 @@
+use std::fmt::Display;
+
+use crate::sexp::span::{FileId, Location, SpanTable};
+use crate::sexp::{Ptr, Sexp};
+
+use super::{discard_seperator_many0, parse_sexp_with_spans};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnclosedParen { opened_at: Location },
+    UnterminatedString { opened_at: Location },
+    UnexpectedCloseParen,
+    InvalidSyntax(String),
+}
+
+impl Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseErrorKind::UnclosedParen { opened_at } => write!(
+                f,
+                "unclosed `(` opened at {}:{}",
+                opened_at.line, opened_at.column
+            ),
+            ParseErrorKind::UnterminatedString { opened_at } => write!(
+                f,
+                "unterminated string opened at {}:{}",
+                opened_at.line, opened_at.column
+            ),
+            ParseErrorKind::UnexpectedCloseParen => write!(f, "unexpected `)`"),
+            ParseErrorKind::InvalidSyntax(near) => write!(f, "invalid syntax near `{near}`"),
+        }
+    }
+}
+
+/// An error when parsing a source file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Where the reader gave up the form.
+    pub location: Location,
+    pub kind: ParseErrorKind,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+enum Problem {
+    UnclosedParen(usize),
+    UnterminatedString(usize),
+    UnexpectedCloseParen(usize),
+}
+
+/// Scan the form at the start of `input` for unbalanced delimiters.
+///
+/// Return the problem found and the offset where the next top-level form may start.
+/// An open paren at the start of a line is regarded as the start of the next top-level form.
+fn scan(input: &str) -> (Option<Problem>, usize) {
+    let mut opened = vec![];
+    let mut chars = input.char_indices().peekable();
+    let mut at_line_start = false;
+
+    while let Some((i, c)) = chars.next() {
+        let line_start = at_line_start;
+        at_line_start = c == '\n';
+
+        match c {
+            '(' if line_start && !opened.is_empty() => {
+                return (opened.last().copied().map(Problem::UnclosedParen), i);
+            }
+            '(' => opened.push(i),
+            ')' => match opened.pop() {
+                None => return (Some(Problem::UnexpectedCloseParen(i)), i + 1),
+                Some(_) if opened.is_empty() => return (None, i + 1),
+                Some(_) => {}
+            },
+            '"' => loop {
+                match chars.next() {
+                    None => return (Some(Problem::UnterminatedString(i)), input.len()),
+                    Some((_, '\\')) => {
+                        chars.next();
+                    }
+                    Some((_, '"')) => break,
+                    Some(_) => {}
+                }
+            },
+            ';' if matches!(chars.peek(), Some((_, ';'))) => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        at_line_start = true;
+                        break;
+                    }
+                }
+            }
+            '#' if matches!(chars.peek(), Some((_, '\\'))) => {
+                chars.next();
+                chars.next();
+            }
+            c if c.is_whitespace() && opened.is_empty() => return (None, i),
+            _ => {}
+        }
+    }
+
+    (opened.last().copied().map(Problem::UnclosedParen), input.len())
+}
+
+/// Read the top-level expressions of a source file one by one,
+/// recording the spans of the parsed nodes.
+///
+/// After a syntax error, the reader skips to the next top-level form,
+/// so that every syntax error in the file can be reported.
+pub struct Reader<'a> {
+    source: &'a str,
+    remaining: &'a str,
+    file: FileId,
+    spans: SpanTable,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(name: impl ToString, source: &'a str, spans: &SpanTable) -> Self {
+        let file = spans.add_file(name, source);
+        Self {
+            source,
+            remaining: source,
+            file,
+            spans: spans.clone(),
+        }
+    }
+
+    /// Read all the expressions, or collect all the syntax errors.
+    pub fn read_all(self) -> Result<Vec<Ptr<Sexp>>, Vec<ParseError>> {
+        let mut exprs = vec![];
+        let mut errors = vec![];
+        for expr in self {
+            match expr {
+                Ok(expr) => exprs.push(expr),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(exprs)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn location(&self, input: &str) -> Location {
+        let offset = input.as_ptr() as usize - self.source.as_ptr() as usize;
+        self.spans.location(self.file, offset)
+    }
+
+    /// Diagnose the form which can't be parsed, and skip it.
+    fn recover(&mut self, form: &'a str, failed_at: &'a str) -> ParseError {
+        let (problem, next_form) = scan(form);
+        // Always make progress.
+        let next_form = next_form.max(form.chars().next().map_or(0, char::len_utf8));
+        self.remaining = &form[next_form..];
+
+        let (location, kind) = match problem {
+            Some(Problem::UnclosedParen(i)) => (
+                self.location(self.remaining),
+                ParseErrorKind::UnclosedParen {
+                    opened_at: self.location(&form[i..]),
+                },
+            ),
+            Some(Problem::UnterminatedString(i)) => (
+                self.location(self.remaining),
+                ParseErrorKind::UnterminatedString {
+                    opened_at: self.location(&form[i..]),
+                },
+            ),
+            Some(Problem::UnexpectedCloseParen(i)) => (
+                self.location(&form[i..]),
+                ParseErrorKind::UnexpectedCloseParen,
+            ),
+            None => {
+                let near = failed_at
+                    .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                    .find(|token| !token.is_empty())
+                    .unwrap_or(failed_at);
+                (
+                    self.location(failed_at),
+                    ParseErrorKind::InvalidSyntax(near.chars().take(20).collect()),
+                )
+            }
+        };
+
+        ParseError { location, kind }
+    }
+}
+
+impl Iterator for Reader<'_> {
+    type Item = Result<Ptr<Sexp>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (remaining, _) = discard_seperator_many0(self.remaining).ok()?;
+        self.remaining = remaining;
+        if remaining.is_empty() {
+            return None;
+        }
+
+        match parse_sexp_with_spans(remaining, self.source, self.file, &self.spans) {
+            Ok((rest, _)) if rest.len() == remaining.len() => {
+                Some(Err(self.recover(remaining, remaining)))
+            }
+            Ok((rest, sexp)) => {
+                self.remaining = rest;
+                Some(Ok(sexp))
+            }
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                Some(Err(self.recover(remaining, e.input)))
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                Some(Err(self.recover(remaining, &remaining[remaining.len()..])))
+            }
+        }
+    }
+}
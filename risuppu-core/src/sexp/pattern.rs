@@ -1,19 +1,33 @@
-use super::{Ptr, Sexp};
+use super::{symbol::Symbol, Cons, Ptr, Sexp};
+
+/// The identifier following a repeated item of a syntax pattern or template.
+pub const ELLIPSIS: &str = "...";
 
 /// # Pattern
 /// TODO
 #[derive(Clone, Debug)]
 pub enum Pattern {
     Literal(Ptr<Sexp>),
-    Binding(String),
+    Binding(Symbol),
     List(Ptr<Sexp>),
     Nil,
+    /// `_` of a syntax pattern, which matches anything without binding it.
+    Wildcard,
+    /// A variable of a syntax pattern, which binds anything including `()`.
+    Variable(Symbol),
+    /// A list of a syntax pattern, where the item at the position of `repeated` matches
+    /// zero or more items, and the tail matches the rest of a dotted list.
+    Sequence {
+        items: Vec<Pattern>,
+        repeated: Option<(usize, Box<Pattern>)>,
+        tail: Box<Pattern>,
+    },
 }
 
 impl Pattern {
     pub fn new(expr: Ptr<Sexp>) -> Self {
         match expr.as_ref() {
-            Sexp::Identifier(ident) => Pattern::Binding(ident.clone()),
+            Sexp::Identifier(ident) => Pattern::Binding(*ident),
             Sexp::Nil => Pattern::Nil,
             Sexp::Form(_)
                 if !(expr.is_quoted()
@@ -40,6 +54,72 @@ impl Pattern {
     }
 }
 
+impl Pattern {
+    /// The pattern of `syntax-rules`, where the identifiers are variables except `_` and the literals,
+    /// and an item followed by `...` is repeated.
+    ///
+    /// `None` if a list has several ellipses, or an ellipsis follows no item.
+    pub fn syntax(expr: Ptr<Sexp>, literals: &[Symbol]) -> Option<Self> {
+        match expr.as_ref() {
+            Sexp::Identifier(ident) if *ident == "_" => Some(Pattern::Wildcard),
+            Sexp::Identifier(ident) if literals.contains(ident) => Some(Pattern::Literal(expr)),
+            Sexp::Identifier(ident) if *ident == ELLIPSIS => None,
+            Sexp::Identifier(ident) => Some(Pattern::Variable(*ident)),
+            Sexp::Nil => Some(Pattern::Nil),
+            Sexp::Form(_) => {
+                let (elements, rest) = split_list(&expr);
+                let (mut items, mut repeated) = (vec![], None);
+                let mut elements = elements.into_iter().peekable();
+                while let Some(element) = elements.next() {
+                    let pattern = Self::syntax(element, literals)?;
+                    if elements.next_if(is_ellipsis).is_none() {
+                        items.push(pattern);
+                    } else if repeated.is_none() {
+                        repeated = Some((items.len(), Box::new(pattern)));
+                    } else {
+                        return None;
+                    }
+                }
+                let tail = Box::new(Self::syntax(rest, literals)?);
+                Some(Pattern::Sequence { items, repeated, tail })
+            }
+            _ => Some(Pattern::Literal(expr)),
+        }
+    }
+
+    /// The identifiers bound by the pattern, including the ones in the repeated items.
+    pub fn variables(&self) -> Vec<Symbol> {
+        match self {
+            Pattern::Binding(ident) | Pattern::Variable(ident) => vec![*ident],
+            Pattern::List(list) => Sexp::iter(list.clone())
+                .flat_map(|item| Pattern::from(item).variables())
+                .collect(),
+            Pattern::Sequence { items, repeated, tail } => items
+                .iter()
+                .chain(repeated.as_ref().map(|(_, pattern)| pattern.as_ref()))
+                .chain([tail.as_ref()])
+                .flat_map(Pattern::variables)
+                .collect(),
+            Pattern::Literal(_) | Pattern::Nil | Pattern::Wildcard => vec![],
+        }
+    }
+}
+
+fn is_ellipsis(expr: &Ptr<Sexp>) -> bool {
+    matches!(expr.as_ref(), Sexp::Identifier(ident) if *ident == ELLIPSIS)
+}
+
+/// The items of a list, and its tail which is `()` unless the list is dotted.
+fn split_list(list: &Ptr<Sexp>) -> (Vec<Ptr<Sexp>>, Ptr<Sexp>) {
+    let (mut items, mut list) = (vec![], list.clone());
+    while let Sexp::Form(Cons { car, cdr }) = list.as_ref() {
+        items.push(car.clone());
+        let cdr = cdr.clone();
+        list = cdr;
+    }
+    (items, list)
+}
+
 impl From<Ptr<Sexp>> for Pattern {
     fn from(value: Ptr<Sexp>) -> Self {
         Self::new(value)
@@ -63,7 +143,15 @@ impl MatchError {
 type MatchResult = Result<Binding, MatchError>;
 
 pub struct Binding {
-    inner: Vec<(String, Ptr<Sexp>)>,
+    inner: Vec<(Symbol, Ptr<Sexp>)>,
+    repetitions: Vec<Repetition>,
+}
+
+/// The bindings of a repeated item of a syntax pattern, one for each item it matched.
+pub struct Repetition {
+    /// The identifiers bound by the repeated item.
+    pub variables: Vec<Symbol>,
+    pub matches: Vec<Binding>,
 }
 
 impl Binding {
@@ -71,26 +159,44 @@ impl Binding {
     where
         T: IntoIterator,
         <T as std::iter::IntoIterator>::IntoIter: Iterator,
-        <T as std::iter::IntoIterator>::Item: Into<(String, Ptr<Sexp>)>,
+        <T as std::iter::IntoIterator>::Item: Into<(Symbol, Ptr<Sexp>)>,
     {
         Self {
             inner: bindings.into_iter().map(Into::into).collect(),
+            repetitions: vec![],
         }
     }
 
     pub fn empty() -> Self {
-        Self { inner: vec![] }
+        Self {
+            inner: vec![],
+            repetitions: vec![],
+        }
     }
 
-    pub fn add_binding(&mut self, identifier: impl ToString, value: Ptr<Sexp>) {
-        self.inner.push((identifier.to_string(), value))
+    pub fn add_binding(&mut self, identifier: impl Into<Symbol>, value: Ptr<Sexp>) {
+        self.inner.push((identifier.into(), value))
     }
 
     pub fn extend_binding(&mut self, bindings: Self) {
-        self.inner.extend(bindings.inner)
+        self.inner.extend(bindings.inner);
+        self.repetitions.extend(bindings.repetitions);
+    }
+
+    /// The value bound to the identifier outside of the repeated items.
+    pub fn get(&self, identifier: impl Into<Symbol>) -> Option<Ptr<Sexp>> {
+        let identifier = identifier.into();
+        self.inner
+            .iter()
+            .find(|(ident, _)| *ident == identifier)
+            .map(|(_, value)| value.clone())
+    }
+
+    pub fn repetitions(&self) -> &[Repetition] {
+        &self.repetitions
     }
 
-    pub fn get_binding(self) -> Vec<(String, Ptr<Sexp>)> {
+    pub fn get_binding(self) -> Vec<(Symbol, Ptr<Sexp>)> {
         self.inner
     }
 }
@@ -117,7 +223,7 @@ impl Pattern {
             (Pattern::Binding(_), Sexp::Nil) => Err(MatchError::new(self.clone(), expr)),
             (Pattern::Binding(ident), _) => {
                 if !expr.is_nil() {
-                    Ok(Binding::new([(ident.clone(), expr)]))
+                    Ok(Binding::new([(*ident, expr)]))
                 } else {
                     Err(MatchError::new(self.clone(), expr))
                 }
@@ -150,13 +256,48 @@ impl Pattern {
                 }
             }
             (Pattern::Nil, _) => Err(MatchError::new(self.clone(), expr)),
+            (Pattern::Wildcard, _) => Ok(Binding::empty()),
+            (Pattern::Variable(ident), _) => Ok(Binding::new([(*ident, expr)])),
+            (Pattern::Sequence { items, repeated, tail }, _) => {
+                let (elements, rest) = split_list(&expr);
+                if elements.len() < items.len() {
+                    return Err(MatchError::new(self.clone(), expr));
+                }
+
+                let at = repeated.as_ref().map_or(items.len(), |(at, _)| *at);
+                let mut elements = elements.into_iter();
+                let mut bindings = Binding::empty();
+                for (pattern, element) in items[..at].iter().zip(elements.by_ref()) {
+                    bindings.extend_binding(pattern.bind(element)?);
+                }
+
+                let Some((_, pattern)) = repeated else {
+                    // The tail matches the items left.
+                    let rest = elements.rev().fold(rest, |list, element| Sexp::cons(element, list));
+                    bindings.extend_binding(tail.bind(rest)?);
+                    return Ok(bindings);
+                };
+
+                let count = elements.len() - (items.len() - at);
+                let matches = elements
+                    .by_ref()
+                    .take(count)
+                    .map(|element| pattern.bind(element))
+                    .collect::<Result<_, _>>()?;
+                bindings.repetitions.push(Repetition { variables: pattern.variables(), matches });
+                for (pattern, element) in items[at..].iter().zip(elements) {
+                    bindings.extend_binding(pattern.bind(element)?);
+                }
+                bindings.extend_binding(tail.bind(rest)?);
+                Ok(bindings)
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::sexp::{parse::parse_sexp, Sexp};
+    use crate::sexp::{parse::parse_sexp, symbol::Symbol, Sexp};
 
     use super::{MatchError, Pattern};
 
@@ -208,7 +349,7 @@ mod test {
         let expr = parse_sexp("1").unwrap().1;
         let binding = pattern.bind(expr).unwrap();
         let binding = binding.get_binding();
-        assert_eq!(binding[0], ("n".to_string(), Sexp::int(1)))
+        assert_eq!(binding[0], (Symbol::intern("n"), Sexp::int(1)))
     }
 
     #[test]
@@ -217,9 +358,9 @@ mod test {
         let expr = parse_sexp("(1 2 1 2 3)").unwrap().1;
         let binding = pattern.bind(expr).unwrap();
         let binding = binding.get_binding();
-        assert_eq!(binding[0], ("a".to_string(), Sexp::int(1)));
-        assert_eq!(binding[1], ("b".to_string(), Sexp::int(2)));
-        assert_eq!(binding[2], ("c".to_string(), Sexp::int(3)));
+        assert_eq!(binding[0], (Symbol::intern("a"), Sexp::int(1)));
+        assert_eq!(binding[1], (Symbol::intern("b"), Sexp::int(2)));
+        assert_eq!(binding[2], (Symbol::intern("c"), Sexp::int(3)));
     }
 
     #[test]
@@ -228,9 +369,9 @@ mod test {
         let expr = parse_sexp("((1 2) 1 2 3)").unwrap().1;
         let binding = pattern.bind(expr).unwrap();
         let binding = binding.get_binding();
-        assert_eq!(binding[0], ("a".to_string(), Sexp::int(1)));
-        assert_eq!(binding[1], ("b".to_string(), Sexp::int(2)));
-        assert_eq!(binding[2], ("c".to_string(), Sexp::int(3)));
+        assert_eq!(binding[0], (Symbol::intern("a"), Sexp::int(1)));
+        assert_eq!(binding[1], (Symbol::intern("b"), Sexp::int(2)));
+        assert_eq!(binding[2], (Symbol::intern("c"), Sexp::int(3)));
     }
 
     #[test]
@@ -239,8 +380,8 @@ mod test {
         let expr = parse_sexp("(-> read print)").unwrap().1;
         let binding = pattern.bind(expr).unwrap();
         let binding = binding.get_binding();
-        assert_eq!(binding[0], ("m".to_string(), Sexp::read()));
-        assert_eq!(binding[1], ("cont".to_string(), Sexp::print()));
+        assert_eq!(binding[0], (Symbol::intern("m"), Sexp::read()));
+        assert_eq!(binding[1], (Symbol::intern("cont"), Sexp::print()));
     }
 
     #[test]
@@ -256,4 +397,51 @@ mod test {
         let expr = parse_sexp("(1 2)").unwrap().1;
         assert!(!pattern.matches(expr))
     }
+
+    fn syntax(pattern: &str, literals: &[&str]) -> Pattern {
+        let literals = literals.iter().map(|literal| Symbol::intern(literal)).collect::<Vec<_>>();
+        Pattern::syntax(parse_sexp(pattern).unwrap().1, &literals).unwrap()
+    }
+
+    #[test]
+    fn bind_syntax() {
+        let pattern = syntax("(a _ => (b) . c)", &["=>"]);
+        let expr = parse_sexp("(() 1 => (2) 3 4)").unwrap().1;
+        let binding = pattern.bind(expr).unwrap();
+        assert_eq!(binding.get("a"), Some(Sexp::nil()));
+        assert_eq!(binding.get("b"), Some(Sexp::int(2)));
+        assert_eq!(binding.get("c"), parse_sexp("(3 4)").ok().map(|(_, expr)| expr));
+        assert_eq!(binding.get("_"), None);
+
+        assert!(!pattern.matches(parse_sexp("(() 1 -> (2))").unwrap().1));
+        assert!(!pattern.matches(parse_sexp("(() 1 =>)").unwrap().1));
+    }
+
+    #[test]
+    fn bind_ellipsis() {
+        let pattern = syntax("(a (b c ...) ... d)", &[]);
+        let expr = parse_sexp("(1 (2) (3 4 5) 6)").unwrap().1;
+        let binding = pattern.bind(expr).unwrap();
+        assert_eq!(binding.get("a"), Some(Sexp::int(1)));
+        assert_eq!(binding.get("d"), Some(Sexp::int(6)));
+
+        let repetition = &binding.repetitions()[0];
+        assert_eq!(repetition.variables, [Symbol::intern("b"), Symbol::intern("c")]);
+        assert_eq!(repetition.matches.len(), 2);
+        assert_eq!(repetition.matches[1].get("b"), Some(Sexp::int(3)));
+        let inner = &repetition.matches[1].repetitions()[0];
+        assert_eq!(inner.matches.len(), 2);
+        assert_eq!(inner.matches[1].get("c"), Some(Sexp::int(5)));
+
+        let binding = pattern.bind(parse_sexp("(1 6)").unwrap().1).unwrap();
+        assert!(binding.repetitions()[0].matches.is_empty());
+        assert!(!pattern.matches(parse_sexp("(1)").unwrap().1));
+        assert!(!pattern.matches(parse_sexp("(1 2 6)").unwrap().1));
+    }
+
+    #[test]
+    fn invalid_syntax() {
+        assert!(Pattern::syntax(parse_sexp("(a ... b ...)").unwrap().1, &[]).is_none());
+        assert!(Pattern::syntax(parse_sexp("(... a)").unwrap().1, &[]).is_none());
+    }
 }
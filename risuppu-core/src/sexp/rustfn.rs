@@ -2,17 +2,21 @@ use std::{cell::RefCell, fmt::Debug};
 
 use gc::{Finalize, Trace};
 
-use crate::semantic::Env;
+use crate::semantic::{Env, EvalError, EvalResult};
 
 use super::{Ptr, Sexp};
 
-type InnerRustFnMut = Box<RefCell<dyn FnMut(Ptr<Sexp>, &mut Env) -> Ptr<Sexp> + 'static>>;
-type InnerRustFn = Box<RefCell<dyn Fn(Ptr<Sexp>, &mut Env) -> Ptr<Sexp> + 'static>>;
+type InnerRustFnMut = Box<RefCell<dyn FnMut(Ptr<Sexp>, &mut Env) -> EvalResult + 'static>>;
+type InnerRustFn = Box<RefCell<dyn Fn(Ptr<Sexp>, &mut Env) -> EvalResult + 'static>>;
 
 #[derive(Finalize)]
 pub struct RustFn {
     inner: InnerRustFnMut,
     preprocess: Option<InnerRustFn>,
+    // Whether the evaluator evaluates the arguments before applying them.
+    eval_args: bool,
+    // Whether the function only expands the arguments into an expression.
+    expander: bool,
 }
 
 impl RustFn {
@@ -20,10 +24,12 @@ impl RustFn {
     /// Don't capture `Gc` value in the closure, which will escape from the gc management.
     /// Don't recurse in the function body.
     /// Quote the ret-value if it might be a list and this function is not a macro.
-    pub unsafe fn new(f: impl FnMut(Ptr<Sexp>, &mut Env) -> Ptr<Sexp> + 'static) -> Self {
+    pub unsafe fn new(f: impl FnMut(Ptr<Sexp>, &mut Env) -> EvalResult + 'static) -> Self {
         Self {
             inner: Box::new(RefCell::new(f)),
             preprocess: None,
+            eval_args: false,
+            expander: false,
         }
     }
 
@@ -32,24 +38,94 @@ impl RustFn {
     /// Don't recurse in f's body.
     /// Quote the ret-value if it might be a list and this function is not a macro.
     pub unsafe fn new_with_preprocess(
-        f: impl FnMut(Ptr<Sexp>, &mut Env) -> Ptr<Sexp> + 'static,
-        p: impl Fn(Ptr<Sexp>, &mut Env) -> Ptr<Sexp> + 'static,
+        f: impl FnMut(Ptr<Sexp>, &mut Env) -> EvalResult + 'static,
+        p: impl Fn(Ptr<Sexp>, &mut Env) -> EvalResult + 'static,
     ) -> Self {
         Self {
             inner: Box::new(RefCell::new(f)),
             preprocess: Some(Box::new(RefCell::new(p))),
+            eval_args: false,
+            expander: false,
         }
     }
 
-    pub fn call(&self, arg: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-        // BUG: Recursion will cause `BorrowMut: alread borrowed` error.
-        let arg = if let Some(preprocess) = self.preprocess.as_ref() {
+    /// Like [`RustFn::new`], but the function is applied to the evaluated arguments.
+    ///
+    /// The arguments are evaluated by the evaluator on its heap stack,
+    /// unlike a preprocess evaluating them, which recurses on the native stack.
+    ///
+    /// # Safety
+    /// Don't capture `Gc` value in the closure, which will escape from the gc management.
+    /// Don't recurse in the function body.
+    /// Quote the ret-value if it might be a list.
+    pub unsafe fn new_with_evaluated_args(f: impl FnMut(Ptr<Sexp>, &mut Env) -> EvalResult + 'static) -> Self {
+        Self {
+            inner: Box::new(RefCell::new(f)),
+            preprocess: None,
+            eval_args: true,
+            expander: false,
+        }
+    }
+
+    /// Like [`RustFn::new`], but the function is a syntax like `let`,
+    /// which returns the expansion of the arguments without looking at the environment.
+    ///
+    /// The calls of an expander can be expanded once before the code is evaluated.
+    ///
+    /// # Safety
+    /// Don't capture `Gc` value in the closure, which will escape from the gc management.
+    /// Don't recurse in the function body.
+    pub unsafe fn new_expander(f: impl FnMut(Ptr<Sexp>, &mut Env) -> EvalResult + 'static) -> Self {
+        Self {
+            inner: Box::new(RefCell::new(f)),
+            preprocess: None,
+            eval_args: false,
+            expander: true,
+        }
+    }
+
+    /// Whether the function is applied to the evaluated arguments.
+    pub fn evaluates_args(&self) -> bool {
+        self.eval_args
+    }
+
+    /// Whether the function only expands the arguments into an expression.
+    pub fn is_expander(&self) -> bool {
+        self.expander
+    }
+
+    /// Whether the arguments are preprocessed before they are applied to the function.
+    pub fn has_preprocess(&self) -> bool {
+        self.preprocess.is_some()
+    }
+
+    pub fn call(&self, arg: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+        let arg = self.preprocess(arg, env)?;
+        self.apply(arg, env)
+    }
+
+    /// Preprocess the arguments, e.g. evaluating them, before they are applied to the function.
+    pub fn preprocess(&self, arg: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+        if let Some(preprocess) = self.preprocess.as_ref() {
             (**preprocess).borrow()(arg, env)
+        } else if self.eval_args {
+            let args = Sexp::iter(arg)
+                .map(|arg| env.try_evaluate(arg))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Sexp::from_vec(args))
         } else {
-            arg
-        };
+            Ok(arg)
+        }
+    }
 
-        self.inner.borrow_mut()(arg, env)
+    /// Apply the preprocessed arguments to the function.
+    pub fn apply(&self, arg: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+        // The function body can't be borrowed again if it recurses into itself.
+        let mut f = self
+            .inner
+            .try_borrow_mut()
+            .map_err(|_| EvalError::ReentrantCall)?;
+        f(arg, env)
     }
 }
 
@@ -0,0 +1,234 @@
+use std::{
+    cell::{RefCell, RefMut},
+    collections::HashMap,
+    fmt::Display,
+    rc::{Rc, Weak},
+};
+
+use super::{Ptr, Sexp};
+
+/// A byte range in a source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A human-readable position in a source file. Lines and columns start from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileId(usize);
+
+struct SourceFile {
+    name: String,
+    line_starts: Vec<usize>,
+    // The offsets of the characters longer than a byte, with the number of their bytes after the first,
+    // which the columns don't count.
+    wide_chars: Vec<(usize, usize)>,
+}
+
+impl SourceFile {
+    fn new(name: String, text: &str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let wide_chars = text
+            .char_indices()
+            .filter(|(_, c)| c.len_utf8() > 1)
+            .map(|(i, c)| (i, c.len_utf8() - 1))
+            .collect();
+        Self {
+            name,
+            line_starts,
+            wide_chars,
+        }
+    }
+
+    fn location(&self, offset: usize) -> Location {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let line_start = self.line_starts[line];
+        let first = self.wide_chars.partition_point(|&(i, _)| i < line_start);
+        let last = self.wide_chars.partition_point(|&(i, _)| i < offset);
+        let extra_bytes = self.wide_chars[first..last].iter().map(|&(_, extra)| extra).sum::<usize>();
+        Location {
+            file: self.name.clone(),
+            line: line + 1,
+            column: offset - line_start - extra_bytes + 1,
+        }
+    }
+}
+
+#[derive(Default)]
+struct SpanTableInner {
+    files: Vec<SourceFile>,
+    // The nodes aren't kept alive. The entry of a node is removed when the node is collected,
+    // before its address can be reused.
+    spans: HashMap<*const Sexp, (FileId, Span)>,
+}
+
+#[derive(Default)]
+struct SharedTable {
+    inner: RefCell<SpanTableInner>,
+    // The nodes collected while the table was borrowed, whose entries are removed at the next borrow.
+    forgotten: RefCell<Vec<*const Sexp>>,
+}
+
+thread_local! {
+    /// The tables of the thread, from which the collected nodes are removed.
+    static TABLES: RefCell<Vec<Weak<SharedTable>>> = const { RefCell::new(vec![]) };
+}
+
+/// Remove the span of a node which is being collected from the tables.
+pub(crate) fn forget(node: *const Sexp) {
+    // The tables may be gone when the remaining nodes are collected at the exit of the thread.
+    let _ = TABLES.try_with(|tables| {
+        for table in tables.borrow().iter().filter_map(Weak::upgrade) {
+            match table.inner.try_borrow_mut() {
+                Ok(mut inner) if !inner.spans.is_empty() => {
+                    inner.spans.remove(&node);
+                }
+                Ok(_) => {}
+                Err(_) => table.forgotten.borrow_mut().push(node),
+            }
+        }
+    });
+}
+
+/// Spans of parsed expressions, keyed by the identity of the nodes.
+///
+/// The table is shared between the clones of it.
+#[derive(Clone)]
+pub struct SpanTable {
+    shared: Rc<SharedTable>,
+}
+
+impl Default for SpanTable {
+    fn default() -> Self {
+        let shared = Rc::new(SharedTable::default());
+        TABLES.with(|tables| {
+            let mut tables = tables.borrow_mut();
+            tables.retain(|table| table.strong_count() > 0);
+            tables.push(Rc::downgrade(&shared));
+        });
+        Self { shared }
+    }
+}
+
+impl SpanTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Borrow the table, removing the entries of the nodes collected while it was borrowed.
+    fn inner(&self) -> RefMut<'_, SpanTableInner> {
+        let mut inner = self.shared.inner.borrow_mut();
+        for node in self.shared.forgotten.borrow_mut().drain(..) {
+            inner.spans.remove(&node);
+        }
+        inner
+    }
+
+    pub fn add_file(&self, name: impl ToString, text: &str) -> FileId {
+        let mut inner = self.inner();
+        inner.files.push(SourceFile::new(name.to_string(), text));
+        FileId(inner.files.len() - 1)
+    }
+
+    pub fn insert(&self, node: Ptr<Sexp>, file: FileId, span: Span) {
+        let key = &*node as *const Sexp;
+        self.inner().spans.insert(key, (file, span));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner().spans.is_empty()
+    }
+
+    pub fn span_of(&self, node: &Ptr<Sexp>) -> Option<(FileId, Span)> {
+        let key = &**node as *const Sexp;
+        self.inner().spans.get(&key).copied()
+    }
+
+    pub fn location(&self, file: FileId, offset: usize) -> Location {
+        self.inner().files[file.0].location(offset)
+    }
+
+    pub fn location_of(&self, node: &Ptr<Sexp>) -> Option<Location> {
+        self.span_of(node)
+            .map(|(file, span)| self.location(file, span.start))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sexp::Sexp;
+
+    use super::{Span, SpanTable};
+
+    #[test]
+    fn line_and_column() {
+        let spans = SpanTable::new();
+        let file = spans.add_file("test.risp", "(a\n  b)\n(c)");
+        assert_eq!(spans.location(file, 0).to_string(), "test.risp:1:1");
+        assert_eq!(spans.location(file, 5).to_string(), "test.risp:2:3");
+        assert_eq!(spans.location(file, 8).to_string(), "test.risp:3:1");
+
+        // The columns count the characters rather than the bytes.
+        let file = spans.add_file("wide.risp", "(λ é\n  é b)");
+        assert_eq!(spans.location(file, 4).to_string(), "wide.risp:1:4");
+        assert_eq!(spans.location(file, 12).to_string(), "wide.risp:2:5");
+    }
+
+    #[test]
+    fn forget_collected_nodes() {
+        let spans = SpanTable::new();
+        let file = spans.add_file("test.risp", "(1)");
+        let (list, item) = (Sexp::from_vec([Sexp::int(1)]), Sexp::int(2));
+        spans.insert(list.clone(), file, Span { start: 0, end: 3 });
+        spans.insert(item.clone(), file, Span { start: 1, end: 2 });
+
+        drop(list);
+        gc::force_collect();
+        assert_eq!(spans.span_of(&item), Some((file, Span { start: 1, end: 2 })));
+        drop(item);
+        gc::force_collect();
+        assert!(spans.is_empty());
+    }
+
+    #[test]
+    fn forget_nodes_collected_while_borrowed() {
+        let spans = SpanTable::new();
+        let file = spans.add_file("test.risp", "1");
+        let item = Sexp::int(1);
+        spans.insert(item.clone(), file, Span { start: 0, end: 1 });
+
+        let borrowed = spans.shared.inner.borrow();
+        drop(item);
+        gc::force_collect();
+        drop(borrowed);
+        assert!(spans.is_empty());
+    }
+
+    #[test]
+    fn keyed_by_identity() {
+        let spans = SpanTable::new();
+        let file = spans.add_file("test.risp", "1 1");
+        let (a, b) = (Sexp::int(1), Sexp::int(1));
+        spans.insert(a.clone(), file, Span { start: 0, end: 1 });
+        spans.insert(b.clone(), file, Span { start: 2, end: 3 });
+        assert_eq!(spans.span_of(&a), Some((file, Span { start: 0, end: 1 })));
+        assert_eq!(spans.span_of(&b), Some((file, Span { start: 2, end: 3 })));
+        assert_eq!(spans.span_of(&Sexp::int(1)), None);
+    }
+}
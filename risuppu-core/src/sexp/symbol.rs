@@ -0,0 +1,155 @@
+use std::{cell::RefCell, collections::HashMap, fmt::Display, marker::PhantomData, ops::Deref};
+
+use gc::{unsafe_empty_trace, Finalize, Trace};
+
+/// The names interned so far, which live as long as the program.
+#[derive(Default)]
+struct Interner {
+    symbols: HashMap<&'static str, Symbol>,
+    names: Vec<&'static str>,
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::default());
+}
+
+/// An interned name, which is compared and hashed by its id.
+///
+/// The names are interned per thread, so the symbols can't be sent to another thread.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol {
+    id: u32,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Symbol {
+    /// The symbol of the name, interning the name if it's new.
+    pub fn intern(name: &str) -> Self {
+        INTERNER.with(|interner| {
+            let mut interner = interner.borrow_mut();
+            if let Some(symbol) = interner.symbols.get(name) {
+                return *symbol;
+            }
+
+            let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+            let symbol = Symbol {
+                id: interner.names.len() as u32,
+                _not_send: PhantomData,
+            };
+            interner.names.push(name);
+            interner.symbols.insert(name, symbol);
+            symbol
+        })
+    }
+
+    /// A new symbol named after the prefix and a number, which differs from every other symbol,
+    /// including an interned one with the same name.
+    ///
+    /// Like the interned names, the name is never freed.
+    pub fn fresh(prefix: &str) -> Self {
+        INTERNER.with(|interner| {
+            let mut interner = interner.borrow_mut();
+            let id = interner.names.len() as u32;
+            let name: &'static str = Box::leak(format!("{prefix}{id}").into_boxed_str());
+            interner.names.push(name);
+            Symbol {
+                id,
+                _not_send: PhantomData,
+            }
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        INTERNER.with(|interner| interner.borrow().names[self.id as usize])
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Symbol {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(value: &str) -> Self {
+        Self::intern(value)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(value: String) -> Self {
+        Self::intern(&value)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(value: &String) -> Self {
+        Self::intern(value)
+    }
+}
+
+impl From<&Symbol> for Symbol {
+    fn from(value: &Symbol) -> Self {
+        *value
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl Finalize for Symbol {}
+
+unsafe impl Trace for Symbol {
+    unsafe_empty_trace!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::Symbol;
+
+    #[test]
+    fn intern() {
+        let a = Symbol::intern("a");
+        assert_eq!(a, Symbol::intern("a"));
+        assert_ne!(a, Symbol::intern("b"));
+        assert_eq!(a.as_str(), "a");
+        assert_eq!(a.to_string(), "a");
+    }
+
+    #[test]
+    fn fresh() {
+        let a = Symbol::fresh("a");
+        assert_ne!(a, Symbol::fresh("a"));
+        assert_ne!(a, Symbol::intern(a.as_str()));
+        assert!(a.starts_with('a'));
+    }
+}
@@ -15,4 +15,20 @@ pub struct Arg {
     /// Configuration file
     #[arg(short, long, env = "RISP_CONF")]
     pub configuration_file: Option<PathBuf>,
+
+    /// Maximum number of evaluation steps of the input files and the REPL
+    #[arg(long)]
+    pub fuel: Option<u64>,
+
+    /// Print the evaluation steps
+    #[arg(long, default_value_t = false)]
+    pub trace: bool,
+
+    /// Raise errors instead of currying when functions get a wrong number of arguments
+    #[arg(long, default_value_t = false)]
+    pub strict_arity: bool,
+
+    /// Expand the macros each time the code calling them is evaluated, instead of once before it
+    #[arg(long, default_value_t = false)]
+    pub lazy_expansion: bool,
 }
@@ -2,8 +2,9 @@ use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
-use risuppu::semantic::Env;
-use risuppu::sexp::parse::parse_sexp;
+use risuppu::semantic::{Env, EvalError, StdoutTracer};
+use risuppu::sexp::parse::Reader;
+use risuppu::sexp::span::SpanTable;
 
 use risuppu_std::base::load_base;
 #[cfg(feature = "arithmetic")]
@@ -21,21 +22,24 @@ use arg::Arg;
 
 use clap::Parser;
 
-fn evaluate_file(file: &Path, env: &mut Env) -> std::io::Result<()> {
-    let mut file = File::open(file)?;
+fn evaluate_file(file_name: &Path, env: &mut Env) -> std::io::Result<()> {
+    let mut file = File::open(file_name)?;
     let mut content = String::new();
     file.read_to_string(&mut content)?;
 
-    let mut remaining_content = content.as_str();
-    while !remaining_content.is_empty() {
-        match parse_sexp(remaining_content) {
-            Ok((unparsed, sexp)) => {
-                remaining_content = unparsed;
-                env.evaluate(sexp);
+    let spans = env.spans().clone();
+    // Nothing is evaluated if the file has syntax errors, like a required module.
+    match Reader::new(file_name.to_string_lossy(), content.as_str(), &spans).read_all() {
+        Ok(exprs) => {
+            for sexp in exprs {
+                if let Err(e) = env.try_evaluate(sexp) {
+                    report_error(e, env);
+                }
             }
-            Err(e) => {
-                println!("{e}");
-                break;
+        }
+        Err(errors) => {
+            for e in errors {
+                eprintln!("{e}");
             }
         }
     }
@@ -43,6 +47,15 @@ fn evaluate_file(file: &Path, env: &mut Env) -> std::io::Result<()> {
     Ok(())
 }
 
+fn report_error(e: EvalError, env: &mut Env) {
+    println!("Error: {e}");
+    if let Some(backtrace) = env.take_backtrace() {
+        if !backtrace.frames.is_empty() {
+            print!("{backtrace}");
+        }
+    }
+}
+
 fn main() {
     let arg: Arg = Arg::parse();
 
@@ -61,6 +74,17 @@ fn main() {
         }
     }
 
+    if arg.trace {
+        env.add_observer(StdoutTracer::new());
+    }
+
+    env.set_strict_arity(arg.strict_arity);
+    env.set_eager_expansion(!arg.lazy_expansion);
+
+    if let Some(fuel) = arg.fuel {
+        env.set_fuel(fuel);
+    }
+
     for file in arg.files {
         if let Err(e) = evaluate_file(&file, &mut env) {
             println!("Error when evaluating {}: {}", file.to_string_lossy(), e);
@@ -78,14 +102,21 @@ fn main() {
         let readline = rl.readline("Risuppu >> ");
         match readline {
             Ok(line) => {
-                let parse_result = parse_sexp(line.as_str());
-                match parse_result {
-                    Ok((_, sexp)) => {
-                        let eval = env.evaluate(sexp);
-                        println!("> {eval}");
+                // Don't keep the spans of the lines.
+                let spans = SpanTable::new();
+                match Reader::new("<repl>", line.as_str(), &spans).read_all() {
+                    Ok(exprs) => {
+                        for sexp in exprs {
+                            match env.try_evaluate(sexp) {
+                                Ok(eval) => println!("> {eval}"),
+                                Err(e) => report_error(e, &mut env),
+                            }
+                        }
                     }
-                    Err(e) => {
-                        println!("{e}");
+                    Err(errors) => {
+                        for e in errors {
+                            println!("{e}");
+                        }
                     }
                 }
             }
@@ -0,0 +1,60 @@
+//! Compare the bytecode VM with the tree walker on a few loops.
+//!
+//! Run with `cargo bench -p risuppu-std --bench engine`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use risuppu::{
+    semantic::{Engine, Env},
+    sexp::parse::parse_sexp,
+};
+use risuppu_std::{arithmetic::load_arithmetic, base::load_base};
+
+const RUNS: u32 = 10;
+
+const CASES: &[(&str, &str)] = &[
+    (
+        "named let",
+        "(let loop ((n 10000) (acc 0)) (if (eq n 0) acc (loop (__builtin_- n 1) (__builtin_+ acc 1))))",
+    ),
+    (
+        "closures",
+        "((lambda (adder) (letrec ((loop (lambda (n acc) (if (eq n 0) acc (loop (__builtin_- n 1) ((adder n) acc)))))) \
+           (loop 10000 0))) (lambda (n) (lambda (acc) (__builtin_+ acc n))))",
+    ),
+    (
+        "fib",
+        "(letrec ((fib (lambda (n) (if (__builtin_less n 2) n (__builtin_+ (fib (__builtin_- n 1)) (fib (__builtin_- n 2))))))) \
+           (fib 18))",
+    ),
+];
+
+/// The average time of evaluating `source` once on the engine.
+fn bench(source: &str, engine: Engine) -> Duration {
+    let expr = parse_sexp(source).unwrap().1;
+    let mut env = Env::new();
+    env.set_engine(engine);
+    load_base(&mut env);
+    load_arithmetic(&mut env);
+
+    // Warm up.
+    env.evaluate(expr.clone());
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(env.evaluate(black_box(expr.clone())));
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    for (name, source) in CASES {
+        let walker_time = bench(source, Engine::TreeWalker);
+        let vm_time = bench(source, Engine::Vm);
+
+        println!("{name} (tree walker): {walker_time:?}");
+        println!("{name} (vm): {vm_time:?}");
+        println!("{name} speedup: {:.2}x", walker_time.as_secs_f64() / vm_time.as_secs_f64());
+    }
+}
@@ -0,0 +1,44 @@
+//! Measure the cost of calling a global function at increasing recursion depths.
+//!
+//! Run with `cargo bench -p risuppu-std --bench globals`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use risuppu::{
+    semantic::{Engine, Env},
+    sexp::parse::parse_sexp,
+};
+use risuppu_std::{arithmetic::load_arithmetic, base::load_base};
+
+const RUNS: u32 = 3;
+
+const DEPTHS: &[u32] = &[10_000, 50_000, 200_000];
+
+const COUNT: &str = "(define (count n) (if (eq n 0) 0 (__builtin_+ (count (__builtin_- n 1)) 1)))";
+
+/// The average time of a call of the global `count` at the depth on the engine.
+fn bench(depth: u32, engine: Engine) -> Duration {
+    let expr = parse_sexp(&format!("(count {depth})")).unwrap().1;
+    let mut env = Env::new();
+    env.set_engine(engine);
+    load_base(&mut env);
+    load_arithmetic(&mut env);
+    env.evaluate(parse_sexp(COUNT).unwrap().1);
+
+    // Warm up.
+    env.evaluate(expr.clone());
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(env.evaluate(black_box(expr.clone())));
+    }
+    start.elapsed() / RUNS / depth
+}
+
+fn main() {
+    for &depth in DEPTHS {
+        println!("depth {depth} (tree walker): {:?} per call", bench(depth, Engine::TreeWalker));
+        println!("depth {depth} (vm): {:?} per call", bench(depth, Engine::Vm));
+    }
+}
@@ -0,0 +1,47 @@
+//! Compare the iteration cost of named `let` with the Y-combinator expansion it used to have.
+//!
+//! Run with `cargo bench -p risuppu-std --bench named_let`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use risuppu::{semantic::Env, sexp::parse::parse_sexp};
+use risuppu_std::{arithmetic::load_arithmetic, base::load_base};
+
+const ITERATIONS: usize = 10_000;
+const RUNS: u32 = 10;
+
+const LOOP_BODY: &str = "(if (eq n 0) acc (loop (__builtin_- n 1) (__builtin_+ acc 1)))";
+
+/// The average time of evaluating `source` once.
+fn bench(source: &str) -> Duration {
+    let expr = parse_sexp(source).unwrap().1;
+    let mut env = Env::new();
+    load_base(&mut env);
+    load_arithmetic(&mut env);
+
+    // Warm up.
+    env.evaluate(expr.clone());
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(env.evaluate(black_box(expr.clone())));
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let named_let = format!("(let loop ((n {ITERATIONS}) (acc 0)) {LOOP_BODY})");
+    let y_combinator = format!(
+        "(((lambda (f) ((lambda (f) (f f)) (lambda (g) (lambda (n acc) ((f (g g)) n acc))))) \
+          (lambda (loop n acc) {LOOP_BODY})) {ITERATIONS} 0)"
+    );
+
+    let letrec_time = bench(&named_let);
+    let y_time = bench(&y_combinator);
+
+    let per_iteration = |time: Duration| time / ITERATIONS as u32;
+    println!("named let (letrec): {:?} per iteration", per_iteration(letrec_time));
+    println!("named let (Y combinator): {:?} per iteration", per_iteration(y_time));
+    println!("speedup: {:.2}x", y_time.as_secs_f64() / letrec_time.as_secs_f64());
+}
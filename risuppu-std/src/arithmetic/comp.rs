@@ -1,78 +1,62 @@
 use risuppu::{
-    semantic::Env,
+    semantic::{Env, EvalResult},
     sexp::{Ptr, Sexp},
 };
 
-pub fn greater(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let init = env.evaluate(args.car());
+pub fn greater(args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let init = env.try_evaluate(args.car())?;
+    let init = super::number(&init)?;
     let ms = args.cdr();
 
-    if let Sexp::I32(init) = init.as_ref() {
-        let ans = Sexp::iter(ms)
-            .map(|a| match a.as_ref() {
-                Sexp::I32(a) => *a,
-                _ => 1,
-            })
-            .all(|n| *init > n);
+    let ans = Sexp::iter(ms)
+        .map(|a| super::number(&a))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .all(|n| init > n);
 
-        Sexp::bool(ans)
-    } else {
-        Sexp::bool(false)
-    }
+    Ok(Sexp::bool(ans))
 }
 
-pub fn less(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let init = env.evaluate(args.car());
+pub fn less(args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let init = env.try_evaluate(args.car())?;
+    let init = super::number(&init)?;
     let ms = args.cdr();
 
-    if let Sexp::I32(init) = init.as_ref() {
-        let ans = Sexp::iter(ms)
-            .map(|a| match a.as_ref() {
-                Sexp::I32(a) => *a,
-                _ => 1,
-            })
-            .all(|n| *init < n);
+    let ans = Sexp::iter(ms)
+        .map(|a| super::number(&a))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .all(|n| init < n);
 
-        Sexp::bool(ans)
-    } else {
-        Sexp::bool(false)
-    }
+    Ok(Sexp::bool(ans))
 }
 
-pub fn ge(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let init = env.evaluate(args.car());
+pub fn ge(args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let init = env.try_evaluate(args.car())?;
+    let init = super::number(&init)?;
     let ms = args.cdr();
 
-    if let Sexp::I32(init) = init.as_ref() {
-        let ans = Sexp::iter(ms)
-            .map(|a| match a.as_ref() {
-                Sexp::I32(a) => *a,
-                _ => 1,
-            })
-            .all(|n| *init >= n);
+    let ans = Sexp::iter(ms)
+        .map(|a| super::number(&a))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .all(|n| init >= n);
 
-        Sexp::bool(ans)
-    } else {
-        Sexp::bool(false)
-    }
+    Ok(Sexp::bool(ans))
 }
 
-pub fn le(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let init = env.evaluate(args.car());
+pub fn le(args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let init = env.try_evaluate(args.car())?;
+    let init = super::number(&init)?;
     let ms = args.cdr();
 
-    if let Sexp::I32(init) = init.as_ref() {
-        let ans = Sexp::iter(ms)
-            .map(|a| match a.as_ref() {
-                Sexp::I32(a) => *a,
-                _ => 1,
-            })
-            .all(|n| *init <= n);
+    let ans = Sexp::iter(ms)
+        .map(|a| super::number(&a))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .all(|n| init <= n);
 
-        Sexp::bool(ans)
-    } else {
-        Sexp::bool(false)
-    }
+    Ok(Sexp::bool(ans))
 }
 
 // TODO: Test
@@ -1,45 +1,40 @@
 use std::iter;
 
 use risuppu::{
-    semantic::Env,
+    semantic::{Env, EvalResult},
     sexp::{Ptr, Sexp},
 };
 
-pub fn minus(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let init = env.evaluate(args.car());
+pub fn minus(args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let init = env.try_evaluate(args.car())?;
+    let init = super::number(&init)?;
     let mut ms = args.cdr();
 
-    if let Sexp::I32(init) = init.as_ref() {
-        let ans = iter::from_fn(|| {
-            if ms.is_nil() {
-                None
-            } else {
-                let car = env.evaluate(ms.car());
-                ms = ms.cdr();
-                Some(car)
-            }
-        })
-        .map(|a| match a.as_ref() {
-            Sexp::I32(a) => *a,
-            _ => 0,
-        })
-        .fold(*init, |pre, n| pre - n);
-
-        Sexp::int(ans)
-    } else {
-        Sexp::nil()
-    }
+    let ans = iter::from_fn(|| {
+        if ms.is_nil() {
+            None
+        } else {
+            let car = env.try_evaluate(ms.car());
+            ms = ms.cdr();
+            Some(car)
+        }
+    })
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .try_fold(init, |pre, a| super::checked(pre.checked_sub(super::number(&a)?)))?;
+
+    Ok(Sexp::int(ans))
 }
 
 #[cfg(test)]
 mod test {
-    use risuppu::{sexp::Sexp, semantic::Env};
+    use risuppu::{sexp::Sexp, semantic::{Env, EvalError}};
 
     #[test]
     fn minus() {
         let numbers = Sexp::from_vec([Sexp::int(1), Sexp::int(2), Sexp::int(3)]);
         let mut env = Env::new();
-        let sum = super::minus(numbers, &mut env);
+        let sum = super::minus(numbers, &mut env).unwrap();
         assert_eq!(sum, Sexp::int(-4));
     }
 
@@ -48,6 +43,14 @@ mod test {
         let list = Sexp::from_vec([Sexp::nil()]);
         let mut env = Env::new();
         let sum = super::minus(list, &mut env);
-        assert_eq!(sum, Sexp::nil());
+        assert_eq!(sum, Err(EvalError::raise("type-error", "Expected a number, found ()")));
+    }
+
+    #[test]
+    fn minus_overflow() {
+        let numbers = Sexp::from_vec([Sexp::int(i32::MIN), Sexp::int(1)]);
+        let mut env = Env::new();
+        let res = super::minus(numbers, &mut env);
+        assert_eq!(res, Err(EvalError::raise("arithmetic-overflow", "Integer overflow")));
     }
 }
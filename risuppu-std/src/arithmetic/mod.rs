@@ -1,4 +1,7 @@
-use crate::pre_function;
+use risuppu::{
+    semantic::EvalError,
+    sexp::{Ptr, Sexp},
+};
 
 mod plus;
 mod minus;
@@ -9,20 +12,41 @@ mod comp;
 
 super::std_library!(
     arithmetic,
-    (plus::plus, "__builtin_+", pre_function),
-    (minus::minus, "__builtin_-", pre_function),
-    (multiply::multiply, "__builtin_*", pre_function),
-    (divide::divide, "__builtin_/", pre_function),
-    (modular::modular, "__builtin_mod", pre_function),
-    (comp::less, "__builtin_less", pre_function),
-    (comp::greater, "__builtin_greater", pre_function),
-    (comp::le, "__builtin_le", pre_function),
-    (comp::ge, "__builtin_ge", pre_function)
+    (plus::plus, "__builtin_+", eval_args),
+    (minus::minus, "__builtin_-", eval_args),
+    (multiply::multiply, "__builtin_*", eval_args),
+    (divide::divide, "__builtin_/", eval_args),
+    (modular::modular, "__builtin_mod", eval_args),
+    (comp::less, "__builtin_less", eval_args),
+    (comp::greater, "__builtin_greater", eval_args),
+    (comp::le, "__builtin_le", eval_args),
+    (comp::ge, "__builtin_ge", eval_args)
 );
 
+/// Get the number, or raise a `type-error`.
+fn number(arg: &Ptr<Sexp>) -> Result<i32, EvalError> {
+    match arg.as_ref() {
+        Sexp::I32(n) => Ok(*n),
+        _ => Err(EvalError::raise("type-error", format!("Expected a number, found {arg}"))),
+    }
+}
+
+/// Get the non-zero divisor, or raise a `division-by-zero`.
+fn divisor(arg: &Ptr<Sexp>) -> Result<i32, EvalError> {
+    match number(arg)? {
+        0 => Err(EvalError::raise("division-by-zero", "Division by zero")),
+        n => Ok(n),
+    }
+}
+
+/// Get the result of a checked operation, or raise an `arithmetic-overflow`.
+fn checked(result: Option<i32>) -> Result<i32, EvalError> {
+    result.ok_or_else(|| EvalError::raise("arithmetic-overflow", "Integer overflow"))
+}
+
 #[cfg(test)]
 mod test {
-    use risuppu::{semantic::Env, sexp::{parse::parse_sexp, Sexp}};
+    use risuppu::{semantic::{Env, EvalError}, sexp::{parse::parse_sexp, Sexp}};
 
     #[test]
     fn fact() {
@@ -62,4 +86,58 @@ mod test {
 
         assert_eq!(res, Sexp::int(6));
     }
+
+    #[test]
+    fn deep_recursion() {
+        let mut env = Env::new();
+        super::load_arithmetic(&mut env);
+
+        env.evaluate(
+            parse_sexp("(define sum (lambda (n) (if (eq n 0) 0 (__builtin_+ n (sum (__builtin_- n 1))))))")
+                .unwrap()
+                .1,
+        );
+
+        assert_eq!(
+            env.evaluate(parse_sexp("(sum 10000)").unwrap().1),
+            Sexp::int(50005000)
+        );
+    }
+
+    #[test]
+    fn deeper_than_native_stack() {
+        let mut env = Env::new();
+        super::load_arithmetic(&mut env);
+
+        env.evaluate(
+            parse_sexp("(define count (lambda (n) (if (eq n 0) 0 (__builtin_+ 1 (count (__builtin_- n 1))))))")
+                .unwrap()
+                .1,
+        );
+        assert_eq!(
+            env.evaluate(parse_sexp("(count 30000)").unwrap().1),
+            Sexp::int(30000)
+        );
+
+        env.evaluate(parse_sexp("(define f (lambda (n) (if (eq n 0) 0 (__builtin_+ 1 (g (__builtin_- n 1))))))").unwrap().1);
+        env.evaluate(parse_sexp("(define g (lambda (n) (if (eq n 0) 0 (__builtin_+ 2 (f (__builtin_- n 1))))))").unwrap().1);
+        assert_eq!(
+            env.evaluate(parse_sexp("(f 30000)").unwrap().1),
+            Sexp::int(45000)
+        );
+    }
+
+    #[test]
+    fn propagate_error() {
+        let mut env = Env::new();
+        super::load_arithmetic(&mut env);
+
+        let res = env.try_evaluate(
+            parse_sexp("(__builtin_+ 1 (__builtin_* 2 undefined))")
+                .unwrap()
+                .1,
+        );
+
+        assert_eq!(res, Err(EvalError::UnboundIdentifier("undefined".to_string())));
+    }
 }
@@ -1,35 +1,35 @@
 use risuppu::{
-    semantic::Env,
+    semantic::{Env, EvalResult},
     sexp::{Ptr, Sexp},
 };
 
-pub fn modular(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let init = env.evaluate(args.car());
+pub fn modular(args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let init = env.try_evaluate(args.car())?;
+    let init = super::number(&init)?;
     let ms = args.cdr();
 
-    if let Sexp::I32(init) = init.as_ref() {
-        let ans = Sexp::iter(ms)
-            .filter_map(|a| match a.as_ref() {
-                Sexp::I32(a) => Some(*a),
-                _ => None,
-            })
-            .fold(*init, |pre, n| pre % n);
+    let ans = Sexp::iter(ms).try_fold(init, |pre, a| super::checked(pre.checked_rem(super::divisor(&a)?)))?;
 
-        Sexp::int(ans)
-    } else {
-        Sexp::nil()
-    }
+    Ok(Sexp::int(ans))
 }
 
 #[cfg(test)]
 mod test {
-    use risuppu::{semantic::Env, sexp::Sexp};
+    use risuppu::{semantic::{Env, EvalError}, sexp::Sexp};
 
     #[test]
     fn modular() {
         let numbers = Sexp::from_vec([Sexp::int(6), Sexp::int(2)]);
         let mut env = Env::new();
-        let sum = super::modular(numbers, &mut env);
+        let sum = super::modular(numbers, &mut env).unwrap();
         assert_eq!(sum, Sexp::int(0));
     }
+
+    #[test]
+    fn modular_overflow() {
+        let numbers = Sexp::from_vec([Sexp::int(i32::MIN), Sexp::int(-1)]);
+        let mut env = Env::new();
+        let res = super::modular(numbers, &mut env);
+        assert_eq!(res, Err(EvalError::raise("arithmetic-overflow", "Integer overflow")));
+    }
 }
@@ -1,38 +1,36 @@
 use std::iter;
 
 use risuppu::{
-    semantic::Env,
+    semantic::{Env, EvalResult},
     sexp::{Ptr, Sexp},
 };
 
-pub fn multiply(mut args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+pub fn multiply(mut args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
     let sum = iter::from_fn(|| {
         if args.is_nil() {
             None
         } else {
-            let car = env.evaluate(args.car());
+            let car = env.try_evaluate(args.car());
             args = args.cdr();
             Some(car)
         }
     })
-    .map(|a| match a.as_ref() {
-        Sexp::I32(a) => *a,
-        _ => 1,
-    })
-    .product();
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .try_fold(1, |product: i32, a| super::checked(product.checked_mul(super::number(&a)?)))?;
 
-    Sexp::int(sum)
+    Ok(Sexp::int(sum))
 }
 
 #[cfg(test)]
 mod test {
-    use risuppu::{semantic::Env, sexp::Sexp};
+    use risuppu::{semantic::{Env, EvalError}, sexp::Sexp};
 
     #[test]
     fn multiply() {
         let numbers = Sexp::from_vec([Sexp::int(1), Sexp::int(2), Sexp::int(3)]);
         let mut env = Env::new();
-        let sum = super::multiply(numbers, &mut env);
+        let sum = super::multiply(numbers, &mut env).unwrap();
         assert_eq!(sum, Sexp::int(6));
     }
 
@@ -41,6 +39,14 @@ mod test {
         let list = Sexp::from_vec([Sexp::nil()]);
         let mut env = Env::new();
         let sum = super::multiply(list, &mut env);
-        assert_eq!(sum, Sexp::int(1));
+        assert_eq!(sum, Err(EvalError::raise("type-error", "Expected a number, found ()")));
+    }
+
+    #[test]
+    fn multiply_overflow() {
+        let numbers = Sexp::from_vec([Sexp::int(65536), Sexp::int(65536)]);
+        let mut env = Env::new();
+        let product = super::multiply(numbers, &mut env);
+        assert_eq!(product, Err(EvalError::raise("arithmetic-overflow", "Integer overflow")));
     }
 }
@@ -1,35 +1,33 @@
 use std::iter;
 
-use risuppu::{sexp::{Ptr, Sexp}, semantic::Env};
+use risuppu::{sexp::{Ptr, Sexp}, semantic::{Env, EvalResult}};
 
-pub fn plus(mut args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+pub fn plus(mut args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
     let sum = iter::from_fn(|| {
         if args.is_nil() {
             None
         } else {
-            let car = env.evaluate(args.car());
+            let car = env.try_evaluate(args.car());
             args = args.cdr();
             Some(car)
         }
     })
-    .map(|a| match a.as_ref() {
-        Sexp::I32(a) => *a,
-        _ => 0,
-    })
-    .sum();
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .try_fold(0, |sum: i32, a| super::checked(sum.checked_add(super::number(&a)?)))?;
 
-    Sexp::int(sum)
+    Ok(Sexp::int(sum))
 }
 
 #[cfg(test)]
 mod test {
-    use risuppu::{sexp::Sexp, semantic::Env};
+    use risuppu::{sexp::Sexp, semantic::{Env, EvalError}};
 
     #[test]
     fn plus() {
         let numbers = Sexp::from_vec([Sexp::int(1), Sexp::int(2), Sexp::int(3)]);
         let mut env = Env::new();
-        let sum = super::plus(numbers, &mut env);
+        let sum = super::plus(numbers, &mut env).unwrap();
         assert_eq!(sum, Sexp::int(6));
     }
 
@@ -38,6 +36,14 @@ mod test {
         let list = Sexp::from_vec([Sexp::nil()]);
         let mut env = Env::new();
         let sum = super::plus(list, &mut env);
-        assert_eq!(sum, Sexp::int(0));
+        assert_eq!(sum, Err(EvalError::raise("type-error", "Expected a number, found ()")));
+    }
+
+    #[test]
+    fn plus_overflow() {
+        let numbers = Sexp::from_vec([Sexp::int(i32::MAX), Sexp::int(1)]);
+        let mut env = Env::new();
+        let sum = super::plus(numbers, &mut env);
+        assert_eq!(sum, Err(EvalError::raise("arithmetic-overflow", "Integer overflow")));
     }
 }
@@ -1,25 +1,19 @@
 use risuppu::{
-    semantic::Env,
-    sexp::{Ptr, Sexp},
+    semantic::{Env, EvalResult},
+    sexp::{symbol::Symbol, Ptr, Sexp},
 };
 
-pub fn eval_cond(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let cond = args.car();
-    let cont = args.cdr();
-
-    let eval_cond = env.evaluate(cond);
-    Sexp::cons(eval_cond, cont)
-}
-
-pub fn and_then(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
+/// `(and-then expr cont)`: Apply `cont` to the value of `expr`, unless the value is `()`.
+pub fn and_then(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let e = args.car();
     let c = args.cdr().car();
 
-    if !e.is_nil() {
-        Sexp::from_vec([c, e])
-    } else {
-        e
-    }
+    // ((lambda (v) (if (eq v ()) v (c v))) e), where `v` can't be captured by `c`.
+    let v = Sexp::wrap(Sexp::Identifier(Symbol::fresh("v")));
+    let is_nil = Sexp::from_vec([Sexp::eq(), v.clone(), Sexp::nil()]);
+    let body = Sexp::from_vec([Sexp::r#if(), is_nil, v.clone(), Sexp::from_vec([c, v.clone()])]);
+    let lambda = Sexp::from_vec([Sexp::lambda(), Sexp::from_vec([v]), body]);
+    Ok(Sexp::from_vec([lambda, e]))
 }
 
 #[cfg(test)]
@@ -38,7 +32,7 @@ mod test {
             Sexp::identifier("b"),
         ]);
 
-        let expanded = super::and_then(Sexp::from_vec([expr, cont]), &mut env);
+        let expanded = super::and_then(Sexp::from_vec([expr, cont]), &mut env).unwrap();
         let evaluated = env.evaluate(expanded);
         assert_eq!(evaluated, Sexp::bool(true));
     }
@@ -53,7 +47,7 @@ mod test {
             Sexp::int(1),
         ]);
 
-        let expanded = super::and_then(Sexp::from_vec([expr, cont]), &mut env);
+        let expanded = super::and_then(Sexp::from_vec([expr, cont]), &mut env).unwrap();
         let evaluated = env.evaluate(expanded);
         assert_eq!(evaluated, Sexp::nil());
     }
@@ -0,0 +1,79 @@
+use risuppu::{
+    semantic::{check_arity, Arity, Env, EvalError, EvalResult},
+    sexp::{Ptr, Sexp},
+};
+
+fn quote(arg: Ptr<Sexp>) -> Ptr<Sexp> {
+    Sexp::from_vec([Sexp::quote(), arg])
+}
+
+/// `(apply f arg ... list)`: Apply `f` to the arguments followed by the elements of `list`.
+fn apply(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    check_arity("apply", &args, Arity::at_least(1))?;
+    let f = args.car();
+    let mut args = Sexp::iter(args.cdr()).collect::<Vec<_>>();
+
+    if let Some(list) = args.pop() {
+        if !matches!(list.as_ref(), Sexp::Form(_) | Sexp::Nil) {
+            return Err(EvalError::raise("type-error", format!("Expected a list, found {list}")));
+        }
+        args.extend(Sexp::iter(list));
+    }
+
+    // The args have been evaluated.
+    Ok(Sexp::cons(f, Sexp::from_vec(args.into_iter().map(quote).collect::<Vec<_>>())))
+}
+
+/// `(partial f arg ...)`: A function applying `f` to the arguments followed by its own arguments.
+///
+/// Unlike currying, it works even if the arity is checked strictly.
+pub fn partial(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    check_arity("partial", &args, Arity::at_least(1))?;
+    let apply = unsafe { Sexp::rust_fn_with_evaluated_args(apply) };
+    let rest = Sexp::identifier("rest");
+
+    let mut body = vec![apply];
+    body.extend(Sexp::iter(args).map(quote));
+    body.push(rest.clone());
+
+    Ok(Sexp::from_vec([
+        Sexp::lambda(),
+        Sexp::from_vec([Sexp::identifier("&rest"), rest]),
+        Sexp::from_vec(body),
+    ]))
+}
+
+#[cfg(test)]
+mod test {
+    use risuppu::{
+        semantic::{Env, EvalError},
+        sexp::{parse::parse_sexp, Sexp},
+    };
+
+    use crate::base::load_base;
+
+    #[test]
+    fn partial() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        let expr = parse_sexp("((partial (lambda (a b c) (cons a (cons b c))) 1) 2 '(3))").unwrap().1;
+        assert_eq!(env.evaluate(expr), parse_sexp("(1 2 3)").unwrap().1);
+
+        let expr = parse_sexp("((partial (lambda () 1)))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::int(1));
+    }
+
+    #[test]
+    fn partial_in_strict_mode() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        env.set_strict_arity(true);
+        env.evaluate(parse_sexp("(define pair (lambda (a b) (cons a b)))").unwrap().1);
+
+        let e = env.try_evaluate(parse_sexp("((pair 1) 2)").unwrap().1).unwrap_err();
+        assert!(matches!(e.root(), EvalError::WrongArity { name, found: 1, .. } if name == "pair"));
+
+        let expr = parse_sexp("((partial pair '(1)) 2)").unwrap().1;
+        assert_eq!(env.evaluate(expr), parse_sexp("((1) . 2)").unwrap().1);
+    }
+}
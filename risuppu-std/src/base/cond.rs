@@ -1,12 +1,12 @@
-use risuppu::{sexp::{Ptr, Sexp}, semantic::Env};
+use risuppu::{sexp::{Ptr, Sexp}, semantic::{sequence, Env, EvalResult}};
 
-pub fn cond(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
+pub fn cond(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let mut arms = vec![];
     let mut else_arm = Sexp::nil();
     let else_flag = Sexp::identifier("else");
 
     for arm in Sexp::iter(args) {
-        let (c, b) = (arm.car(), arm.cdr().car());
+        let (c, b) = (arm.car(), sequence(arm.cdr()));
         if c == else_flag {
             else_arm = b;
             break;
@@ -15,9 +15,9 @@ pub fn cond(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
         arms.push((c, b));
     }
 
-    arms.into_iter().rev().fold(else_arm, |else_branch, (condition, branch)| {
+    Ok(arms.into_iter().rev().fold(else_arm, |else_branch, (condition, branch)| {
         Sexp::from_vec([Sexp::r#if(), condition, branch, else_branch])
-    })
+    }))
 }
 
 #[cfg(test)]
@@ -30,11 +30,20 @@ mod test {
     fn expand_cond() {
         let mut env = Env::new();
         let expr = parse_sexp("(((eq n 1) 2) ((eq n 2) 1) (else 3))").unwrap().1;
-        let expanded = super::cond(expr, &mut env);
+        let expanded = super::cond(expr, &mut env).unwrap();
         let expected = parse_sexp("(if (eq n 1) 2 (if (eq n 2) 1 3))").unwrap().1;
         assert_eq!(expanded, expected);
     }
 
+    #[test]
+    fn multi_form_arm() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        let expr = parse_sexp("((lambda (n) (cond ((eq n 1) (define m n) m) (else (define m 0) m))) 1)").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::int(1));
+        assert!(env.get("m").is_none());
+    }
+
     #[test]
     fn cond() {
         let mut env = Env::new();
@@ -50,4 +59,16 @@ mod test {
         env.set_global("n", Sexp::int(3));
         assert_eq!(env.evaluate(expr.clone()), Sexp::int(3));
     }
+
+    #[test]
+    fn tail_call_in_arm() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        env.set_max_depth(100);
+        env.set_global("l", Sexp::from_vec(vec![Sexp::int(1); 1000]));
+        env.evaluate(parse_sexp("(define (walk l) (cond ((eq l '()) 'done) (else (walk (cdr l)))))").unwrap().1);
+
+        let res = env.try_evaluate(parse_sexp("(walk l)").unwrap().1);
+        assert_eq!(res, Ok(Sexp::identifier("done")));
+    }
 }
@@ -1,11 +1,11 @@
 use std::iter;
 
 use risuppu::{
-    semantic::Env,
+    semantic::{Env, EvalResult},
     sexp::{Ptr, Sexp},
 };
 
-pub fn r#do(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
+pub fn r#do(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let mut forms = args;
     let mut v = vec![];
 
@@ -33,10 +33,16 @@ pub fn r#do(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
         break;
     }
 
-    v.into_iter()
+    Ok(v.into_iter()
         .rev()
         .fold(Sexp::nil(), |form, (mut body, lambda_params)| {
-            if let Some(lambda_params) = lambda_params {
+            let is_define = matches!(body.car().as_ref(), Sexp::Define);
+            if is_define && lambda_params.as_ref().is_some_and(|params| params.is_nil()) {
+                // `define` takes no continuation, so the rest of the block follows it.
+                Sexp::from_vec([Sexp::begin(), body, form])
+            } else if let Some(lambda_params) = lambda_params {
+                // The steps share the scope of the block.
+                let lambda_params = Sexp::cons(Sexp::identifier("#:block"), lambda_params);
                 let lambda = Sexp::from_vec([Sexp::lambda(), lambda_params, form]);
                 let mut body: Vec<_> = iter::from_fn(|| {
                     if body.is_nil() {
@@ -53,12 +59,14 @@ pub fn r#do(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
             } else {
                 body
             }
-        })
+        }))
 }
 
 #[cfg(test)]
 mod test {
-    use risuppu::semantic::Env;
+    use risuppu::{semantic::Env, sexp::parse::parse_sexp};
+
+    use crate::base::load_base;
 
     #[test]
     fn expand_do() {
@@ -68,7 +76,7 @@ mod test {
             risuppu::sexp::parse::parse_sexp("(do (-> (read) (name)) (-> (print name)) name)")
                 .unwrap()
                 .1;
-        let expanded_expr = super::r#do(expr.cdr(), &mut env);
+        let expanded_expr = super::r#do(expr.cdr(), &mut env).unwrap();
 
         // let expected = Sexp::from_vec([
         //     Sexp::read(),
@@ -83,10 +91,25 @@ mod test {
         //     ]),
         // ]);
         let expected = risuppu::sexp::parse::parse_sexp(
-            "(read (lambda (name) (print name (lambda () name))))",
+            "(read (lambda (#:block name) (print name (lambda (#:block) name))))",
         )
         .unwrap()
         .1;
         assert_eq!(expanded_expr, expected);
     }
+
+    #[test]
+    fn define_in_block() {
+        let mut env = Env::new();
+        load_base(&mut env);
+
+        let expr = parse_sexp(
+            "((lambda () (do (-> (define a 1)) (-> (define (f) (cons a b))) (-> (define b 2)) (f))))",
+        )
+        .unwrap()
+        .1;
+        assert_eq!(env.evaluate(expr), parse_sexp("(1 . 2)").unwrap().1);
+        assert!(env.get("a").is_none());
+        assert!(env.get("f").is_none());
+    }
 }
@@ -0,0 +1,70 @@
+use risuppu::{
+    semantic::{Env, EvalError, EvalResult},
+    sexp::{Ptr, Sexp},
+};
+
+/// `(raise value)`: Raise any value, which is passed to the handlers of `try`.
+pub fn raise(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    Err(EvalError::Raised(args.car()))
+}
+
+/// `(error 'kind message)` or `(error message)`: Raise an error value.
+pub fn error(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    let first = args.car();
+    let error = match first.as_ref() {
+        Sexp::Identifier(kind) => Sexp::error(kind, args.cdr().car()),
+        _ => Sexp::error("error", first),
+    };
+    Err(EvalError::Raised(error))
+}
+
+pub fn is_error(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    Ok(Sexp::bool(matches!(args.car().as_ref(), Sexp::Error(_))))
+}
+
+pub fn error_kind(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    match args.car().as_ref() {
+        Sexp::Error(e) => Ok(Sexp::from_vec([Sexp::quote(), Sexp::identifier(&e.kind)])),
+        arg => Err(EvalError::raise("type-error", format!("Expected an error, found {arg}"))),
+    }
+}
+
+pub fn error_message(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    match args.car().as_ref() {
+        Sexp::Error(e) => Ok(Sexp::from_vec([Sexp::quote(), e.message.clone()])),
+        arg => Err(EvalError::raise("type-error", format!("Expected an error, found {arg}"))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use risuppu::{
+        semantic::{Env, EvalError},
+        sexp::{parse::parse_sexp, Sexp},
+    };
+
+    use crate::base::load_base;
+
+    #[test]
+    fn raise_error() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        let res = env.try_evaluate(parse_sexp("(error 'bad-config \"Missing field\")").unwrap().1);
+        assert_eq!(res, Err(EvalError::raise("bad-config", "Missing field")));
+        assert_eq!(res.unwrap_err().to_string(), "bad-config: Missing field");
+    }
+
+    #[test]
+    fn catch_error() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        let expr = parse_sexp("(try (error 'bad-config \"Missing field\") (bad-config e (error-message e)))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::string("Missing field"));
+        let expr = parse_sexp("(try (error \"Oops\") (else e (error-kind e)))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::identifier("error"));
+        let expr = parse_sexp("(try (raise '(1 2)) (else e (error? e)))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::bool(false));
+        let expr = parse_sexp("(try (raise '(1 2)) (else e e))").unwrap().1;
+        assert_eq!(env.evaluate(expr), parse_sexp("(1 2)").unwrap().1);
+    }
+}
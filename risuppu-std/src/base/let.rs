@@ -1,48 +1,15 @@
 use risuppu::{
-    semantic::Env,
+    semantic::{Env, EvalResult},
     sexp::{Ptr, Sexp},
 };
 
-fn omega() -> Ptr<Sexp> {
-    Sexp::from_vec([
-        Sexp::lambda(),
-        Sexp::from_vec([Sexp::identifier("f")]),
-        Sexp::from_vec([Sexp::identifier("f"), Sexp::identifier("f")]),
-    ])
-}
-
-fn y(params: Vec<Ptr<Sexp>>) -> Ptr<Sexp> {
-    let params = Sexp::from_vec(params);
-    Sexp::from_vec([
-        Sexp::lambda(),
-        Sexp::from_vec([Sexp::identifier("f")]),
-        Sexp::from_vec([
-            omega(),
-            Sexp::from_vec([
-                Sexp::lambda(),
-                Sexp::from_vec([Sexp::identifier("g")]),
-                Sexp::from_vec([
-                    Sexp::lambda(),
-                    params.clone(),
-                    Sexp::cons(
-                        Sexp::from_vec([
-                            Sexp::identifier("f"),
-                            Sexp::from_vec([Sexp::identifier("g"), Sexp::identifier("g")]),
-                        ]),
-                        params,
-                    ),
-                ]),
-            ]),
-        ]),
-    ])
-}
-
-pub fn r#let(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
+pub fn r#let(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let first_form = args.car();
-    let (named, decls, cont) = if let Sexp::Identifier(_) = first_form.as_ref() {
-        (Some(first_form), args.cdr().car(), args.cdr().cdr().car())
+    // The body can have several forms.
+    let (named, decls, body) = if let Sexp::Identifier(_) = first_form.as_ref() {
+        (Some(first_form), args.cdr().car(), args.cdr().cdr())
     } else {
-        (None, first_form, args.cdr().car())
+        (None, first_form, args.cdr())
     };
 
     let (idents, decls): (Vec<_>, Vec<_>) = Sexp::iter(decls)
@@ -59,13 +26,17 @@ pub fn r#let(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
         .unzip();
 
     if let Some(named) = named {
-        let lambda = Sexp::from_vec([Sexp::lambda(), Sexp::cons(named, Sexp::from_vec(idents.clone())), cont]);
-        let yc = y(idents);
-        let yc_lambda = Sexp::from_vec([yc, lambda]);
-        Sexp::cons(yc_lambda, Sexp::from_vec(decls))
+        // ((letrec ((named (lambda (idents...) body...))) named) decls...)
+        let lambda = Sexp::cons(Sexp::lambda(), Sexp::cons(Sexp::from_vec(idents), body));
+        let letrec = Sexp::from_vec([
+            Sexp::letrec(),
+            Sexp::from_vec([Sexp::from_vec([named.clone(), lambda])]),
+            named,
+        ]);
+        Ok(Sexp::cons(letrec, Sexp::from_vec(decls)))
     } else {
-        let lambda = Sexp::from_vec([Sexp::lambda(), Sexp::from_vec(idents), cont]);
-        Sexp::cons(lambda, Sexp::from_vec(decls))
+        let lambda = Sexp::cons(Sexp::lambda(), Sexp::cons(Sexp::from_vec(idents), body));
+        Ok(Sexp::cons(lambda, Sexp::from_vec(decls)))
     }
 }
 
@@ -81,7 +52,7 @@ mod test {
         let expr = risuppu::sexp::parse::parse_sexp("(((a 1)) (eq a 1))")
             .unwrap()
             .1;
-        let expanded = super::r#let(expr, &mut env);
+        let expanded = super::r#let(expr, &mut env).unwrap();
         let expected = risuppu::sexp::parse::parse_sexp("((lambda (a) (eq a 1)) 1)")
             .unwrap()
             .1;
@@ -94,7 +65,7 @@ mod test {
         let expr = risuppu::sexp::parse::parse_sexp("(((a 1) (b 2)) (eq a b))")
             .unwrap()
             .1;
-        let expanded = super::r#let(expr, &mut env);
+        let expanded = super::r#let(expr, &mut env).unwrap();
         let expected = risuppu::sexp::parse::parse_sexp("((lambda (a b) (eq a b)) 1 2)")
             .unwrap()
             .1;
@@ -107,20 +78,22 @@ mod test {
         let expr = risuppu::sexp::parse::parse_sexp("(loop ((a 1) (b 2)) (loop a b))")
             .unwrap()
             .1;
-        let expanded = super::r#let(expr, &mut env);
-        let expected = risuppu::sexp::parse::parse_sexp(
-            "(((lambda (f) ((lambda (f) (f f)) (lambda (g) (lambda (a b) ((f (g g)) a b))))) (lambda (loop a b) (loop a b))) 1 2)",
-        )
-        .unwrap()
-        .1;
+        let expanded = super::r#let(expr, &mut env).unwrap();
+        let expected = risuppu::sexp::parse::parse_sexp("((letrec ((loop (lambda (a b) (loop a b)))) loop) 1 2)")
+            .unwrap()
+            .1;
+        assert_eq!(expanded, expected);
+    }
 
-        // assert_eq!(expanded, expected);
-        // The exprs are too complicated to debug.
-        if expanded != expected {
-            println!("expanded = {}", expanded);
-            println!("expected = {}", expected);
-            panic!("The let-form wasn't expanded to the expected form!")
-        }
+    #[test]
+    fn let_multi_form_body() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        let expr = risuppu::sexp::parse::parse_sexp("(let ((a 1)) (define b a) (cons a b))")
+            .unwrap()
+            .1;
+        assert_eq!(env.evaluate(expr), Sexp::cons(Sexp::int(1), Sexp::int(1)));
+        assert!(env.get("b").is_none());
     }
 
     #[test]
@@ -129,7 +102,7 @@ mod test {
         let expr = risuppu::sexp::parse::parse_sexp("(let ((l '(1 2))) (car l))")
             .unwrap()
             .1;
-        let expanded = super::r#let(expr, &mut env);
+        let expanded = super::r#let(expr, &mut env).unwrap();
         let expected = Sexp::int(1);
         assert_eq!(env.evaluate(expanded), expected);
     }
@@ -149,6 +122,19 @@ mod test {
         assert_eq!(evaluated, expected);
     }
 
+    #[test]
+    fn let_long_loop() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        load_arithmetic(&mut env);
+        let expr = risuppu::sexp::parse::parse_sexp(
+            "(let loop ((n 10000) (acc 0)) (if (eq n 0) acc (loop (__builtin_- n 1) (__builtin_+ acc 1))))",
+        )
+        .unwrap()
+        .1;
+        assert_eq!(env.evaluate(expr), Sexp::int(10000));
+    }
+
     #[test]
     fn let_nil_last_param() {
         let mut env = Env::new();
@@ -163,4 +149,22 @@ mod test {
         let expected = Sexp::int(15);
         assert_eq!(evaluated, expected);
     }
+
+    #[test]
+    fn let_expanded_once() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        let parse = |s: &str| risuppu::sexp::parse::parse_sexp(s).unwrap().1;
+        env.evaluate(parse("(define (f x) (let ((y x)) (cons x y)))"));
+
+        // The body of `f` was expanded when it was defined.
+        env.set_global("let", Sexp::int(0));
+        assert_eq!(env.evaluate(parse("(f 1)")), Sexp::cons(Sexp::int(1), Sexp::int(1)));
+
+        env.set_eager_expansion(false);
+        load_base(&mut env);
+        env.evaluate(parse("(define (g x) (let ((y x)) (cons x y)))"));
+        env.set_global("let", Sexp::int(0));
+        assert!(env.try_evaluate(parse("(g 1)")).is_err());
+    }
 }
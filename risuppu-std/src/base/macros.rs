@@ -0,0 +1,111 @@
+use risuppu::{
+    semantic::{expand_once, Env, EvalError, EvalResult},
+    sexp::{symbol::Symbol, Ptr, Sexp},
+};
+
+fn quote(arg: Ptr<Sexp>) -> Ptr<Sexp> {
+    Sexp::from_vec([Sexp::quote(), arg])
+}
+
+/// `(gensym)` or `(gensym prefix)`: An identifier which differs from every other identifier,
+/// including the ones read with the same name.
+pub fn gensym(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    let prefix = match args.car().as_ref() {
+        Sexp::Nil => "g".to_string(),
+        Sexp::SString(prefix) => prefix.clone(),
+        Sexp::Identifier(prefix) => prefix.to_string(),
+        prefix => {
+            let message = format!("Expected a string or an identifier, found {prefix}");
+            return Err(EvalError::raise("type-error", message));
+        }
+    };
+    Ok(quote(Sexp::wrap(Sexp::Identifier(Symbol::fresh(&prefix)))))
+}
+
+/// `(macroexpand-1 form)`: Expand the form once if it's a call of a macro or an expanding form like `let`,
+/// without evaluating the expansion.
+pub fn macroexpand_1(args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let form = args.car();
+    Ok(quote(expand_once(&form, env)?.unwrap_or(form)))
+}
+
+/// `(macroexpand form)`: Expand the form until it's no longer a call of a macro or an expanding form.
+///
+/// The subforms of the expansion are left as they are.
+pub fn macroexpand(args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let mut form = args.car();
+    while let Some(expansion) = expand_once(&form, env)? {
+        form = expansion;
+    }
+    Ok(quote(form))
+}
+
+#[cfg(test)]
+mod test {
+    use risuppu::{
+        semantic::{Env, EvalError},
+        sexp::{parse::parse_sexp, Ptr, Sexp},
+    };
+
+    use crate::base::load_base;
+
+    fn eval(env: &mut Env, expr: &str) -> Result<Ptr<Sexp>, EvalError> {
+        env.try_evaluate(parse_sexp(expr).unwrap().1)
+    }
+
+    #[test]
+    fn gensym() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        let a = eval(&mut env, "(gensym)").unwrap();
+        let b = eval(&mut env, "(gensym \"tmp\")").unwrap();
+        assert!(a.is_identifier() && b.is_identifier());
+        assert_ne!(a, b);
+        assert!(b.to_string().starts_with("tmp"));
+        // A name read from the source is a different identifier.
+        assert_ne!(Sexp::identifier(&b), b);
+        assert_eq!(
+            eval(&mut env, "(gensym 1)"),
+            Err(EvalError::raise("type-error", "Expected a string or an identifier, found 1"))
+        );
+    }
+
+    #[test]
+    fn macroexpand_1() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        eval(&mut env, "(define unless (macro (c body) (cons 'if (cons c (cons () (cons body ()))))))").unwrap();
+        eval(&mut env, "(define unless-nil (macro (c body) (cons 'unless (cons (cons 'eq (cons c '(()))) (cons body ())))))").unwrap();
+
+        let expanded = eval(&mut env, "(macroexpand-1 '(unless (print 1) (print 2)))").unwrap();
+        assert_eq!(expanded, parse_sexp("(if (print 1) () (print 2))").unwrap().1);
+        let expanded = eval(&mut env, "(macroexpand-1 '(unless-nil x y))").unwrap();
+        assert_eq!(expanded, parse_sexp("(unless (eq x ()) y)").unwrap().1);
+        let expanded = eval(&mut env, "(macroexpand-1 '(let ((a 1)) a))").unwrap();
+        assert_eq!(expanded, parse_sexp("((lambda (a) a) 1)").unwrap().1);
+        let expanded = eval(&mut env, "(macroexpand-1 '(cond ((eq a 1) 2) (else 3)))").unwrap();
+        assert_eq!(expanded, parse_sexp("(if (eq a 1) 2 3)").unwrap().1);
+
+        // The other forms are left as they are.
+        let expanded = eval(&mut env, "(macroexpand-1 '(cons 1 2))").unwrap();
+        assert_eq!(expanded, parse_sexp("(cons 1 2)").unwrap().1);
+        assert_eq!(eval(&mut env, "(macroexpand-1 'a)"), Ok(Sexp::identifier("a")));
+    }
+
+    #[test]
+    fn macroexpand() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        eval(&mut env, "(define-syntax my-let (syntax-rules () ((_ (n v) body) (let ((n v)) body))))").unwrap();
+
+        let expanded = eval(&mut env, "(macroexpand '(my-let (a 1) (my-let (b 2) b)))").unwrap();
+        assert_eq!(expanded, parse_sexp("((lambda (a) (my-let (b 2) b)) 1)").unwrap().1);
+        let expanded = eval(&mut env, "(macroexpand '(match 1 (a a)))").unwrap();
+        assert_eq!(expanded.cdr(), parse_sexp("(1 '(a a))").unwrap().1);
+        let expanded = eval(&mut env, "(macroexpand '(do (-> (read) (a)) a))").unwrap();
+        assert_eq!(expanded, parse_sexp("(read (lambda (#:block a) a))").unwrap().1);
+        let expanded = eval(&mut env, "(macroexpand '(and-then 1 f))").unwrap();
+        assert!(matches!(expanded.car().car().as_ref(), Sexp::Lambda));
+        assert_eq!(expanded.cdr(), parse_sexp("(1)").unwrap().1);
+    }
+}
@@ -1,50 +1,54 @@
 use risuppu::{
-    semantic::Env,
+    semantic::{sequence, Env, EvalError, EvalResult},
     sexp::{pattern::Pattern, Ptr, Sexp},
 };
 
-pub fn r#match(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
+pub fn r#match(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let arg = args.car();
     let arms = args.cdr();
     let else_flag = Sexp::identifier("else");
 
     for arm in Sexp::iter(arms) {
-        let (pat, ret_val): (Ptr<Sexp>, Ptr<Sexp>) = (arm.car(), arm.cdr().car());
+        // The arm can have several forms.
+        let (pat, body): (Ptr<Sexp>, Ptr<Sexp>) = (arm.car(), arm.cdr());
         if pat == else_flag {
-            #[cfg(debug_assertions)]
-            println!("Enter Else arm.");
-            return ret_val;
+            return Ok(sequence(body));
         }
 
         let pat: Pattern = pat.into();
-        #[cfg(debug_assertions)]
-        println!("Match {arg:?} against {pat:?}:");
 
         if let Ok(bindings) = pat.bind(arg.clone()) {
             let (params, args): (Vec<_>, Vec<_>) = bindings
                 .get_binding()
                 .into_iter()
-                .map(|(s, v)| (Sexp::identifier(s), v))
+                .map(|(s, v)| (Sexp::wrap(Sexp::Identifier(s)), v))
                 .unzip();
-            let lambda = Sexp::from_vec([Sexp::lambda(), Sexp::from_vec(params), ret_val]);
-            return Sexp::cons(lambda, Sexp::from_vec(args));
+            let lambda = Sexp::cons(Sexp::lambda(), Sexp::cons(Sexp::from_vec(params), body));
+            return Ok(Sexp::cons(lambda, Sexp::from_vec(args)));
         }
     }
 
-    Sexp::nil()
+    Err(EvalError::raise("match-error", format!("No arm matches {arg}")))
 }
 
-pub fn pre_match(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+/// `(match expr arm ...)`: Evaluate `expr`, and then match the value against the arms.
+///
+/// The arms are quoted, so only `expr` is evaluated by the evaluator.
+pub fn expand_match(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let (arg, arms) = (args.car(), args.cdr());
-    let arg = env.evaluate(arg);
-    Sexp::cons(arg, arms)
+    let r#match = unsafe { Sexp::rust_fn_with_evaluated_args(r#match) };
+    let arms = Sexp::iter(arms)
+        .map(|arm| Sexp::from_vec([Sexp::quote(), arm]))
+        .collect::<Vec<_>>();
+    Ok(Sexp::cons(r#match, Sexp::cons(arg, Sexp::from_vec(arms))))
 }
 
 #[cfg(test)]
 mod test {
-    use risuppu::{sexp::{parse::parse_sexp, Sexp}, semantic::Env};
+    use risuppu::{sexp::{parse::parse_sexp, Sexp}, semantic::{Env, EvalError}};
 
     use super::r#match;
+    use crate::base::load_base;
 
     #[test]
     fn bind_name() {
@@ -54,7 +58,7 @@ mod test {
         let expr = Sexp::from_vec([arg, Sexp::from_vec([pat, ret_val])]);
 
         let mut env = Env::new();
-        let res = r#match(expr, &mut env);
+        let res = r#match(expr, &mut env).unwrap();
         let res = env.evaluate(res);
         let expected = Sexp::int(1);
         assert_eq!(res, expected);
@@ -70,9 +74,42 @@ mod test {
         let expr = Sexp::from_vec([arg, Sexp::from_vec([pat, ret_val]), Sexp::from_vec([else_flag, else_value])]);
 
         let mut env = Env::new();
-        let res = r#match(expr, &mut env);
+        let res = r#match(expr, &mut env).unwrap();
         let res = env.evaluate(res);
         let expected = Sexp::int(3);
         assert_eq!(res, expected);
     }
+
+    #[test]
+    fn no_arm_matched() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        let expr = parse_sexp("(match '(1 2) ('(a) a))").unwrap().1;
+        let res = env.try_evaluate(expr);
+        assert_eq!(res, Err(EvalError::raise("match-error", "No arm matches (1 2)")));
+    }
+
+    #[test]
+    fn multi_form_arm() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        let expr = parse_sexp("(match '(1 2) ((a b) (define c b) (cons a c)))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::cons(Sexp::int(1), Sexp::int(2)));
+        assert!(env.get("c").is_none());
+
+        let expr = parse_sexp("(match '(1 2) ((a) a) (else 'ignored 3))").unwrap().1;
+        assert_eq!(env.evaluate(expr), Sexp::int(3));
+    }
+
+    #[test]
+    fn tail_call_in_arm() {
+        let mut env = Env::new();
+        load_base(&mut env);
+        env.set_max_depth(100);
+        env.set_global("l", Sexp::from_vec(vec![Sexp::int(1); 1000]));
+        env.evaluate(parse_sexp("(define (walk l) (match l (() 'done) (else (walk (cdr l)))))").unwrap().1);
+
+        let res = env.try_evaluate(parse_sexp("(walk l)").unwrap().1);
+        assert_eq!(res, Ok(Sexp::identifier("done")));
+    }
 }
@@ -1,20 +1,31 @@
-use and_then::eval_cond;
-use r#match::pre_match;
-use crate::pre_function;
-
 mod seq;
 mod r#do;
 mod and_then;
 mod r#let;
 mod cond;
 mod r#match;
+mod error;
+mod apply;
+mod syntax_rules;
+mod macros;
 
 super::std_library!(
     base,
-    (seq::seq, "seq", pre_function),
-    (r#do::r#do, "do"),
-    (and_then::and_then, "and-then", eval_cond),
-    (r#let::r#let, "let"),
-    (cond::cond, "cond"),
-    (r#match::r#match, "match", pre_match)
+    (seq::seq, "seq", eval_args),
+    (r#do::r#do, "do", expander),
+    (and_then::and_then, "and-then", expander),
+    (r#let::r#let, "let", expander),
+    (cond::cond, "cond", expander),
+    (r#match::expand_match, "match", expander),
+    (syntax_rules::define_syntax, "define-syntax", expander),
+    (syntax_rules::syntax_rules, "syntax-rules", expander),
+    (macros::gensym, "gensym", eval_args),
+    (macros::macroexpand_1, "macroexpand-1", eval_args),
+    (macros::macroexpand, "macroexpand", eval_args),
+    (error::raise, "raise", eval_args),
+    (error::error, "error", eval_args),
+    (error::is_error, "error?", eval_args),
+    (error::error_kind, "error-kind", eval_args),
+    (error::error_message, "error-message", eval_args),
+    (apply::partial, "partial", eval_args)
 );
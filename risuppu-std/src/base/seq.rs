@@ -1,6 +1,6 @@
-use risuppu::{sexp::{Ptr, Sexp}, semantic::Env};
+use risuppu::{sexp::{Ptr, Sexp}, semantic::{Env, EvalResult}};
 
-pub fn seq(mut args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
+pub fn seq(mut args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let mut ret = Sexp::nil();
 
     while !args.is_nil() {
@@ -11,7 +11,7 @@ pub fn seq(mut args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
         ret = expr;
     }
 
-    ret
+    Ok(ret)
 }
 
 #[cfg(test)]
@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+
+use risuppu::{
+    semantic::{Env, EvalError, EvalResult},
+    sexp::{
+        pattern::{Binding, Pattern, Repetition, ELLIPSIS},
+        symbol::Symbol,
+        Cons, Ptr, Sexp,
+    },
+};
+
+/// `(define-syntax name transformer)`: Bind the transformer of `syntax-rules` to the name.
+///
+/// A `syntax-rules` transformer is given the name, which its errors report in the calls.
+pub fn define_syntax(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    let (name, transformer) = (args.car(), args.cdr().car());
+    match transformer.as_ref() {
+        Sexp::Form(Cons { car, cdr }) if is_syntax_rules(car) => {
+            Ok(Sexp::from_vec([Sexp::define(), name.clone(), rules_macro(cdr.clone(), name)]))
+        }
+        _ => Ok(Sexp::cons(Sexp::define(), args)),
+    }
+}
+
+/// `(syntax-rules (literal ...) ((_ pattern ...) template) ...)`: A macro which rewrites its call
+/// with the template of the first rule whose pattern matches the call.
+///
+/// The identifiers which the template binds and which aren't pattern variables are renamed
+/// in the scopes binding them on each expansion, so they can't capture the variables of the call.
+/// The new names are never freed, so a call expanded on each evaluation with `--lazy-expansion`
+/// adds names each time, while an eager expansion adds them once per call site.
+pub fn syntax_rules(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    Ok(rules_macro(args, Sexp::nil()))
+}
+
+/// `(macro form (transform 'spec form 'name))`, where the name is nil if the macro isn't named.
+fn rules_macro(spec: Ptr<Sexp>, name: Ptr<Sexp>) -> Ptr<Sexp> {
+    let transform = unsafe { Sexp::rust_fn_with_evaluated_args(transform) };
+    let form = Sexp::identifier("form");
+    let spec = Sexp::from_vec([Sexp::quote(), spec]);
+    let name = Sexp::from_vec([Sexp::quote(), name]);
+    Sexp::from_vec([Sexp::r#macro(), form.clone(), Sexp::from_vec([transform, spec, form, name])])
+}
+
+fn transform(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    let (spec, form, name) = (args.car(), args.cdr().car(), args.cdr().cdr().car());
+    let literals = Sexp::iter(spec.car())
+        .map(|literal| match literal.as_ref() {
+            Sexp::Identifier(ident) => Ok(*ident),
+            _ => Err(syntax_error(format!("Invalid literal {literal}"))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for rule in Sexp::iter(spec.cdr()) {
+        let (pattern, template) = (rule.car(), rule.cdr().car());
+        // The keyword in front of the pattern is ignored.
+        let pattern = match pattern.as_ref() {
+            Sexp::Form(Cons { cdr, .. }) => Pattern::syntax(cdr.clone(), &literals),
+            _ => None,
+        }
+        .ok_or_else(|| syntax_error(format!("Invalid pattern {pattern}")))?;
+
+        if let Ok(binding) = pattern.bind(form.clone()) {
+            let variables = pattern.variables();
+            let mut introduced = vec![];
+            binders(&template, &mut introduced);
+            let renames: HashMap<_, _> = introduced
+                .into_iter()
+                .filter(|ident| !variables.contains(ident) && *ident != ELLIPSIS)
+                .map(|ident| (ident, Symbol::fresh(&format!("{ident}."))))
+                .collect();
+            let originals = renames.iter().map(|(ident, renamed)| (*renamed, *ident)).collect();
+
+            let mut expansion = Expansion {
+                bindings: vec![&binding],
+                renames,
+            };
+            let expansion = expansion.template(&template, false)?;
+            // The renamed identifiers outside of the scopes binding them are free, e.g. globals.
+            let expansion = Scopes { originals, bound: vec![] }.code(&expansion);
+            // The result of a Rust function is evaluated, so the expansion is quoted.
+            return Ok(Sexp::from_vec([Sexp::quote(), expansion]));
+        }
+    }
+
+    // The call is shown with the keyword of the patterns if the macro isn't named.
+    let keyword = if name.is_nil() { Sexp::identifier("_") } else { name };
+    Err(syntax_error(format!("No rule matches {}", Sexp::cons(keyword, form))))
+}
+
+fn syntax_error(message: String) -> EvalError {
+    EvalError::raise("syntax-error", message)
+}
+
+fn is_syntax_rules(expr: &Ptr<Sexp>) -> bool {
+    matches!(expr.as_ref(), Sexp::Identifier(ident) if *ident == "syntax-rules")
+}
+
+fn is_ellipsis(expr: &Ptr<Sexp>) -> bool {
+    matches!(expr.as_ref(), Sexp::Identifier(ident) if *ident == ELLIPSIS)
+}
+
+struct Expansion<'a> {
+    /// The bindings of the pattern, followed by the matches of the repeated items being expanded.
+    bindings: Vec<&'a Binding>,
+    renames: HashMap<Symbol, Symbol>,
+}
+
+impl<'a> Expansion<'a> {
+    /// Instantiate the template, where `(... template)` escapes the ellipses in the template.
+    fn template(&mut self, template: &Ptr<Sexp>, escaped: bool) -> EvalResult {
+        let Sexp::Form(Cons { car, cdr }) = template.as_ref() else {
+            return match template.as_ref() {
+                Sexp::Identifier(ident) => self.identifier(*ident, template),
+                _ => Ok(template.clone()),
+            };
+        };
+
+        if !escaped && is_ellipsis(car) {
+            return self.template(&cdr.car(), true);
+        }
+        match cdr.as_ref() {
+            Sexp::Form(Cons { car: next, cdr: rest }) if !escaped && is_ellipsis(next) => {
+                let items = self.repeat(car)?;
+                let rest = self.template(rest, escaped)?;
+                Ok(items.into_iter().rev().fold(rest, |list, item| Sexp::cons(item, list)))
+            }
+            _ => Ok(Sexp::cons(self.template(car, escaped)?, self.template(cdr, escaped)?)),
+        }
+    }
+
+    fn identifier(&self, ident: Symbol, template: &Ptr<Sexp>) -> EvalResult {
+        for binding in self.bindings.iter().rev() {
+            if let Some(value) = binding.get(ident) {
+                return Ok(value);
+            }
+        }
+        if self.repetition(ident).is_some() {
+            return Err(syntax_error(format!("Pattern variable {ident} is used without an ellipsis")));
+        }
+        Ok(match self.renames.get(&ident) {
+            Some(renamed) => Sexp::wrap(Sexp::Identifier(*renamed)),
+            None => template.clone(),
+        })
+    }
+
+    /// The innermost repeated item binding the identifier, unless the identifier is bound in a deeper match.
+    fn repetition(&self, ident: Symbol) -> Option<&'a Repetition> {
+        for binding in self.bindings.iter().rev() {
+            if binding.get(ident).is_some() {
+                return None;
+            }
+            let repetition = binding.repetitions().iter().find(|r| r.variables.contains(&ident));
+            if repetition.is_some() {
+                return repetition;
+            }
+        }
+        None
+    }
+
+    /// Instantiate the template followed by an ellipsis once for each item matched by its pattern variables.
+    fn repeat(&mut self, template: &Ptr<Sexp>) -> Result<Vec<Ptr<Sexp>>, EvalError> {
+        let mut identifiers = vec![];
+        collect_identifiers(template, &mut identifiers);
+        let mut repetitions: Vec<&'a Repetition> = vec![];
+        for repetition in identifiers.into_iter().filter_map(|ident| self.repetition(ident)) {
+            if !repetitions.iter().any(|r| std::ptr::eq(*r, repetition)) {
+                repetitions.push(repetition);
+            }
+        }
+
+        let Some(count) = repetitions.first().map(|r| r.matches.len()) else {
+            return Err(syntax_error(format!("No pattern variable to repeat in {template}")));
+        };
+        if repetitions.iter().any(|r| r.matches.len() != count) {
+            return Err(syntax_error(format!("Pattern variables in {template} matched different numbers of items")));
+        }
+
+        (0..count)
+            .map(|i| {
+                let depth = self.bindings.len();
+                self.bindings.extend(repetitions.iter().map(|r| &r.matches[i]));
+                let item = self.template(template, false);
+                self.bindings.truncate(depth);
+                item
+            })
+            .collect()
+    }
+}
+
+/// Restore the renamed identifiers which are outside of the scopes binding them.
+struct Scopes {
+    /// The original names of the renamed identifiers.
+    originals: HashMap<Symbol, Symbol>,
+    /// The names bound by the enclosing scopes.
+    bound: Vec<Symbol>,
+}
+
+impl Scopes {
+    fn original(&self, ident: Symbol) -> Symbol {
+        self.originals.get(&ident).copied().unwrap_or(ident)
+    }
+
+    fn identifier(&self, ident: Symbol, expr: &Ptr<Sexp>) -> Ptr<Sexp> {
+        if self.originals.contains_key(&ident) && !self.bound.contains(&ident) {
+            Sexp::wrap(Sexp::Identifier(self.original(ident)))
+        } else {
+            expr.clone()
+        }
+    }
+
+    /// Restore the free identifiers of an expression.
+    fn code(&mut self, expr: &Ptr<Sexp>) -> Ptr<Sexp> {
+        let Sexp::Form(Cons { car, cdr }) = expr.as_ref() else {
+            return match expr.as_ref() {
+                Sexp::Identifier(ident) => self.identifier(*ident, expr),
+                _ => expr.clone(),
+            };
+        };
+
+        match car.as_ref() {
+            Sexp::Quote => Sexp::cons(car.clone(), self.data(cdr)),
+            Sexp::Lambda => {
+                let mut names = vec![];
+                params(&cdr.car(), &mut names);
+                Sexp::cons(car.clone(), self.scoped(names, |scopes| scopes.list(cdr)))
+            }
+            Sexp::Define => match cdr.car().as_ref() {
+                // The name is defined in the enclosing scope, and the params in the body.
+                Sexp::Form(Cons { car: name, cdr: params_list }) => {
+                    let name = self.code(name);
+                    let mut names = vec![];
+                    params(params_list, &mut names);
+                    let (params_list, body) =
+                        self.scoped(names, |scopes| (scopes.list(params_list), scopes.list(&cdr.cdr())));
+                    Sexp::cons(car.clone(), Sexp::cons(Sexp::cons(name, params_list), body))
+                }
+                _ => Sexp::cons(car.clone(), self.list(cdr)),
+            },
+            Sexp::Letrec | Sexp::LetrecStar => {
+                let mut names = vec![];
+                bindings(&cdr.car(), &mut names);
+                Sexp::cons(car.clone(), self.scoped(names, |scopes| scopes.list(cdr)))
+            }
+            Sexp::Identifier(ident) if self.original(*ident) == "let" => {
+                let head = self.identifier(*ident, car);
+                Sexp::cons(head, self.r#let(cdr))
+            }
+            _ => Sexp::cons(self.code(car), self.list(cdr)),
+        }
+    }
+
+    /// `(name? ((name init) ...) body ...)` of `let`, whose inits are outside of the scope of the names.
+    fn r#let(&mut self, args: &Ptr<Sexp>) -> Ptr<Sexp> {
+        let (named, decls, body) = match args.car().as_ref() {
+            Sexp::Identifier(_) => (Some(args.car()), args.cdr().car(), args.cdr().cdr()),
+            _ => (None, args.car(), args.cdr()),
+        };
+        let decls = Sexp::iter(decls).collect::<Vec<_>>();
+        let inits = decls.iter().map(|decl| self.list(&decl.cdr())).collect::<Vec<_>>();
+
+        let mut names = vec![];
+        named.iter().for_each(|name| push_identifier(name, &mut names));
+        decls.iter().for_each(|decl| push_identifier(&decl.car(), &mut names));
+        self.scoped(names, |scopes| {
+            let decls = decls
+                .iter()
+                .zip(inits)
+                .map(|(decl, init)| Sexp::cons(scopes.code(&decl.car()), init))
+                .collect::<Vec<_>>();
+            let rest = Sexp::cons(Sexp::from_vec(decls), scopes.list(&body));
+            match named {
+                Some(name) => Sexp::cons(scopes.code(&name), rest),
+                None => rest,
+            }
+        })
+    }
+
+    fn scoped<T>(&mut self, names: Vec<Symbol>, f: impl FnOnce(&mut Self) -> T) -> T {
+        let depth = self.bound.len();
+        self.bound.extend(names);
+        let result = f(self);
+        self.bound.truncate(depth);
+        result
+    }
+
+    /// Restore the free identifiers of the items of a list, and of a dotted tail.
+    fn list(&mut self, list: &Ptr<Sexp>) -> Ptr<Sexp> {
+        match list.as_ref() {
+            Sexp::Form(Cons { car, cdr }) => Sexp::cons(self.code(car), self.list(cdr)),
+            _ => self.code(list),
+        }
+    }
+
+    /// Restore every renamed identifier of quoted data.
+    fn data(&self, data: &Ptr<Sexp>) -> Ptr<Sexp> {
+        match data.as_ref() {
+            Sexp::Identifier(ident) => Sexp::wrap(Sexp::Identifier(self.original(*ident))),
+            Sexp::Form(Cons { car, cdr }) => Sexp::cons(self.data(car), self.data(cdr)),
+            _ => data.clone(),
+        }
+    }
+}
+
+fn collect_identifiers(template: &Ptr<Sexp>, identifiers: &mut Vec<Symbol>) {
+    match template.as_ref() {
+        Sexp::Identifier(ident) => identifiers.push(*ident),
+        Sexp::Form(Cons { car, cdr }) => {
+            collect_identifiers(car, identifiers);
+            collect_identifiers(cdr, identifiers);
+        }
+        _ => {}
+    }
+}
+
+/// The identifiers bound by `lambda`, `let`, `letrec`, `letrec*` and the params of `define` in the template.
+///
+/// The names defined by `define` are kept, so a macro can define them.
+fn binders(template: &Ptr<Sexp>, names: &mut Vec<Symbol>) {
+    let Sexp::Form(Cons { car, cdr }) = template.as_ref() else {
+        return;
+    };
+    match car.as_ref() {
+        Sexp::Lambda => params(&cdr.car(), names),
+        Sexp::Define => {
+            if let Sexp::Form(Cons { cdr: params_list, .. }) = cdr.car().as_ref() {
+                params(params_list, names);
+            }
+        }
+        Sexp::Letrec | Sexp::LetrecStar => bindings(&cdr.car(), names),
+        Sexp::Identifier(ident) if *ident == "let" => match cdr.car().as_ref() {
+            Sexp::Identifier(name) => {
+                names.push(*name);
+                bindings(&cdr.cdr().car(), names);
+            }
+            _ => bindings(&cdr.car(), names),
+        },
+        _ => {}
+    }
+
+    let mut items = template.clone();
+    while let Sexp::Form(Cons { car, cdr }) = items.as_ref() {
+        binders(car, names);
+        let cdr = cdr.clone();
+        items = cdr;
+    }
+}
+
+fn params(params: &Ptr<Sexp>, names: &mut Vec<Symbol>) {
+    let mut params = params.clone();
+    loop {
+        let next = match params.as_ref() {
+            Sexp::Identifier(rest) => return names.push(*rest),
+            Sexp::Form(Cons { car, cdr }) => {
+                match car.as_ref() {
+                    Sexp::Identifier(param) if param.starts_with("#:") || *param == "&rest" => {}
+                    Sexp::Identifier(param) => names.push(*param),
+                    // An optional param with its default value.
+                    Sexp::Form(Cons { car, .. }) => push_identifier(car, names),
+                    _ => {}
+                }
+                cdr.clone()
+            }
+            _ => return,
+        };
+        params = next;
+    }
+}
+
+/// The names of `((name init) ...)`.
+fn bindings(bindings: &Ptr<Sexp>, names: &mut Vec<Symbol>) {
+    for binding in Sexp::iter(bindings.clone()) {
+        push_identifier(&binding.car(), names);
+    }
+}
+
+fn push_identifier(name: &Ptr<Sexp>, names: &mut Vec<Symbol>) {
+    if let Sexp::Identifier(name) = name.as_ref() {
+        names.push(*name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use risuppu::{
+        semantic::{Env, EvalError},
+        sexp::{parse::parse_sexp, Sexp},
+    };
+
+    use crate::{arithmetic::load_arithmetic, base::load_base};
+
+    fn env() -> Env {
+        let mut env = Env::new();
+        load_base(&mut env);
+        load_arithmetic(&mut env);
+        env
+    }
+
+    fn eval(env: &mut Env, expr: &str) -> Result<risuppu::sexp::Ptr<Sexp>, EvalError> {
+        env.try_evaluate(parse_sexp(expr).unwrap().1)
+    }
+
+    #[test]
+    fn swap() {
+        let mut env = env();
+        eval(
+            &mut env,
+            "(define-syntax swap! (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))",
+        )
+        .unwrap();
+        eval(&mut env, "(define tmp 1)").unwrap();
+        eval(&mut env, "(define other 2)").unwrap();
+        eval(&mut env, "(swap! tmp other)").unwrap();
+        assert_eq!(env.get("tmp"), Some(Sexp::int(2)));
+        assert_eq!(env.get("other"), Some(Sexp::int(1)));
+    }
+
+    #[test]
+    fn rename_in_scope() {
+        let mut env = env();
+        eval(&mut env, "(define x 5)").unwrap();
+        eval(&mut env, "(define-syntax m (syntax-rules () ((_) (cons (let ((x 1)) x) x))))").unwrap();
+        assert_eq!(eval(&mut env, "(m)"), Ok(Sexp::cons(Sexp::int(1), Sexp::int(5))));
+
+        // The inits of `let` are outside of its scope, and quoted names are kept.
+        eval(&mut env, "(define-syntax n (syntax-rules () ((_ e) (let ((x x)) (cons 'x (cons x e))))))").unwrap();
+        let expected = parse_sexp("(x 5 . 2)").unwrap().1;
+        assert_eq!(eval(&mut env, "(n 2)"), Ok(expected));
+    }
+
+    #[test]
+    fn ellipsis() {
+        let mut env = env();
+        eval(
+            &mut env,
+            "(define-syntax my-or (syntax-rules ()
+                ((_) #f)
+                ((_ e) e)
+                ((_ e rest ...) (let ((t e)) (if t t (my-or rest ...))))))",
+        )
+        .unwrap();
+        assert_eq!(eval(&mut env, "(my-or)"), Ok(Sexp::bool(false)));
+        assert_eq!(eval(&mut env, "(let ((t 5)) (my-or #f t))"), Ok(Sexp::int(5)));
+        assert_eq!(eval(&mut env, "(my-or #f #f 3)"), Ok(Sexp::int(3)));
+    }
+
+    #[test]
+    fn nested_ellipsis() {
+        let mut env = env();
+        eval(
+            &mut env,
+            "(define-syntax my-let (syntax-rules ()
+                ((_ ((name value) ...) body ...) ((lambda (name ...) body ...) value ...))))",
+        )
+        .unwrap();
+        assert_eq!(eval(&mut env, "(my-let ((a 1) (b 2)) (define c 3) (__builtin_+ a b c))"), Ok(Sexp::int(6)));
+
+        eval(
+            &mut env,
+            "(define-syntax pairs (syntax-rules () ((_ (a b ...) ...) '(a ... ((a b) ...) ...))))",
+        )
+        .unwrap();
+        let expected = parse_sexp("(1 4 ((1 2) (1 3)) ((4 5)))").unwrap().1;
+        assert_eq!(eval(&mut env, "(pairs (1 2 3) (4 5))"), Ok(expected));
+
+        eval(&mut env, "(define-syntax bad (syntax-rules () ((_ a ...) 'a)))").unwrap();
+        let error = EvalError::raise("syntax-error", "Pattern variable a is used without an ellipsis");
+        assert_eq!(eval(&mut env, "(bad 1)"), Err(error));
+    }
+
+    #[test]
+    fn literals() {
+        let mut env = env();
+        eval(
+            &mut env,
+            "(define-syntax arrow (syntax-rules (=>) ((_ a => b) (cons a b)) ((_ a b) 'no-arrow)))",
+        )
+        .unwrap();
+        assert_eq!(eval(&mut env, "(arrow 1 => 2)"), Ok(Sexp::cons(Sexp::int(1), Sexp::int(2))));
+        assert_eq!(eval(&mut env, "(arrow 1 2)"), Ok(Sexp::identifier("no-arrow")));
+        assert_eq!(
+            eval(&mut env, "(arrow 1)"),
+            Err(EvalError::raise("syntax-error", "No rule matches (arrow 1)"))
+        );
+        assert_eq!(
+            eval(&mut env, "((syntax-rules () ((_ a) a)))"),
+            Err(EvalError::raise("syntax-error", "No rule matches (_)"))
+        );
+    }
+}
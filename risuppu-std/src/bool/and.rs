@@ -1,7 +1,7 @@
-use risuppu::{sexp::{Ptr, Sexp}, semantic::Env};
+use risuppu::{sexp::{Ptr, Sexp}, semantic::{Env, EvalResult}};
 
-pub fn and(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
-    Sexp::bool(Sexp::iter(args).all(|arg| !matches!(arg.as_ref(), Sexp::Bool(false))))
+pub fn and(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    Ok(Sexp::bool(Sexp::iter(args).all(|arg| !matches!(arg.as_ref(), Sexp::Bool(false)))))
 }
 
 #[cfg(test)]
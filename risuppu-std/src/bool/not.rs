@@ -1,8 +1,8 @@
-use risuppu::{sexp::{Ptr, Sexp}, semantic::Env};
+use risuppu::{sexp::{Ptr, Sexp}, semantic::{Env, EvalResult}};
 
-pub fn not(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
+pub fn not(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let arg = args.car();
-    Sexp::bool(matches!(arg.as_ref(), Sexp::Bool(false)))
+    Ok(Sexp::bool(matches!(arg.as_ref(), Sexp::Bool(false))))
 }
 
 #[cfg(test)]
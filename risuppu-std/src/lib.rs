@@ -1,4 +1,4 @@
-use risuppu::{sexp::{Sexp, Ptr}, semantic::Env};
+use risuppu::{sexp::{Sexp, Ptr}, semantic::{Env, EvalResult}};
 
 pub mod base;
 #[cfg(feature = "arithmetic")]
@@ -26,28 +26,48 @@ macro_rules! std_library {
 
 #[macro_export]
 macro_rules! load_fn {
-    ($env:ident, $function:expr, $rt_name:literal, $pre_function:expr) => {
+    ($env:ident, $function:expr, $rt_name:literal, eval_args) => {{
+        let function = $function;
+        $env.set_global($rt_name, unsafe {
+            risuppu::sexp::Sexp::rust_fn_with_evaluated_args(function)
+        })
+    }};
+    ($env:ident, $function:expr, $rt_name:literal, expander) => {{
+        let function = $function;
+        $env.set_global($rt_name, unsafe {
+            risuppu::sexp::Sexp::rust_fn_expander(function)
+        })
+    }};
+    ($env:ident, $function:expr, $rt_name:literal, $pre_function:expr) => {{
+        let (function, pre_function) = ($function, $pre_function);
         $env.set_global($rt_name, unsafe {
             risuppu::sexp::Sexp::rust_fn_with_preprocess(
-                $function,
-                $pre_function,
+                function,
+                pre_function,
             )
         })
-    };
-    ($env:ident, $function:expr, $rt_name:literal) => {
+    }};
+    ($env:ident, $function:expr, $rt_name:literal) => {{
+        let function = $function;
         $env.set_global($rt_name, unsafe {
-            risuppu::sexp::Sexp::rust_fn($function)
+            risuppu::sexp::Sexp::rust_fn(function)
         })
-    };
+    }};
 }
 
-pub fn pre_function(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
-    let args: Vec<_> = Sexp::iter(args).map(|s| env.evaluate(s)).collect();
-    Sexp::from_vec(args)
+/// Evaluate the arguments before applying them.
+///
+/// Prefer registering the function with `eval_args`,
+/// which evaluates the arguments without recursing on the native stack.
+pub fn pre_function(args: Ptr<Sexp>, env: &mut Env) -> EvalResult {
+    let args = Sexp::iter(args)
+        .map(|s| env.try_evaluate(s))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Sexp::from_vec(args))
 }
 
-pub fn id(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
-    args
+pub fn id(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    Ok(args)
 }
 
 #[cfg(test)]
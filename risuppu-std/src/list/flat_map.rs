@@ -1,20 +1,26 @@
 use risuppu::{
-    semantic::Env,
+    semantic::{Env, EvalResult},
     sexp::{Ptr, Sexp},
 };
 
 use super::quote;
 
-pub fn flat_map(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+/// `(flat-map list f)`: The concatenated lists of `f` applied to each element.
+///
+/// Return the applications passed to a concatenation, which are evaluated by the evaluator.
+pub fn flat_map(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let (list, lambda) = (args.car(), args.cdr().car());
-    let list = env.evaluate(list);
-    let lambda = env.evaluate(lambda);
+    let concat = unsafe { Sexp::rust_fn_with_evaluated_args(concat) };
 
-    quote(Sexp::from_vec(
-        Sexp::iter(list)
-            .flat_map(|elem| Sexp::iter(env.evaluate(Sexp::from_vec([lambda.clone(), quote(elem)]))))
-            .collect::<Vec<_>>(),
-    ))
+    let mut applied = vec![concat];
+    applied.extend(Sexp::iter(list).map(|elem| Sexp::from_vec([lambda.clone(), quote(elem)])));
+    Ok(Sexp::from_vec(applied))
+}
+
+fn concat(lists: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    Ok(quote(Sexp::from_vec(
+        Sexp::iter(lists).flat_map(Sexp::iter).collect::<Vec<_>>(),
+    )))
 }
 
 #[cfg(test)]
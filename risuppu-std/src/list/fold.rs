@@ -1,28 +1,29 @@
 use risuppu::{
-    semantic::Env,
+    semantic::{Env, EvalResult},
     sexp::{Ptr, Sexp},
 };
 
 use super::quote;
 
-pub fn fold(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+/// `(fold f init list ...)`: Apply `f` to the accumulated value and each element in order.
+///
+/// Return the nested applications, which are evaluated by the evaluator.
+pub fn fold(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let (lambda, init, lists) = (args.car(), args.cdr().car(), args.cdr().cdr());
-    let lambda = env.evaluate(lambda);
-    let mut init = env.evaluate(init);
-    let lists: Vec<_> = Sexp::iter(lists).map(|list| env.evaluate(list)).collect();
+    let mut folded = quote(init);
 
-    for list in lists {
+    for list in Sexp::iter(lists) {
         match list.as_ref() {
             Sexp::Form(_) => {
                 for elem in Sexp::iter(list) {
-                    init = env.evaluate(Sexp::from_vec([lambda.clone(), quote(init), quote(elem)]));
+                    folded = Sexp::from_vec([lambda.clone(), folded, quote(elem)]);
                 }
             }
-            _ => init = env.evaluate(Sexp::from_vec([lambda.clone(), quote(init), quote(list)])),
+            _ => folded = Sexp::from_vec([lambda.clone(), folded, quote(list)]),
         }
     }
 
-    quote(init)
+    Ok(folded)
 }
 
 #[cfg(test)]
@@ -1,20 +1,21 @@
 use risuppu::{
-    semantic::Env,
+    semantic::{Env, EvalResult},
     sexp::{Ptr, Sexp},
 };
 
 use super::quote;
 
-pub fn map(args: Ptr<Sexp>, env: &mut Env) -> Ptr<Sexp> {
+/// `(map list f)`: The list of `f` applied to each element.
+///
+/// Return the applications consed into a list, which are evaluated by the evaluator.
+pub fn map(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let (list, lambda) = (args.car(), args.cdr().car());
-    let list = env.evaluate(list);
-    let lambda = env.evaluate(lambda);
-
-    quote(Sexp::from_vec(
-        Sexp::iter(list)
-            .map(|elem| env.evaluate(Sexp::from_vec([lambda.clone(), quote(elem)])))
-            .collect::<Vec<_>>(),
-    ))
+    let elems = Sexp::iter(list).collect::<Vec<_>>();
+
+    Ok(elems.into_iter().rev().fold(Sexp::nil(), |rest, elem| {
+        let applied = Sexp::from_vec([lambda.clone(), quote(elem)]);
+        Sexp::from_vec([Sexp::wrap(Sexp::Cons), applied, rest])
+    }))
 }
 
 #[cfg(test)]
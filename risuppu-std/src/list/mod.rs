@@ -1,26 +1,24 @@
 use risuppu::{
-    semantic::Env,
+    semantic::{Env, EvalResult},
     sexp::{Ptr, Sexp},
 };
 
-use crate::id;
-
 mod flat_map;
 mod fold;
 mod map;
 
 crate::std_library!(
     list,
-    (create_list, "__builtin_list", crate::pre_function),
-    (id, "__builtin_fold", fold::fold),
-    (id, "__builtin_map", map::map),
-    (id, "__builtin_flat-map", flat_map::flat_map)
+    (create_list, "__builtin_list", eval_args),
+    (fold::fold, "__builtin_fold", eval_args),
+    (map::map, "__builtin_map", eval_args),
+    (flat_map::flat_map, "__builtin_flat-map", eval_args)
 );
 
 pub fn quote(args: Ptr<Sexp>) -> Ptr<Sexp> {
     Sexp::from_vec([Sexp::quote(), args])
 }
 
-pub fn create_list(args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
-    quote(Sexp::from_vec(Sexp::iter(args).collect::<Vec<_>>()))
+pub fn create_list(args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
+    Ok(quote(Sexp::from_vec(Sexp::iter(args).collect::<Vec<_>>())))
 }
@@ -1,6 +1,6 @@
-use risuppu::{sexp::{Ptr, Sexp}, semantic::Env};
+use risuppu::{sexp::{Ptr, Sexp}, semantic::{Env, EvalResult}};
 
-pub fn concat(mut args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
+pub fn concat(mut args: Ptr<Sexp>, _env: &mut Env) -> EvalResult {
     let mut v = vec![];
     while !args.is_nil() {
         let arg = args.car();
@@ -18,7 +18,7 @@ pub fn concat(mut args: Ptr<Sexp>, _env: &mut Env) -> Ptr<Sexp> {
         buf.push_str(s.as_str());
     }
 
-    Sexp::string(buf)
+    Ok(Sexp::string(buf))
 }
 
 #[cfg(test)]
@@ -31,7 +31,7 @@ mod test {
     fn concat() {
         let numbers = Sexp::from_vec([Sexp::string("One"), Sexp::string("Two"), Sexp::string("Three")]);
         let mut env = Env::new();
-        let numbers = super::concat(numbers, &mut env);
+        let numbers = super::concat(numbers, &mut env).unwrap();
         assert_eq!(numbers, Sexp::string("OneTwoThree"));
     }
 
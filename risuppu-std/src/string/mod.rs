@@ -1,8 +1,6 @@
-use crate::pre_function;
-
 mod concat;
 
 super::std_library!(
     string,
-    (concat::concat, "__builtin_concat", pre_function)
+    (concat::concat, "__builtin_concat", eval_args)
 );